@@ -0,0 +1,94 @@
+use apca::api::v2::order;
+use apca_balancer::{best_asset_to_fund, generate_orders, TradeSearch};
+use criterion::{criterion_group, criterion_main, Criterion};
+use num_decimal::Num;
+use std::hint::black_box;
+
+// Symbols in the portfolio and whole-share buys the budget covers.
+const SYMBOLS: usize = 100;
+const BUYS: usize = 500;
+const PRICE: i64 = 50;
+
+// Uneven targets so the buys spread over many symbols.
+fn ideal_allocations() -> Vec<f64> {
+    let weights: Vec<_> = (1..=SYMBOLS).map(|i| i as f64).collect();
+    let total: f64 = weights.iter().sum();
+    weights.iter().map(|w| w / total).collect()
+}
+
+// The search before the heap: every step scores all the positions.
+fn linear_scan(equities: &[f64], prices: &[f64], ideal_allocations: &[f64]) -> Vec<f64> {
+    let mut equities = equities.to_vec();
+    for _ in 0..BUYS {
+        let (i, _, _) = best_asset_to_fund(
+            equities.iter().cloned(),
+            prices.iter().cloned(),
+            prices.iter().cloned(),
+            ideal_allocations.iter().cloned(),
+            |_| true,
+            |_| false,
+            false,
+        )
+        .unwrap();
+        equities[i] += prices[i];
+    }
+    equities
+}
+
+fn heap_search(equities: &[f64], prices: &[f64], ideal_allocations: &[f64]) -> Vec<f64> {
+    let mut equities = equities.to_vec();
+    let mut search = TradeSearch::new(&equities, prices, prices, ideal_allocations.iter().cloned(), false);
+    for _ in 0..BUYS {
+        let (i, side) = search.next(|_| true, |_| false).unwrap();
+        equities[i] += prices[i];
+        search.apply(i, side, equities[i]);
+    }
+    equities
+}
+
+fn bench_search(c: &mut Criterion) {
+    let equities = vec![1000.0; SYMBOLS];
+    let prices = vec![PRICE as f64; SYMBOLS];
+    let ideal_allocations = ideal_allocations();
+
+    let mut group = c.benchmark_group("search 100 symbols 500 buys");
+    group.bench_function("linear scan", |b| {
+        b.iter(|| linear_scan(black_box(&equities), &prices, &ideal_allocations))
+    });
+    group.bench_function("heap", |b| {
+        b.iter(|| heap_search(black_box(&equities), &prices, &ideal_allocations))
+    });
+    group.finish();
+}
+
+fn bench_generate_orders(c: &mut Criterion) {
+    let equities = vec![Num::from(1000); SYMBOLS];
+    let prices = vec![Num::from(PRICE); SYMBOLS];
+    let ideal_allocations = ideal_allocations();
+    let min_allocations = vec![0.0; SYMBOLS];
+    let max_allocations = vec![1.0; SYMBOLS];
+    let max_fund = Num::from(PRICE * BUYS as i64);
+
+    c.bench_function("generate_orders 100 symbols 500 buys", |b| {
+        b.iter(|| {
+            let (orders, _) = generate_orders(
+                black_box(&equities),
+                &prices,
+                &prices,
+                ideal_allocations.iter().cloned(),
+                &min_allocations,
+                &max_allocations,
+                max_fund.clone(),
+                false,
+                false,
+                false,
+            )
+            .unwrap();
+            assert!(orders.iter().all(|&(_, side, _)| side == order::Side::Buy));
+            orders
+        })
+    });
+}
+
+criterion_group!(benches, bench_search, bench_generate_orders);
+criterion_main!(benches);
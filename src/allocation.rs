@@ -0,0 +1,89 @@
+use apca::api::v2::position;
+use serde::Deserialize;
+use std::collections::HashMap;
+use tracing::warn;
+
+use crate::api::TimedClient;
+use crate::error::{Error, Result};
+use crate::{normalize_map, volatility};
+
+// Calendar days of daily bars the risk parity volatilities are measured over.
+const RISK_PARITY_LOOKBACK_DAYS: i64 = 30;
+
+// How a generated state weights its symbols.
+#[derive(Clone, Default, Deserialize)]
+pub enum AllocationStrategy {
+    // Keeps the current position sizes.
+    #[default]
+    CurrentWeights,
+    EqualWeight,
+    MarketCapWeight {
+        shares_outstanding: HashMap<String, u64>,
+    },
+    // Weights each symbol by the inverse volatility of its daily returns.
+    RiskParity,
+}
+
+impl AllocationStrategy {
+    // Weights `syms`, taking market values and prices from the positions held.
+    pub async fn allocations(
+        &self,
+        client: &TimedClient,
+        syms: &[String],
+        pos: &[position::Position],
+    ) -> Result<HashMap<String, f64>> {
+        let held: HashMap<_, _> = pos.iter().map(|pos| (pos.symbol.as_str(), pos)).collect();
+
+        let mut allocations: HashMap<_, _> = match self {
+            AllocationStrategy::CurrentWeights => syms
+                .iter()
+                .map(|sym| {
+                    let e = held
+                        .get(sym.as_str())
+                        .map_or(0.0, |pos| pos.market_value.as_ref().unwrap().to_f64().unwrap());
+                    (sym.clone(), e)
+                })
+                .collect(),
+            AllocationStrategy::EqualWeight => syms.iter().map(|sym| (sym.clone(), 1.0)).collect(),
+            AllocationStrategy::MarketCapWeight { shares_outstanding } => syms
+                .iter()
+                .map(|sym| {
+                    let price = held
+                        .get(sym.as_str())
+                        .and_then(|pos| pos.current_price.as_ref())
+                        .map(|p| p.to_f64().unwrap());
+                    let weight = match (shares_outstanding.get(sym), price) {
+                        (Some(&shares), Some(price)) => shares as f64 * price,
+                        (None, _) => {
+                            warn!("No shares outstanding given for {}, weighting it at zero", sym);
+                            0.0
+                        }
+                        (_, None) => {
+                            warn!("No current price for {} as it isn't held, weighting it at zero", sym);
+                            0.0
+                        }
+                    };
+                    (sym.clone(), weight)
+                })
+                .collect(),
+            AllocationStrategy::RiskParity => {
+                let mut weights = HashMap::new();
+                for sym in syms {
+                    weights.insert(
+                        sym.clone(),
+                        1.0 / volatility::daily_volatility(client, sym, RISK_PARITY_LOOKBACK_DAYS).await?,
+                    );
+                }
+                weights
+            }
+        };
+
+        if allocations.values().sum::<f64>() <= 0.0 {
+            return Err(Error::InvalidConfig(
+                "the allocation strategy gave every symbol zero weight".to_string(),
+            ));
+        }
+        normalize_map(&mut allocations);
+        Ok(allocations)
+    }
+}
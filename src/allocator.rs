@@ -0,0 +1,80 @@
+use apca::api::v2::order;
+use num_decimal::Num;
+
+use crate::error::Result;
+use crate::{best_asset_to_fund, generate_orders, normalize_vec, to_money, PlannedOrder};
+
+// The greedy order search towards one set of target weights. Positions are
+// referred to by their index in `ideal_allocations`, and should be ordered by
+// symbol since ties go to the lower index.
+pub struct Allocator {
+    pub ideal_allocations: Vec<f64>,
+    // Weight limits per position, as fractions of the total equity.
+    pub min_allocations: Vec<f64>,
+    pub max_allocations: Vec<f64>,
+    pub sell_enabled: bool,
+    pub fractional_shares: bool,
+    // Weights each position's squared allocation error by its ideal allocation.
+    pub weighted_error: bool,
+}
+
+impl Allocator {
+    // Buys only, whole shares and no weight limits, towards the normalized allocations.
+    pub fn new(ideal_allocations: Vec<f64>) -> Self {
+        let n = ideal_allocations.len();
+        Allocator {
+            ideal_allocations: normalize_vec(ideal_allocations),
+            min_allocations: vec![0.0; n],
+            max_allocations: vec![1.0; n],
+            sell_enabled: false,
+            fractional_shares: false,
+            weighted_error: false,
+        }
+    }
+
+    // The orders spending at most `budget`, plus the proceeds of any sells,
+    // that bring `equities` closest to the ideal allocations, along with the
+    // equities once they fill. Each buy is `buy_sizes` of the position, which
+    // is usually its price. The amounts are planned as exact decimals, to the
+    // hundredth of a cent.
+    pub fn generate_orders(
+        &self,
+        equities: &[f64],
+        prices: &[f64],
+        buy_sizes: &[f64],
+        budget: f64,
+    ) -> Result<(Vec<PlannedOrder>, Vec<Num>)> {
+        let money = |amounts: &[f64]| -> Vec<Num> { amounts.iter().map(|&a| to_money(a)).collect() };
+        generate_orders(
+            &money(equities),
+            &money(prices),
+            &money(buy_sizes),
+            self.ideal_allocations.iter().cloned(),
+            &self.min_allocations,
+            &self.max_allocations,
+            to_money(budget),
+            self.sell_enabled,
+            self.fractional_shares,
+            self.weighted_error,
+        )
+    }
+
+    // The single buy, or error-reducing sell of one share, that brings
+    // `equities` closest to the ideal allocations, ignoring the weight limits.
+    pub fn best_trade(&self, equities: &[f64], prices: &[f64], buy_sizes: &[f64]) -> Option<PlannedOrder> {
+        let (idx, side, _) = best_asset_to_fund(
+            equities.iter().cloned(),
+            prices.iter().cloned(),
+            buy_sizes.iter().cloned(),
+            self.ideal_allocations.iter().cloned(),
+            |i| buy_sizes[i] > 0.0,
+            |i| self.sell_enabled && equities[i] >= prices[i],
+            self.weighted_error,
+        )?;
+        let amount = match side {
+            order::Side::Buy => buy_sizes[idx],
+            order::Side::Sell => prices[idx],
+        };
+        Some((idx, side, to_money(amount)))
+    }
+}
@@ -0,0 +1,303 @@
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::OsRng;
+use apca::api::v2::{account, account_activities, asset, calendar, order, orders, positions};
+use apca::data::v2::{bars, last_quotes, quotes, trades};
+use apca::{ApiInfo, Client, RequestError, Subscribable};
+use http_endpoint::Endpoint;
+use std::any::type_name;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+const SLOW_CALL_MS: f64 = 5000.0;
+
+// Weight given to the latest cycle when updating the persisted latency averages.
+const LATENCY_EMA_ALPHA: f64 = 0.2;
+
+#[derive(Default)]
+pub struct ApiCallTimer {
+    records: Mutex<Vec<(String, f64)>>,
+}
+
+impl ApiCallTimer {
+    pub fn record(&self, endpoint: String, duration_ms: f64) {
+        if duration_ms > SLOW_CALL_MS {
+            warn!("{} took {:.0}ms", endpoint, duration_ms);
+        }
+        self.records.lock().unwrap().push((endpoint, duration_ms));
+    }
+
+    // Awaits a call to `endpoint` and records how long it took.
+    pub async fn time<T, Er>(&self, endpoint: String, call: impl Future<Output = Result<T, Er>>) -> Result<T, Er> {
+        let start = Instant::now();
+        let result = call.await;
+        let duration_ms = start.elapsed().as_secs_f64() * 1000.0;
+        debug!(endpoint = %endpoint, duration_ms, ok = result.is_ok(), "API call");
+        self.record(endpoint, duration_ms);
+        result
+    }
+
+    // Folds the calls recorded since the last update into per-endpoint moving
+    // averages of the mean and 99th percentile latency.
+    pub fn update_averages(&self, avg_ms: &mut HashMap<String, f64>, p99_ms: &mut HashMap<String, f64>) {
+        let records = std::mem::take(&mut *self.records.lock().unwrap());
+
+        let mut by_endpoint: HashMap<String, Vec<f64>> = HashMap::new();
+        for (endpoint, duration_ms) in records {
+            by_endpoint.entry(endpoint).or_default().push(duration_ms);
+        }
+
+        for (endpoint, mut durations) in by_endpoint {
+            durations.sort_by(|a, b| a.total_cmp(b));
+            let mean = durations.iter().sum::<f64>() / durations.len() as f64;
+            let p99 = durations[((durations.len() as f64 * 0.99).ceil() as usize).max(1) - 1];
+
+            for (averages, value) in [(&mut *avg_ms, mean), (&mut *p99_ms, p99)] {
+                averages
+                    .entry(endpoint.clone())
+                    .and_modify(|ema| *ema += LATENCY_EMA_ALPHA * (value - *ema))
+                    .or_insert(value);
+            }
+        }
+    }
+}
+
+const MAX_ATTEMPTS: u32 = 5;
+const BASE_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// Alpaca's rate limit is per minute. apca doesn't expose the response headers,
+// so a `Retry-After` can't be read and the whole window is waited out.
+const RATE_LIMIT_DELAY: Duration = Duration::from_secs(60);
+// Rate limited waits don't count as attempts, but are capped separately so a
+// persistent limit still surfaces as an error.
+const MAX_RATE_LIMIT_WAITS: u32 = 10;
+
+// Half the delay plus a random part of the other half, so clients that failed
+// together don't all retry at the same moment.
+fn jittered(delay: Duration) -> Duration {
+    let half = delay / 2;
+    let fraction = OsRng.next_u32() as f64 / u32::MAX as f64;
+    half + half.mul_f64(fraction)
+}
+
+// Calls `f` until it succeeds, it fails with an error `is_retryable` rejects,
+// or `max_attempts` calls have failed, doubling the wait, before jitter, after
+// each failure. Failures `rate_limit_delay` gives a delay for are retried after
+// that delay without using up an attempt.
+pub async fn retry_with_backoff<T, Er, F, Fut>(
+    max_attempts: u32,
+    base_delay: Duration,
+    is_retryable: impl Fn(&Er) -> bool,
+    rate_limit_delay: impl Fn(&Er) -> Option<Duration>,
+    mut f: F,
+) -> Result<T, Er>
+where
+    Er: std::fmt::Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Er>>,
+{
+    let mut attempt = 0;
+    let mut rate_limit_waits = 0;
+    loop {
+        match f().await {
+            Err(e) if rate_limit_waits < MAX_RATE_LIMIT_WAITS && rate_limit_delay(&e).is_some() => {
+                let delay = rate_limit_delay(&e).unwrap();
+                warn!("Rate limited, retrying in {:?}: {}", delay, e);
+                tokio::time::sleep(delay).await;
+                rate_limit_waits += 1;
+            }
+            Err(e) if attempt + 1 < max_attempts && is_retryable(&e) => {
+                let delay = jittered(base_delay * 2u32.pow(attempt));
+                warn!("Retrying in {:?} after attempt {} failed: {}", delay, attempt + 1, e);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+// Whether a failed request is worth repeating. Rate limited requests were
+// never processed so they are always safe to retry. Requests that aren't
+// idempotent, like order submission, are not retried after server or
+// transport errors since they may have gone through.
+pub trait Retryable {
+    const IDEMPOTENT: bool;
+
+    fn is_rate_limited(&self) -> bool;
+    fn is_server_error(&self) -> bool;
+}
+
+macro_rules! retryable {
+    ($idempotent:literal => $($err:ty),*) => {
+        $(
+            impl Retryable for $err {
+                const IDEMPOTENT: bool = $idempotent;
+
+                fn is_rate_limited(&self) -> bool {
+                    matches!(self, Self::RateLimitExceeded(_))
+                }
+
+                // authentication failures and other 4xx responses won't change on a retry
+                fn is_server_error(&self) -> bool {
+                    matches!(self, Self::UnexpectedStatus(status, _) if status.is_server_error())
+                }
+            }
+        )*
+    };
+}
+
+retryable!(true =>
+    account::GetError,
+    account_activities::GetError,
+    asset::GetError,
+    calendar::GetError,
+    positions::GetError,
+    order::GetError,
+    order::GetByClientIdError,
+    orders::GetError,
+    order::DeleteError,
+    last_quotes::GetError,
+    quotes::GetError,
+    trades::GetError,
+    bars::GetError
+);
+retryable!(false => order::PostError);
+
+fn is_retryable<E: Retryable>(e: &RequestError<E>) -> bool {
+    match e {
+        RequestError::Endpoint(e) => e.is_rate_limited() || (E::IDEMPOTENT && e.is_server_error()),
+        RequestError::Hyper(_) | RequestError::Io(_) => E::IDEMPOTENT,
+    }
+}
+
+pub fn rate_limit_delay<E: Retryable>(e: &RequestError<E>) -> Option<Duration> {
+    matches!(e, RequestError::Endpoint(e) if e.is_rate_limited()).then_some(RATE_LIMIT_DELAY)
+}
+
+// Shortens e.g. `apca::api::v2::account::Get` to `account::Get`.
+pub fn endpoint_name<E>() -> String {
+    let segments: Vec<_> = type_name::<E>().split("::").collect();
+    segments[segments.len().saturating_sub(2)..].join("::")
+}
+
+pub struct TimedClient {
+    client: Client,
+    pub timer: ApiCallTimer,
+}
+
+impl TimedClient {
+    pub fn new(client: Client) -> Self {
+        TimedClient {
+            client,
+            timer: ApiCallTimer::default(),
+        }
+    }
+
+    pub async fn issue<E>(&self, input: &E::Input) -> Result<E::Output, RequestError<E::Error>>
+    where
+        E: Endpoint,
+        E::Error: Retryable,
+    {
+        retry_with_backoff(MAX_ATTEMPTS, BASE_RETRY_DELAY, is_retryable, rate_limit_delay, || {
+            self.timer.time(endpoint_name::<E>(), self.client.issue::<E>(input))
+        })
+        .await
+    }
+
+    pub async fn subscribe<S>(&self) -> Result<(S::Stream, S::Subscription), apca::Error>
+    where
+        S: Subscribable<Input = ApiInfo>,
+    {
+        self.client.subscribe::<S>().await
+    }
+}
+
+// The requests the order logic issues, so tests can stand in for the client.
+pub trait AlpacaClient {
+    async fn issue<E>(&self, input: &E::Input) -> Result<E::Output, RequestError<E::Error>>
+    where
+        E: Endpoint,
+        E::Error: Retryable;
+}
+
+impl AlpacaClient for TimedClient {
+    async fn issue<E>(&self, input: &E::Input) -> Result<E::Output, RequestError<E::Error>>
+    where
+        E: Endpoint,
+        E::Error: Retryable,
+    {
+        TimedClient::issue::<E>(self, input).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broker::Broker;
+    use crate::testing::{order_json, MockBroker, MockClient};
+    use reqwest::StatusCode;
+
+    const LATENCY: Duration = Duration::from_millis(20);
+    // A call slow enough to stand out from the mock's own overhead.
+    const SLOW_LATENCY: Duration = Duration::from_millis(100);
+
+    #[tokio::test]
+    async fn calls_are_timed_into_moving_averages() {
+        let broker = MockBroker::new(1000.0, &[("VTI", 10.0, 200.0)]);
+        let timer = ApiCallTimer::default();
+        // a broker that answers after a delay, like a round trip to Alpaca
+        let slow_account = || async {
+            tokio::time::sleep(LATENCY).await;
+            broker.get_account().await
+        };
+        for _ in 0..3 {
+            timer.time("account::Get".to_string(), slow_account()).await.unwrap();
+        }
+        timer.time("positions::Get".to_string(), broker.get_positions()).await.unwrap();
+
+        let records = timer.records.lock().unwrap().clone();
+        assert_eq!(records.len(), 4);
+        let latency_ms = LATENCY.as_secs_f64() * 1000.0;
+        assert!(records[..3].iter().all(|(_, ms)| *ms >= latency_ms), "{:?}", records);
+
+        let (mut avg_ms, mut p99_ms) = (HashMap::new(), HashMap::new());
+        timer.update_averages(&mut avg_ms, &mut p99_ms);
+        assert!(timer.records.lock().unwrap().is_empty());
+        let mean = records[..3].iter().map(|(_, ms)| ms).sum::<f64>() / 3.0;
+        let slowest = records[..3].iter().map(|(_, ms)| *ms).fold(0.0, f64::max);
+        assert!((avg_ms["account::Get"] - mean).abs() < 1e-9);
+        assert_eq!(p99_ms["account::Get"], slowest);
+        assert_eq!(avg_ms["positions::Get"], records[3].1);
+
+        // the next cycle's slower calls only move the averages part of the way
+        let client = MockClient::default();
+        for _ in 0..2 {
+            client.respond("order::Get", StatusCode::OK, order_json("VTI", "buy", "1", "new", "0", None));
+        }
+        let id = order::Id(uuid::Uuid::nil());
+        timer.time(endpoint_name::<order::Get>(), client.issue::<order::Get>(&id)).await.unwrap();
+        timer.update_averages(&mut avg_ms, &mut p99_ms);
+        let fast_ms = avg_ms["order::Get"];
+
+        let slow_order = async {
+            tokio::time::sleep(SLOW_LATENCY).await;
+            client.issue::<order::Get>(&id).await
+        };
+        timer.time(endpoint_name::<order::Get>(), slow_order).await.unwrap();
+        let slow_ms = timer.records.lock().unwrap()[0].1;
+        let slow_latency_ms = SLOW_LATENCY.as_secs_f64() * 1000.0;
+        assert!(slow_ms >= slow_latency_ms, "{}", slow_ms);
+
+        timer.update_averages(&mut avg_ms, &mut p99_ms);
+        let expected_ms = fast_ms + LATENCY_EMA_ALPHA * (slow_ms - fast_ms);
+        assert!((avg_ms["order::Get"] - expected_ms).abs() < 1e-9);
+        assert!((p99_ms["order::Get"] - expected_ms).abs() < 1e-9);
+        assert!(avg_ms["order::Get"] >= fast_ms + LATENCY_EMA_ALPHA * (slow_latency_ms - fast_ms));
+        // endpoints without calls keep their averages
+        assert!((avg_ms["account::Get"] - mean).abs() < 1e-9);
+        assert_eq!(avg_ms["positions::Get"], records[3].1);
+    }
+}
@@ -0,0 +1,288 @@
+use apca::api::v2::order;
+use apca::data::v2::bars;
+use chrono::{DateTime, NaiveDate, NaiveTime, TimeZone, Utc};
+use chrono_tz::US::Eastern;
+use std::collections::BTreeMap;
+
+use crate::api::TimedClient;
+use crate::error::{Error, Result};
+use crate::{allocation_error, consolidate_orders, normalize_vec, rounding, stats, Allocator, State};
+
+const TRADING_DAYS_PER_YEAR: f64 = 252.0;
+
+// A symbol's open and close on one day.
+#[derive(Clone, Copy)]
+struct DailyBar {
+    open: f64,
+    close: f64,
+}
+
+pub struct BacktestResult {
+    pub trading_days: usize,
+    pub funding_days: usize,
+    pub orders_filled: usize,
+    // Cash spent on buys less the proceeds of sells.
+    pub total_invested: f64,
+    pub final_value: f64,
+    // RMSE between the ending and ideal allocations.
+    pub drift: f64,
+    // Annualized standard deviation of the daily return differences between
+    // the holdings and the ideal allocations rebalanced daily.
+    pub tracking_error: Option<f64>,
+}
+
+// Split and dividend adjusted daily bars of `sym`, keyed by their Eastern date.
+async fn fetch_daily_bars(
+    client: &TimedClient,
+    sym: &str,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<BTreeMap<NaiveDate, DailyBar>> {
+    let mut days = BTreeMap::new();
+    let mut page_token = None;
+    loop {
+        let request = bars::BarsReqInit {
+            adjustment: Some(bars::Adjustment::All),
+            page_token,
+            ..Default::default()
+        }
+        .init(sym, start, end, bars::TimeFrame::OneDay);
+        let response = client.issue::<bars::Get>(&request).await?;
+        for bar in response.bars {
+            let bar_date = bar.time.with_timezone(&Eastern).date_naive();
+            let (open, close) = (bar.open.to_f64().unwrap(), bar.close.to_f64().unwrap());
+            days.insert(bar_date, DailyBar { open, close });
+        }
+        match response.next_page_token {
+            Some(token) => page_token = Some(token),
+            None => return Ok(days),
+        }
+    }
+}
+
+// Funding days trade at the open.
+fn open_dt(date: NaiveDate) -> DateTime<Utc> {
+    Eastern
+        .from_local_datetime(&date.and_time(NaiveTime::from_hms_opt(9, 30, 0).unwrap()))
+        .unwrap()
+        .with_timezone(&Utc)
+}
+
+// Replays the funding strategy over `days`, each holding every symbol's bar in
+// the order of `ideal_allocations`. Like the stress test, each funding day
+// invests the target still missing divided by the funding days left, but the
+// orders fill at the open.
+fn replay(
+    state: &State,
+    allocator: &Allocator,
+    days: &[(NaiveDate, Vec<DailyBar>)],
+    initial_equity: f64,
+) -> Result<BacktestResult> {
+    let mut next_funding_dt = open_dt(days[0].0);
+    let mut funding_days = Vec::new();
+    for (i, (date, _)) in days.iter().enumerate() {
+        if open_dt(*date) >= next_funding_dt {
+            funding_days.push(i);
+            next_funding_dt = state.funding_frequency.next_funding_dt(open_dt(*date));
+        }
+    }
+
+    let ideal = &allocator.ideal_allocations;
+    let target_invested = initial_equity * state.target_investment_equity_ratio;
+    let mut cash = initial_equity;
+    let mut shares = vec![0.0; ideal.len()];
+    let (mut fund_accum, mut total_invested, mut orders_filled) = (0.0, 0.0, 0);
+    let mut return_differences = Vec::new();
+    let mut prev_value = 0.0;
+    let mut funding_idx = 0;
+
+    for (i, (_, bars)) in days.iter().enumerate() {
+        let opens: Vec<f64> = bars.iter().map(|bar| bar.open).collect();
+        let mut invested_today = 0.0;
+
+        if funding_days.get(funding_idx) == Some(&i) {
+            let periods_left = (funding_days.len() - funding_idx) as f64;
+            funding_idx += 1;
+            let equities: Vec<f64> = shares.iter().zip(&opens).map(|(s, p)| s * p).collect();
+            let invested: f64 = equities.iter().sum();
+            let funding_today = ((target_invested - invested) / periods_left).max(0.0) + fund_accum;
+
+            // the unspent funding carries over, like it does between funding cycles
+            fund_accum = funding_today;
+            if allocation_error(&equities, ideal).sqrt() >= state.min_rebalance_drift {
+                let budget = funding_today.min(cash);
+                let (orders, _) = allocator.generate_orders(&equities, &opens, &opens, budget)?;
+                // the replay works in floating point like the rest of the simulation
+                let orders: Vec<_> = consolidate_orders(orders)
+                    .into_iter()
+                    .map(|(idx, side, funding)| (idx, side, funding.to_f64().unwrap()))
+                    .collect();
+
+                let buys: Vec<_> = orders
+                    .iter()
+                    .filter(|&&(_, side, _)| side == order::Side::Buy)
+                    .map(|&(idx, _, funding)| (funding, opens[idx]))
+                    .collect();
+                let sell_proceeds: f64 = orders
+                    .iter()
+                    .filter(|&&(_, side, _)| side == order::Side::Sell)
+                    .map(|&(_, _, funding)| funding)
+                    .sum();
+                let mut buy_quantities = if state.fractional_shares {
+                    buys.iter().map(|&(funds, price)| (funds / price * 100.0).floor() / 100.0).collect()
+                } else {
+                    rounding::order_quantities(&buys, budget + sell_proceeds, state.rounding_strategy)
+                        .into_iter()
+                        .map(|q| q as f64)
+                        .collect::<Vec<_>>()
+                }
+                .into_iter();
+
+                for &(idx, side, funding) in &orders {
+                    let qty = match side {
+                        order::Side::Buy => buy_quantities.next().unwrap(),
+                        order::Side::Sell => (funding / opens[idx] * 100.0).round() / 100.0,
+                    };
+                    if qty <= 0.0 {
+                        continue;
+                    }
+                    let signed_qty = match side {
+                        order::Side::Buy => qty,
+                        order::Side::Sell => -qty,
+                    };
+                    shares[idx] += signed_qty;
+                    cash -= signed_qty * opens[idx];
+                    invested_today += signed_qty * opens[idx];
+                    orders_filled += 1;
+                }
+                fund_accum = funding_today - invested_today;
+                total_invested += invested_today;
+            }
+        }
+
+        // the day's buys are taken to be added before the day's return
+        let value: f64 = shares.iter().zip(bars).map(|(s, bar)| s * bar.close).sum();
+        if i > 0 && prev_value > 0.0 {
+            let prev_bars = &days[i - 1].1;
+            let portfolio_return = value / (prev_value + invested_today) - 1.0;
+            let ideal_return: f64 = ideal
+                .iter()
+                .zip(bars.iter().zip(prev_bars))
+                .map(|(w, (bar, prev))| w * (bar.close / prev.close - 1.0))
+                .sum();
+            return_differences.push(portfolio_return - ideal_return);
+        }
+        prev_value = value;
+    }
+
+    let last_bars = &days.last().unwrap().1;
+    let equities: Vec<f64> = shares.iter().zip(last_bars).map(|(s, bar)| s * bar.close).collect();
+    Ok(BacktestResult {
+        trading_days: days.len(),
+        funding_days: funding_days.len(),
+        orders_filled,
+        total_invested,
+        final_value: cash + equities.iter().sum::<f64>(),
+        drift: allocation_error(&equities, ideal).sqrt(),
+        tracking_error: stats::std_dev(return_differences.iter().cloned()).map(|sd| sd * TRADING_DAYS_PER_YEAR.sqrt()),
+    })
+}
+
+// Replays the funding strategy over Alpaca's daily bars from `start` to `end`,
+// starting from `initial_equity` in cash and the state's ideal allocations.
+pub async fn backtest(
+    client: &TimedClient,
+    state: &State,
+    start: NaiveDate,
+    end: NaiveDate,
+    initial_equity: f64,
+) -> Result<()> {
+    if initial_equity <= 0.0 {
+        return Err(Error::InvalidConfig(format!(
+            "initial equity must be positive, got {}",
+            initial_equity
+        )));
+    }
+    if start >= end {
+        return Err(Error::InvalidConfig(format!("backtest start {} must be before its end {}", start, end)));
+    }
+
+    let mut syms: Vec<_> = state.ideal_allocations.keys().cloned().collect();
+    syms.sort();
+    let mut allocator = Allocator::new(normalize_vec(syms.iter().map(|sym| state.ideal_allocations[sym]).collect()));
+    allocator.min_allocations = syms.iter().map(|sym| state.min_allocations.get(sym).cloned().unwrap_or(0.0)).collect();
+    allocator.max_allocations = syms.iter().map(|sym| state.max_allocations.get(sym).cloned().unwrap_or(1.0)).collect();
+    allocator.sell_enabled = state.sell_enabled;
+    allocator.fractional_shares = state.fractional_shares;
+
+    let (start_dt, end_dt) = (open_dt(start), open_dt(end));
+    let mut bars_by_sym = Vec::with_capacity(syms.len());
+    for sym in &syms {
+        bars_by_sym.push(fetch_daily_bars(client, sym, start_dt, end_dt).await?);
+    }
+
+    // only days every symbol traded on are replayed
+    let days: Vec<_> = bars_by_sym[0]
+        .keys()
+        .filter_map(|date| {
+            let bars = bars_by_sym.iter().map(|bars| bars.get(date).cloned()).collect::<Option<Vec<_>>>()?;
+            Some((*date, bars))
+        })
+        .collect();
+    if days.is_empty() {
+        return Err(Error::UnexpectedData(format!(
+            "no day from {} to {} has bars for every symbol in ideal_allocations",
+            start, end
+        )));
+    }
+
+    let result = replay(state, &allocator, &days, initial_equity)?;
+    println!(
+        "Replayed {} trading days from {} to {}, funding on {} of them",
+        result.trading_days,
+        days[0].0,
+        days.last().unwrap().0,
+        result.funding_days
+    );
+    println!("Orders filled = {}", result.orders_filled);
+    println!("Total invested = {:.2}", result.total_invested);
+    println!("Final value = {:.2}", result.final_value);
+    println!("Ending allocation drift = {:.4}", result.drift);
+    match result.tracking_error {
+        Some(te) => println!("Tracking error = {:.2}%", te * 100.0),
+        None => println!("Tracking error = -"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn replay_invests_the_target_at_the_ideal_allocations() {
+        let mut state = State::new(HashMap::new(), HashMap::new());
+        state.target_investment_equity_ratio = 0.5;
+        state.fractional_shares = true;
+        state.min_rebalance_drift = 0.0;
+        let mut allocator = Allocator::new(vec![0.5, 0.5]);
+        allocator.fractional_shares = true;
+
+        let start = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+        let days: Vec<_> = (0..20)
+            .map(|i| {
+                let bar = |price: f64| DailyBar { open: price, close: price };
+                let date = start + chrono::Days::new(i);
+                (date, vec![bar(100.0), bar(50.0)])
+            })
+            .collect();
+
+        let result = replay(&state, &allocator, &days, 10_000.0).unwrap();
+        assert_eq!(result.funding_days, 20);
+        assert!((result.total_invested - 5000.0).abs() < 50.0, "{}", result.total_invested);
+        assert!(result.drift < 0.05, "{}", result.drift);
+        // flat prices leave nothing for the holdings to deviate on
+        assert!(result.tracking_error.is_some_and(|te| te < 1e-9));
+    }
+}
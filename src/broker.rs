@@ -0,0 +1,42 @@
+use apca::api::v2::{account, calendar, order, position, positions};
+use apca::RequestError;
+
+use crate::api::AlpacaClient;
+use crate::error::{Error, Result};
+
+// The account operations a funding cycle is built on. Alpaca's API provides
+// them through any `AlpacaClient`, and `testing::MockBroker` keeps them in
+// memory.
+pub trait Broker {
+    async fn get_account(&self) -> Result<account::Account>;
+    async fn get_positions(&self) -> Result<Vec<position::Position>>;
+    async fn get_calendar(&self, request: &calendar::CalendarReq) -> Result<Vec<calendar::OpenClose>>;
+    // Orders the broker refuses outright come back as `Error::OrderRejected`.
+    async fn submit_order(&self, request: &order::OrderReq) -> Result<order::Order>;
+}
+
+impl<C: AlpacaClient> Broker for C {
+    async fn get_account(&self) -> Result<account::Account> {
+        Ok(self.issue::<account::Get>(&()).await?)
+    }
+
+    async fn get_positions(&self) -> Result<Vec<position::Position>> {
+        Ok(self.issue::<positions::Get>(&()).await?)
+    }
+
+    async fn get_calendar(&self, request: &calendar::CalendarReq) -> Result<Vec<calendar::OpenClose>> {
+        Ok(self.issue::<calendar::Get>(request).await?)
+    }
+
+    async fn submit_order(&self, request: &order::OrderReq) -> Result<order::Order> {
+        self.issue::<order::Post>(request).await.map_err(|e| match e {
+            RequestError::Endpoint(e @ (order::PostError::NotPermitted(_) | order::PostError::InvalidInput(_))) => {
+                Error::OrderRejected {
+                    symbol: request.symbol.to_string(),
+                    reason: e.to_string(),
+                }
+            }
+            e => e.into(),
+        })
+    }
+}
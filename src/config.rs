@@ -0,0 +1,597 @@
+use apca::ApiInfo;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::{Tz, US::Eastern};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use crate::allocation::AllocationStrategy;
+use crate::pricing::{self, OrderType};
+use crate::error::{Error, Result};
+use crate::persistence::StateBackend;
+use crate::schedule::{self, FundingFrequency};
+use crate::slack::SlackWebhookConfig;
+use crate::sweep::{self, IdleCashSweep};
+use crate::{
+    normalize_map, validate_allocation_bounds, validate_buying_power_buffer_fraction, validate_limit_price_factor,
+    validate_reinvestment_rate, validate_target_investment_equity_ratio, OrderSettings,
+    State,
+};
+
+// Declares the initial state. Fields left out keep the generated defaults.
+#[derive(Deserialize)]
+pub struct Config {
+    // Funded equally unless `ideal_allocations` is given.
+    #[serde(default)]
+    pub symbols: Vec<String>,
+    #[serde(default)]
+    pub ideal_allocations: HashMap<String, f64>,
+    // Weights the held symbols when neither of the above is given.
+    pub allocation_strategy: Option<AllocationStrategy>,
+    pub target_investment_equity_ratio: Option<f64>,
+    // Timestamps without an offset, or plain dates, are in `display_timezone`.
+    #[serde(rename = "finish_date")]
+    raw_finish_date: Option<String>,
+    #[serde(skip)]
+    pub finish_date: Option<DateTime<Utc>>,
+    // Timezone such as "Europe/London" that times are also logged in. Trading
+    // is still scheduled in Eastern time.
+    pub display_timezone: Option<String>,
+    // The finish date is pushed back a year whenever fewer days than this remain.
+    pub auto_extend_days: Option<u32>,
+    pub limit_price_factor: Option<f64>,
+    pub min_rebalance_drift: Option<f64>,
+    #[serde(default)]
+    pub min_allocations: HashMap<String, f64>,
+    #[serde(default)]
+    pub max_allocations: HashMap<String, f64>,
+    pub fractional_shares: Option<bool>,
+    // Lets the order search sell overweight positions. Buy-only unless given.
+    pub sell_enabled: Option<bool>,
+    pub funding_frequency: Option<FundingFrequency>,
+    // Minutes after the open to trade at, or before the close when negative.
+    pub trading_offset_minutes: Option<i64>,
+    // Orders may fill in the pre- and post-market sessions, and are placed
+    // `extended_hours_offset_minutes` (60 by default) before the open.
+    #[serde(default)]
+    pub extended_hours: bool,
+    pub extended_hours_offset_minutes: Option<i64>,
+    // Limit orders unless given.
+    pub order_type: Option<OrderType>,
+    // How far past the quote MarketableLimit orders are placed, 0.005 by default.
+    pub marketable_limit_collar: Option<f64>,
+    // Buys of fractionable symbols are placed for their exact funding in
+    // dollars. Needs market orders.
+    #[serde(default)]
+    pub notional_orders: bool,
+    // Orders are placed later, or not at all, on the holiday eves and any
+    // days added to it, 2 hours after the trading time by default.
+    pub thin_liquidity: Option<schedule::ThinLiquidityDates>,
+    pub calendar_lookahead_days: Option<u32>,
+    pub calendar_max_lookahead_days: Option<u32>,
+    // Share of the buying power orders leave unspent, 0.02 by default.
+    pub buying_power_buffer_fraction: Option<f64>,
+    // Symbols whose fraction is this far above their ideal allocation are
+    // sold back down to it.
+    pub sell_rebalance_threshold: Option<f64>,
+    // Symbols sold at a loss are replaced by their substitute here.
+    #[serde(default)]
+    pub tax_loss_pairs: HashMap<String, String>,
+    // Fraction below the cost basis a position must fall to be harvested, 0.05 by default.
+    pub harvest_threshold: Option<f64>,
+    // Weights each symbol's squared allocation error by its ideal allocation.
+    #[serde(default)]
+    pub use_weighted_error: bool,
+    // Sizes orders at the EMA of this many daily closes instead of the live price.
+    pub price_ema_days: Option<u32>,
+    // Scales each symbol's buy increments by target_vol over its annualized volatility.
+    #[serde(default)]
+    pub volatility_scaling: bool,
+    pub target_vol: Option<f64>,
+    // Weights of the urgency score's allocation error, days since the last
+    // funding and turnover.
+    pub rebalance_weights: Option<RebalanceWeights>,
+    // Orders are skipped while the equity is this fraction below its high watermark.
+    pub halt_on_drawdown: Option<f64>,
+    // Funds early once a position's share of the portfolio moves this far from
+    // its ideal allocation between funding dates.
+    pub drift_trigger_threshold: Option<f64>,
+    // Positions missing from the ideal allocations are added to them with a weight of 0.
+    #[serde(default)]
+    pub auto_discover_new_positions: bool,
+    // Positions missing from the ideal allocations are sold in full at market.
+    #[serde(default)]
+    pub sell_removed_symbols: bool,
+    // Stops instead of funding when positions fall short of their reference
+    // equities and `reconcile_reference_equities` is off.
+    #[serde(default)]
+    pub halt_on_reconciliation: bool,
+    // Watchlisted symbols only join the allocations once the equity is above this.
+    pub watchlist_min_equity: Option<f64>,
+    // Daily rate invested funds are assumed to earn until the finish date.
+    pub reinvestment_rate: Option<f64>,
+    pub journal_path: Option<String>,
+    // Incoming webhook each funding cycle posts a portfolio summary to.
+    pub slack: Option<SlackWebhookConfig>,
+    // Posts to the slack webhook once every order of a funding cycle has
+    // filled, or ended partially filled.
+    #[serde(default)]
+    pub notify_on_all_fills: bool,
+    // Money-market ETF that cash is swept into on days without orders.
+    pub idle_cash_symbol: Option<String>,
+    pub idle_cash_threshold: Option<f64>,
+    pub idle_cash_buffer: Option<f64>,
+    // Where allocation snapshots are appended, `portfolio_snapshots.csv` by default.
+    pub snapshot_path: Option<String>,
+    // Directory each funding cycle's JSON report is written to, `reports` by default.
+    pub reports_dir: Option<String>,
+    // SQLite file each funding cycle appends a row of portfolio history to.
+    pub history_db: Option<String>,
+    // Keeps the state in a SQLite file, `state.db` by default, instead of JSON.
+    pub state_backend: Option<StateBackend>,
+    // Sub-portfolios in other Alpaca accounts, balanced instead of the
+    // environment's account when given.
+    #[serde(default)]
+    pub accounts: Vec<AccountConfig>,
+    // Portfolios sharing the environment's account, balanced instead of the
+    // top level's allocations when given.
+    #[serde(default)]
+    pub portfolios: Vec<PortfolioConfig>,
+    // Fraction of the account's equity, cash and buying power a portfolio is
+    // funded from. Taken from the portfolio's `capital_share`.
+    #[serde(skip)]
+    pub capital_share: Option<f64>,
+}
+
+pub const PAPER_API_BASE_URL: &str = "https://paper-api.alpaca.markets/";
+pub const LIVE_API_BASE_URL: &str = "https://api.alpaca.markets/";
+
+// How much each component of the urgency score counts.
+#[derive(Clone, Copy, Deserialize)]
+pub struct RebalanceWeights {
+    pub mse: f64,
+    pub cash_drag: f64,
+    pub turnover: f64,
+}
+
+impl Default for RebalanceWeights {
+    fn default() -> Self {
+        RebalanceWeights {
+            mse: 0.6,
+            cash_drag: 0.3,
+            turnover: 0.1,
+        }
+    }
+}
+
+// An account's own credentials and state. Its other fields are read like the
+// top level of the config.
+#[derive(Deserialize)]
+pub struct AccountConfig {
+    pub api_key_id: String,
+    pub api_secret_key: String,
+    // The paper trading API unless given.
+    pub api_base_url: Option<String>,
+    pub state_file: String,
+    #[serde(flatten)]
+    pub config: Config,
+}
+
+impl AccountConfig {
+    // `base_url` overrides the account's own, as `--paper` and `--live` do.
+    pub fn api_info(&self, base_url: Option<&str>) -> Result<ApiInfo> {
+        let base_url = base_url.or(self.api_base_url.as_deref()).unwrap_or(PAPER_API_BASE_URL);
+        ApiInfo::from_parts(base_url, &self.api_key_id, &self.api_secret_key)
+            .map_err(|e| Error::InvalidConfig(e.to_string()))
+    }
+}
+
+// A portfolio's own state and allocations within the environment's account.
+// Its other fields are read like the top level of the config.
+#[derive(Deserialize)]
+pub struct PortfolioConfig {
+    pub state_file: String,
+    // Fraction of the account this portfolio is funded from, such as 0.6.
+    pub capital_share: f64,
+    #[serde(flatten)]
+    pub config: Config,
+}
+
+// Snapshots, reports, journals and histories a sub-config leaves unset go to the top
+// level's paths prefixed with the state file's name, so they're never shared.
+fn namespace_paths(config: &mut Config, state_file: &str, top: &Config) {
+    let prefix = Path::new(state_file)
+        .file_stem()
+        .map_or_else(|| state_file.to_string(), |stem| stem.to_string_lossy().into_owned());
+    let prefixed = |path: &str| {
+        let path = Path::new(path);
+        let name = format!("{}_{}", prefix, path.file_name().unwrap_or_default().to_string_lossy());
+        path.with_file_name(name).to_string_lossy().into_owned()
+    };
+    if config.snapshot_path.is_none() {
+        let snapshot_path = top.snapshot_path.as_deref().unwrap_or(crate::snapshot::DEFAULT_SNAPSHOT_PATH);
+        config.snapshot_path = Some(prefixed(snapshot_path));
+    }
+    if config.reports_dir.is_none() {
+        let reports_dir = top.reports_dir.as_deref().unwrap_or(crate::rebalancing_report::DEFAULT_REPORTS_DIR);
+        config.reports_dir = Some(prefixed(reports_dir));
+    }
+    if config.journal_path.is_none() {
+        config.journal_path = top.journal_path.as_deref().map(prefixed);
+    }
+    if config.history_db.is_none() {
+        config.history_db = top.history_db.as_deref().map(prefixed);
+    }
+}
+
+// Validates an account's or portfolio's config and fills it in from the top level.
+fn prepare_sub_config(
+    kind: &str,
+    state_file: &str,
+    config: &mut Config,
+    top: &Config,
+    state_files: &mut HashSet<String>,
+) -> Result<()> {
+    if !config.accounts.is_empty() || !config.portfolios.is_empty() {
+        return Err(Error::InvalidConfig(format!(
+            "{} {} can't have accounts or portfolios of its own",
+            kind, state_file
+        )));
+    }
+    if !state_files.insert(state_file.to_string()) {
+        return Err(Error::InvalidConfig(format!(
+            "state file {} is used by more than one {}",
+            state_file, kind
+        )));
+    }
+    if config.display_timezone.is_none() {
+        config.display_timezone = top.display_timezone.clone();
+    }
+    if config.slack.is_none() {
+        config.slack = top.slack.clone();
+    }
+    if config.rebalance_weights.is_none() {
+        config.rebalance_weights = top.rebalance_weights;
+    }
+    if config.thin_liquidity.is_none() {
+        config.thin_liquidity = top.thin_liquidity.clone();
+    }
+    let backend = config.state_backend.or(top.state_backend).unwrap_or_default();
+    if StateBackend::of_path(state_file) != backend {
+        return Err(Error::InvalidConfig(format!(
+            "{} state file {} doesn't match the {:?} state_backend, SQLite state files end in .db, .sqlite or .sqlite3",
+            kind, state_file, backend
+        )));
+    }
+    validate_config(config)?;
+    config.finish_date = parse_finish_date(config)?;
+    namespace_paths(config, state_file, top);
+    Ok(())
+}
+
+// Environment variables such as APCA_BALANCER_LIMIT_PRICE_FACTOR=0.995
+// override the top-level setting of the same name.
+pub const ENV_PREFIX: &str = "APCA_BALANCER_";
+
+pub fn env_overrides() -> Vec<(String, String)> {
+    std::env::vars().filter(|(name, _)| name.starts_with(ENV_PREFIX)).collect()
+}
+
+// Values are read as TOML, so numbers, booleans and arrays keep their types,
+// and anything else, dates included, as a plain string.
+pub fn apply_env_overrides(table: &mut toml::Table, vars: impl IntoIterator<Item = (String, String)>) {
+    for (name, raw) in vars {
+        let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let value = match toml::from_str::<toml::Table>(&format!("value = {}", raw)).map(|mut t| t.remove("value")) {
+            Ok(Some(value)) if !value.is_datetime() => value,
+            _ => toml::Value::String(raw),
+        };
+        table.insert(key.to_lowercase(), value);
+    }
+}
+
+// The file is optional when every setting comes from the environment.
+pub fn load_config(path: &str) -> Result<Config> {
+    let mut table: toml::Table = match fs::read_to_string(path) {
+        Ok(contents) => toml::from_str(&contents)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && !env_overrides().is_empty() => toml::Table::new(),
+        Err(e) => return Err(e.into()),
+    };
+    apply_env_overrides(&mut table, env_overrides());
+    let mut config: Config = table.try_into()?;
+    validate_config(&config)?;
+    config.finish_date = parse_finish_date(&config)?;
+    if !config.accounts.is_empty() && !config.portfolios.is_empty() {
+        return Err(Error::InvalidConfig(
+            "accounts and portfolios can't both be given".to_string(),
+        ));
+    }
+
+    let mut accounts = std::mem::take(&mut config.accounts);
+    let mut state_files = HashSet::new();
+    for account in &mut accounts {
+        prepare_sub_config("account", &account.state_file, &mut account.config, &config, &mut state_files)?;
+    }
+    config.accounts = accounts;
+
+    let mut portfolios = std::mem::take(&mut config.portfolios);
+    for portfolio in &mut portfolios {
+        prepare_sub_config("portfolio", &portfolio.state_file, &mut portfolio.config, &config, &mut state_files)?;
+        portfolio.config.capital_share = Some(portfolio.capital_share);
+    }
+    validate_portfolios(&portfolios)?;
+    config.portfolios = portfolios;
+    Ok(config)
+}
+
+// Portfolios split one account, so their shares must fit in it and each
+// symbol's position can only belong to one of them.
+fn validate_portfolios(portfolios: &[PortfolioConfig]) -> Result<()> {
+    let mut owners: HashMap<String, &str> = HashMap::new();
+    for portfolio in portfolios {
+        if !(portfolio.capital_share > 0.0 && portfolio.capital_share <= 1.0) {
+            return Err(Error::InvalidConfig(format!(
+                "capital_share of portfolio {} must be in (0, 1], got {}",
+                portfolio.state_file, portfolio.capital_share
+            )));
+        }
+        if portfolio.config.idle_cash_symbol.is_some() {
+            return Err(Error::InvalidConfig(format!(
+                "portfolio {} can't sweep idle cash, the account's cash is shared",
+                portfolio.state_file
+            )));
+        }
+        let Some(allocations) = portfolio.config.allocations() else {
+            return Err(Error::InvalidConfig(format!(
+                "portfolio {} needs ideal_allocations or symbols",
+                portfolio.state_file
+            )));
+        };
+        for sym in allocations.into_keys() {
+            if let Some(other) = owners.insert(sym.clone(), &portfolio.state_file) {
+                return Err(Error::InvalidConfig(format!(
+                    "{} is in both portfolio {} and portfolio {}",
+                    sym, other, portfolio.state_file
+                )));
+            }
+        }
+    }
+
+    let total: f64 = portfolios.iter().map(|p| p.capital_share).sum();
+    if total > 1.0 + 1e-9 {
+        return Err(Error::InvalidConfig(format!(
+            "the portfolios' capital_share add up to {}, more than the whole account",
+            total
+        )));
+    }
+    Ok(())
+}
+
+fn parse_finish_date(config: &Config) -> Result<Option<DateTime<Utc>>> {
+    let Some(raw) = &config.raw_finish_date else {
+        return Ok(None);
+    };
+    if let Ok(dt) = DateTime::parse_from_rfc3339(raw) {
+        return Ok(Some(dt.with_timezone(&Utc)));
+    }
+
+    let naive = NaiveDateTime::parse_from_str(raw, "%Y-%m-%dT%H:%M:%S%.f")
+        .or_else(|_| NaiveDate::parse_from_str(raw, "%Y-%m-%d").map(|date| date.and_hms_opt(0, 0, 0).unwrap()))
+        .map_err(|_| Error::InvalidConfig(format!("finish_date {} is not a date or timestamp", raw)))?;
+    let tz = config.display_tz().unwrap_or(Eastern);
+    tz.from_local_datetime(&naive)
+        .earliest()
+        .map(|dt| Some(dt.with_timezone(&Utc)))
+        .ok_or_else(|| Error::InvalidConfig(format!("finish_date {} doesn't exist in {}", raw, tz)))
+}
+
+fn validate_config(config: &Config) -> Result<()> {
+    if let Some(name) = &config.display_timezone {
+        name.parse::<Tz>()
+            .map_err(|_| Error::InvalidConfig(format!("unknown display_timezone {}", name)))?;
+    }
+    if let Some(factor) = config.limit_price_factor {
+        validate_limit_price_factor(factor)?;
+    }
+    if let Some(ratio) = config.target_investment_equity_ratio {
+        validate_target_investment_equity_ratio(ratio)?;
+    }
+    validate_allocation_bounds(&config.min_allocations, &config.max_allocations)?;
+    if let Some(fraction) = config.buying_power_buffer_fraction {
+        validate_buying_power_buffer_fraction(fraction)?;
+    }
+    if let Some(rate) = config.reinvestment_rate {
+        validate_reinvestment_rate(rate)?;
+    }
+    if let Some(minutes) = config.trading_offset_minutes {
+        schedule::validate_trading_offset(minutes)?;
+    }
+    if config.extended_hours && config.order_type == Some(OrderType::Market) {
+        return Err(Error::InvalidConfig(
+            "extended_hours needs limit orders, Alpaca rejects market orders outside regular hours".to_string(),
+        ));
+    }
+    if let Some(collar) = config.marketable_limit_collar.filter(|c| !(0.0..0.1).contains(c)) {
+        return Err(Error::InvalidConfig(format!(
+            "marketable_limit_collar must be in [0, 0.1), got {}",
+            collar
+        )));
+    }
+    if config.notional_orders && config.order_type != Some(OrderType::Market) {
+        return Err(Error::InvalidConfig(
+            "notional_orders needs order_type = \"Market\", Alpaca only takes notional amounts at market".to_string(),
+        ));
+    }
+    if config.auto_discover_new_positions && config.sell_removed_symbols {
+        return Err(Error::InvalidConfig(
+            "auto_discover_new_positions and sell_removed_symbols contradict each other".to_string(),
+        ));
+    }
+    if let Some(threshold) = config.drift_trigger_threshold.filter(|t| !(t > &0.0 && t < &1.0)) {
+        return Err(Error::InvalidConfig(format!(
+            "drift_trigger_threshold must be in (0, 1), got {}",
+            threshold
+        )));
+    }
+    if config.notify_on_all_fills && config.slack.is_none() {
+        return Err(Error::InvalidConfig(
+            "notify_on_all_fills needs a slack webhook to post to".to_string(),
+        ));
+    }
+    if config.price_ema_days == Some(0) {
+        return Err(Error::InvalidConfig("price_ema_days must be at least 1".to_string()));
+    }
+    if let Some(minutes) = config.extended_hours_offset_minutes {
+        schedule::validate_extended_hours_offset(minutes)?;
+    }
+    Ok(())
+}
+
+impl Config {
+    pub fn allocations(&self) -> Option<HashMap<String, f64>> {
+        let mut allocations = if !self.ideal_allocations.is_empty() {
+            self.ideal_allocations.clone()
+        } else if !self.symbols.is_empty() {
+            self.symbols.iter().map(|sym| (sym.clone(), 1.0)).collect()
+        } else {
+            return None;
+        };
+        normalize_map(&mut allocations);
+        Some(allocations)
+    }
+
+    pub fn display_tz(&self) -> Option<Tz> {
+        self.display_timezone.as_deref().and_then(|name| name.parse().ok())
+    }
+
+    pub fn idle_cash_sweep(&self) -> Option<IdleCashSweep> {
+        Some(IdleCashSweep {
+            symbol: self.idle_cash_symbol.clone()?,
+            threshold: self.idle_cash_threshold.unwrap_or(sweep::DEFAULT_IDLE_CASH_THRESHOLD),
+            buffer: self.idle_cash_buffer.unwrap_or(0.0),
+            order_settings: self.order_settings(),
+        })
+    }
+
+    pub fn order_settings(&self) -> OrderSettings {
+        OrderSettings {
+            order_type: self.order_type.unwrap_or_default(),
+            extended_hours: self.extended_hours,
+            notional: self.notional_orders,
+            marketable_limit_collar: self
+                .marketable_limit_collar
+                .unwrap_or(pricing::DEFAULT_MARKETABLE_LIMIT_COLLAR),
+        }
+    }
+
+    pub fn apply(&self, state: &mut State) {
+        if let Some(allocations) = self.allocations() {
+            // symbols not held yet start without a reference equity
+            for sym in allocations.keys() {
+                state.reference_equities.entry(sym.clone()).or_insert(0.0);
+            }
+            state.ideal_allocations = allocations;
+        }
+        if let Some(ratio) = self.target_investment_equity_ratio {
+            state.target_investment_equity_ratio = ratio;
+        }
+        if let Some(dt) = self.finish_date {
+            state.finish_date = dt;
+        }
+        if let Some(factor) = self.limit_price_factor {
+            state.limit_price_factor = factor;
+        }
+        if let Some(drift) = self.min_rebalance_drift {
+            state.min_rebalance_drift = drift;
+        }
+        if !self.min_allocations.is_empty() {
+            state.min_allocations = self.min_allocations.clone();
+        }
+        if !self.max_allocations.is_empty() {
+            state.max_allocations = self.max_allocations.clone();
+        }
+        if let Some(fractional) = self.fractional_shares {
+            state.fractional_shares = fractional;
+        }
+        if let Some(sell_enabled) = self.sell_enabled {
+            state.sell_enabled = sell_enabled;
+        }
+        if let Some(frequency) = self.funding_frequency {
+            state.funding_frequency = frequency;
+        }
+        if let Some(path) = &self.journal_path {
+            state.journal_path = Some(path.clone());
+        }
+    }
+
+    // Names the configured fields that differ from the state.
+    pub fn disagreements(&self, state: &State) -> Vec<&'static str> {
+        let allocations_differ = self.allocations().is_some_and(|allocations| {
+            allocations.len() != state.ideal_allocations.len()
+                || allocations.iter().any(|(sym, a)| {
+                    state
+                        .ideal_allocations
+                        .get(sym)
+                        .is_none_or(|b| (a - b).abs() > 1e-9)
+                })
+        });
+
+        [
+            ("ideal_allocations", allocations_differ),
+            (
+                "target_investment_equity_ratio",
+                self.target_investment_equity_ratio
+                    .is_some_and(|r| r != state.target_investment_equity_ratio),
+            ),
+            (
+                "finish_date",
+                self.finish_date.is_some_and(|dt| dt != state.finish_date),
+            ),
+            (
+                "limit_price_factor",
+                self.limit_price_factor
+                    .is_some_and(|f| f != state.limit_price_factor),
+            ),
+            (
+                "min_rebalance_drift",
+                self.min_rebalance_drift
+                    .is_some_and(|d| d != state.min_rebalance_drift),
+            ),
+            (
+                "min_allocations",
+                !self.min_allocations.is_empty() && self.min_allocations != state.min_allocations,
+            ),
+            (
+                "max_allocations",
+                !self.max_allocations.is_empty() && self.max_allocations != state.max_allocations,
+            ),
+            (
+                "fractional_shares",
+                self.fractional_shares
+                    .is_some_and(|f| f != state.fractional_shares),
+            ),
+            (
+                "sell_enabled",
+                self.sell_enabled.is_some_and(|s| s != state.sell_enabled),
+            ),
+            (
+                "funding_frequency",
+                self.funding_frequency
+                    .is_some_and(|f| f != state.funding_frequency),
+            ),
+            (
+                "journal_path",
+                self.journal_path
+                    .as_ref()
+                    .is_some_and(|p| Some(p) != state.journal_path.as_ref()),
+            ),
+        ]
+        .into_iter()
+        .filter(|&(_, differs)| differs)
+        .map(|(field, _)| field)
+        .collect()
+    }
+}
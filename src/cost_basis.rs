@@ -0,0 +1,78 @@
+use apca::api::v2::order;
+use std::collections::HashMap;
+
+use crate::error::Result;
+use crate::journal;
+
+// Buys add what was paid, while sells remove the average cost of the shares
+// sold, so the basis always covers the shares still held.
+pub fn record_fill(
+    cost_basis: &mut HashMap<String, f64>,
+    shares_held: &mut HashMap<String, f64>,
+    sym: &str,
+    side: order::Side,
+    qty: f64,
+    price: f64,
+) {
+    let basis = cost_basis.entry(sym.to_string()).or_insert(0.0);
+    let held = shares_held.entry(sym.to_string()).or_insert(0.0);
+
+    match side {
+        order::Side::Buy => {
+            *basis += qty * price;
+            *held += qty;
+        }
+        order::Side::Sell => {
+            let sold = qty.min(*held);
+            if *held > 0.0 {
+                *basis -= *basis * sold / *held;
+            }
+            *held -= sold;
+        }
+    }
+}
+
+// Keeps a running average of every buy's fill price, unaffected by sells.
+pub fn record_purchase(
+    average_purchase_price: &mut HashMap<String, f64>,
+    total_shares_purchased: &mut HashMap<String, f64>,
+    sym: &str,
+    qty: f64,
+    price: f64,
+) {
+    let avg = average_purchase_price.entry(sym.to_string()).or_insert(0.0);
+    let purchased = total_shares_purchased.entry(sym.to_string()).or_insert(0.0);
+    if *purchased + qty > 0.0 {
+        *avg = (*avg * *purchased + price * qty) / (*purchased + qty);
+    }
+    *purchased += qty;
+}
+
+pub fn average_cost(cost_basis: &HashMap<String, f64>, shares_held: &HashMap<String, f64>, sym: &str) -> Option<f64> {
+    let held = *shares_held.get(sym)?;
+    (held > 0.0).then(|| cost_basis.get(sym).cloned().unwrap_or(0.0) / held)
+}
+
+// Replays the fills recorded in the journal.
+pub fn from_journal(path: &str) -> Result<(HashMap<String, f64>, HashMap<String, f64>)> {
+    let (mut cost_basis, mut shares_held) = (HashMap::new(), HashMap::new());
+    for fill in journal::read_fills(path)? {
+        record_fill(&mut cost_basis, &mut shares_held, &fill.symbol, fill.side, fill.quantity, fill.price);
+    }
+    Ok((cost_basis, shares_held))
+}
+
+// Replays the buys recorded in the journal.
+pub fn purchases_from_journal(path: &str) -> Result<(HashMap<String, f64>, HashMap<String, f64>)> {
+    let (mut average_purchase_price, mut total_shares_purchased) = (HashMap::new(), HashMap::new());
+    for fill in journal::read_fills(path)?.into_iter().filter(|fill| fill.side == order::Side::Buy) {
+        record_purchase(
+            &mut average_purchase_price,
+            &mut total_shares_purchased,
+            &fill.symbol,
+            fill.quantity,
+            fill.price,
+        );
+    }
+    Ok((average_purchase_price, total_shares_purchased))
+}
@@ -0,0 +1,102 @@
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use pbkdf2::pbkdf2_hmac;
+use sha2::Sha256;
+use std::sync::OnceLock;
+use tracing::info;
+
+use crate::error::{Error, Result};
+
+pub const PASSPHRASE_ENV: &str = "APCA_BALANCER_PASSPHRASE";
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const PBKDF2_ROUNDS: u32 = 600_000;
+
+struct Passphrase {
+    passphrase: String,
+    // Derived once per run, so saving doesn't repeat the key derivation.
+    salt: [u8; SALT_LEN],
+    key: Key<Aes256Gcm>,
+}
+
+static PASSPHRASE: OnceLock<Passphrase> = OnceLock::new();
+
+// State files are encrypted from now on when a passphrase is given here or
+// in the environment.
+pub fn init(passphrase: Option<String>) {
+    let Some(passphrase) = passphrase
+        .or_else(|| std::env::var(PASSPHRASE_ENV).ok())
+        .filter(|p| !p.is_empty())
+    else {
+        return;
+    };
+    let mut salt = [0; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let key = derive_key(&passphrase, &salt);
+    let _ = PASSPHRASE.set(Passphrase { passphrase, salt, key });
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Key<Aes256Gcm> {
+    let mut key = [0; 32];
+    pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, PBKDF2_ROUNDS, &mut key);
+    key.into()
+}
+
+// Encrypted files hold the salt, the nonce and then the ciphertext. Without a
+// passphrase the data is written as is.
+pub fn encrypt(plaintext: Vec<u8>) -> Vec<u8> {
+    let Some(p) = PASSPHRASE.get() else {
+        return plaintext;
+    };
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = Aes256Gcm::new(&p.key)
+        .encrypt(&nonce, plaintext.as_slice())
+        .expect("AES-GCM encryption doesn't fail for in-memory data");
+    [p.salt.as_slice(), nonce.as_slice(), &ciphertext].concat()
+}
+
+// Plaintext files are still read with a passphrase set, so an existing state
+// is encrypted the next time it's saved.
+pub fn decrypt(data: Vec<u8>, filename: &str) -> Result<Vec<u8>> {
+    let Some(p) = PASSPHRASE.get() else {
+        return Ok(data);
+    };
+    if serde_json::from_slice::<serde_json::Value>(&data).is_ok() {
+        info!("{} isn't encrypted yet, it will be on the next save", filename);
+        return Ok(data);
+    }
+    // a truncated file can still be replaced by a backup
+    if data.len() < SALT_LEN + NONCE_LEN {
+        return Err(Error::StateIo(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!("{} is too short to be encrypted", filename),
+        )));
+    }
+
+    let (salt, rest) = data.split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    let key = if salt == p.salt { p.key } else { derive_key(&p.passphrase, salt) };
+    Aes256Gcm::new(&key)
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| Error::InvalidState(format!("could not decrypt {}, is the passphrase right?", filename)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_with_a_passphrase() {
+        init(Some("correct horse".to_string()));
+        let encrypted = encrypt(b"{\"version\":13}".to_vec());
+        assert_ne!(encrypted, b"{\"version\":13}");
+        assert_eq!(decrypt(encrypted.clone(), "state.json").unwrap(), b"{\"version\":13}");
+
+        let mut tampered = encrypted;
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(decrypt(tampered, "state.json").is_err());
+        assert_eq!(decrypt(b"{}".to_vec(), "state.json").unwrap(), b"{}");
+    }
+}
@@ -0,0 +1,155 @@
+use apca::RequestError;
+use std::error::Error as StdError;
+use std::fmt;
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::api::{self, Retryable};
+use crate::reconcile::ReconciliationWarning;
+
+// An endpoint's own error, such as `order::PostError`. Every endpoint has its
+// own type, so it's boxed to fit in one `RequestError`.
+#[derive(Debug, Error)]
+#[error(transparent)]
+pub struct EndpointError(Box<dyn StdError + Send + Sync>);
+
+impl EndpointError {
+    pub fn downcast_ref<E: StdError + 'static>(&self) -> Option<&E> {
+        self.0.downcast_ref()
+    }
+}
+
+// The apca error behind a failed call.
+#[derive(Debug)]
+pub enum ApiError {
+    Request(RequestError<EndpointError>),
+    // Connecting to a stream, and other failures outside a request.
+    Client(apca::Error),
+}
+
+impl<E: StdError + Send + Sync + 'static> From<RequestError<E>> for ApiError {
+    fn from(e: RequestError<E>) -> Self {
+        ApiError::Request(match e {
+            RequestError::Endpoint(e) => RequestError::Endpoint(EndpointError(Box::new(e))),
+            RequestError::Hyper(e) => RequestError::Hyper(e),
+            RequestError::Io(e) => RequestError::Io(e),
+        })
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // apca's own messages leave the detail to the source
+        let (e, source): (&dyn StdError, _) = match self {
+            ApiError::Request(RequestError::Endpoint(e)) => return write!(f, "{}", e),
+            ApiError::Request(e) => (e, e.source()),
+            ApiError::Client(e) => (e, e.source()),
+        };
+        match source {
+            Some(source) => write!(f, "{}: {}", e, source),
+            None => write!(f, "{}", e),
+        }
+    }
+}
+
+impl StdError for ApiError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            ApiError::Request(e) => Some(e),
+            ApiError::Client(e) => Some(e),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    // Requests that still failed after `TimedClient` retried them.
+    #[error("Alpaca API request failed: {0}")]
+    Api(Box<ApiError>),
+    // Responses like authentication failures that repeating the request won't change.
+    #[error("Alpaca API rejected the request: {0}")]
+    ApiRejected(Box<ApiError>),
+    // Still rate limited after waiting out the limit several times.
+    #[error("Alpaca API rate limit exceeded, retry after {retry_after:?}")]
+    RateLimited { retry_after: Duration },
+    #[error("HTTP request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("file I/O failed: {0}")]
+    StateIo(#[from] std::io::Error),
+    #[error("JSON parsing failed: {0}")]
+    StateParse(#[from] serde_json::Error),
+    #[error("CSV export failed: {0}")]
+    Csv(#[from] csv::Error),
+    // The portfolio history or a SQLite state store.
+    #[error("SQLite database failed: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("invalid state: {0}")]
+    InvalidState(String),
+    #[error("invalid configuration: {0}")]
+    InvalidConfig(String),
+    #[error("order for {symbol} was rejected: {reason}")]
+    OrderRejected { symbol: String, reason: String },
+    // A downloaded dataset didn't have the expected layout.
+    #[error("unexpected data: {0}")]
+    UnexpectedData(String),
+    // Positions short of their reference equities with `halt_on_reconciliation` set.
+    #[error("positions fall short of their reference equities: {}", shortfalls(.0))]
+    Reconciliation(Vec<ReconciliationWarning>),
+}
+
+fn shortfalls(warnings: &[ReconciliationWarning]) -> String {
+    let shortfalls: Vec<_> = warnings
+        .iter()
+        .map(|w| format!("{} ({:.1}%)", w.symbol, w.magnitude * 100.0))
+        .collect();
+    shortfalls.join(", ")
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    // Failures that may clear up if the same work is tried again later.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Error::Api(_) | Error::Http(_) | Error::RateLimited { .. })
+    }
+
+    // Failures particular to one day's funding, like a rejected order or data
+    // that doesn't add up, which shouldn't stop the following days' cycles.
+    pub fn skips_day(&self) -> bool {
+        matches!(
+            self,
+            Error::OrderRejected { .. } | Error::Reconciliation(_) | Error::UnexpectedData(_)
+        )
+    }
+}
+
+impl<E: StdError + Send + Sync + Retryable + 'static> From<RequestError<E>> for Error {
+    fn from(e: RequestError<E>) -> Self {
+        if let Some(retry_after) = api::rate_limit_delay(&e) {
+            return Error::RateLimited { retry_after };
+        }
+        match e {
+            // the endpoint error carries the HTTP status and Alpaca's message
+            RequestError::Endpoint(ref endpoint) if !endpoint.is_server_error() => Error::ApiRejected(Box::new(e.into())),
+            e => Error::Api(Box::new(e.into())),
+        }
+    }
+}
+
+impl From<apca::Error> for Error {
+    fn from(e: apca::Error) -> Self {
+        Error::Api(Box::new(ApiError::Client(e)))
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(e: toml::de::Error) -> Self {
+        Error::InvalidConfig(e.to_string())
+    }
+}
+
+impl From<uuid::Error> for Error {
+    fn from(e: uuid::Error) -> Self {
+        Error::InvalidState(format!("invalid order id: {}", e))
+    }
+}
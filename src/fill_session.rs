@@ -0,0 +1,111 @@
+use apca::api::v2::order;
+use serde::{Deserialize, Serialize};
+
+// Quantities this close to the submitted one count as filled.
+const FILL_TOLERANCE: f64 = 1e-6;
+
+// One symbol's order in a session, and what has filled of it so far across
+// the repriced and market orders that replaced it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionOrder {
+    pub symbol: String,
+    pub side: order::Side,
+    pub quantity: f64,
+    pub limit_price: f64,
+    pub filled_quantity: f64,
+    pub filled_value: f64,
+}
+
+impl SessionOrder {
+    pub fn avg_fill_price(&self) -> Option<f64> {
+        (self.filled_quantity > 0.0).then(|| self.filled_value / self.filled_quantity)
+    }
+
+    pub fn unfilled_quantity(&self) -> f64 {
+        (self.quantity - self.filled_quantity).max(0.0)
+    }
+}
+
+// The orders a funding cycle submitted, followed until none is pending any
+// more so `notify_on_all_fills` reports them once. The id is the trading day,
+// so a run restarted during the wait continues the same session.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FillSession {
+    pub id: String,
+    pub orders: Vec<SessionOrder>,
+    // Set once the notification went out, so it isn't sent again.
+    pub notified: bool,
+}
+
+// How a session ended, with the totals of its buys.
+#[derive(Debug, PartialEq)]
+pub enum FillsEvent {
+    FillsCompleted {
+        shares_bought: f64,
+        capital_deployed: f64,
+    },
+    PartialFillsCompleted {
+        shares_bought: f64,
+        capital_deployed: f64,
+        // Symbols and the quantities of their orders that never filled.
+        unfilled: Vec<(String, f64)>,
+    },
+}
+
+impl FillSession {
+    pub fn new(id: String) -> Self {
+        FillSession {
+            id,
+            orders: Vec::new(),
+            notified: false,
+        }
+    }
+
+    // An order resubmitted after a restart is only added once.
+    pub fn record_submission(&mut self, symbol: &str, side: order::Side, quantity: f64, limit_price: f64) {
+        if self.orders.iter().any(|o| o.symbol == symbol && o.side == side) {
+            return;
+        }
+        self.orders.push(SessionOrder {
+            symbol: symbol.to_string(),
+            side,
+            quantity,
+            limit_price,
+            filled_quantity: 0.0,
+            filled_value: 0.0,
+        });
+    }
+
+    // Fills of symbols the session didn't order, like a tax-loss harvest's, are ignored.
+    pub fn record_fill(&mut self, symbol: &str, side: order::Side, quantity: f64, price: f64) {
+        if let Some(o) = self.orders.iter_mut().find(|o| o.symbol == symbol && o.side == side) {
+            o.filled_quantity += quantity;
+            o.filled_value += quantity * price;
+        }
+    }
+
+    pub fn event(&self) -> FillsEvent {
+        let buys = self.orders.iter().filter(|o| o.side == order::Side::Buy);
+        let shares_bought = buys.clone().map(|o| o.filled_quantity).sum();
+        let capital_deployed = buys.map(|o| o.filled_value).sum();
+        let unfilled: Vec<_> = self
+            .orders
+            .iter()
+            .filter(|o| o.unfilled_quantity() > FILL_TOLERANCE)
+            .map(|o| (o.symbol.clone(), o.unfilled_quantity()))
+            .collect();
+
+        if unfilled.is_empty() {
+            FillsEvent::FillsCompleted {
+                shares_bought,
+                capital_deployed,
+            }
+        } else {
+            FillsEvent::PartialFillsCompleted {
+                shares_bought,
+                capital_deployed,
+                unfilled,
+            }
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use apca::api::v2::{order, position};
+use chrono::{Duration, Utc};
+use std::collections::HashMap;
+use tracing::{info, warn};
+
+use crate::api::TimedClient;
+use crate::error::Result;
+use crate::shutdown::Shutdown;
+use crate::slack::SlackWebhookConfig;
+use crate::{cost_basis, pricing, OrderSettings, State};
+
+pub const DEFAULT_HARVEST_THRESHOLD: f64 = 0.05;
+
+// Buying back a symbol sold at a loss within this many days is a wash sale.
+const WASH_SALE_DAYS: i64 = 31;
+
+// Sells the program's shares of each paired symbol trading more than
+// `threshold` below their cost basis and buys its substitute with the
+// proceeds, moving the ideal allocation across. Symbols sold at a loss can't
+// be bought back until their cooldown ends. Returns whether anything was sold.
+#[allow(clippy::too_many_arguments)]
+pub async fn harvest_losses(
+    client: &TimedClient,
+    state: &mut State,
+    pos: &[position::Position],
+    pairs: &HashMap<String, String>,
+    threshold: f64,
+    settings: OrderSettings,
+    shutdown: &Shutdown,
+    slack: Option<&SlackWebhookConfig>,
+) -> Result<bool> {
+    let now = Utc::now();
+    state.harvest_cooldowns.retain(|_, until| *until > now);
+
+    let mut harvested = false;
+    for pos in pos {
+        let Some(substitute) = pairs.get(&pos.symbol) else {
+            continue;
+        };
+        if state.harvest_cooldowns.contains_key(substitute) {
+            continue;
+        }
+
+        let price = pos.current_price.as_ref().unwrap().to_f64().unwrap();
+        let held = pos.quantity.to_f64().unwrap();
+        let Some(average_cost) = cost_basis::average_cost(&state.cost_basis, &state.shares_held, &pos.symbol) else {
+            continue;
+        };
+        // shares held before the program started have no recorded cost
+        let qty = state.shares_held[&pos.symbol].min(held);
+        let cost = average_cost * qty;
+        if qty * price >= cost * (1.0 - threshold) {
+            continue;
+        }
+
+        info!(
+            "Harvesting a {:.2} loss on {} {}, switching to {}",
+            cost - qty * price,
+            qty,
+            pos.symbol,
+            substitute
+        );
+        let sell_limit = settings.order_price(state, order::Side::Sell, price, None);
+        if state
+            .place_order(client, shutdown, &pos.symbol, order::Side::Sell, sell_limit, qty, settings)
+            .await?
+            .is_none()
+        {
+            continue;
+        }
+        harvested = true;
+        state
+            .harvest_cooldowns
+            .insert(pos.symbol.clone(), now + Duration::days(WASH_SALE_DAYS));
+
+        if let Some(allocation) = state.ideal_allocations.remove(&pos.symbol) {
+            *state.ideal_allocations.entry(substitute.clone()).or_insert(0.0) += allocation;
+        }
+        state.reference_equities.entry(substitute.clone()).or_insert(0.0);
+
+        // the proceeds are only available once the sale fills
+        state.monitor_pending_orders(client, shutdown, slack).await?;
+        let Some(&(ask, _)) = pricing::get_quotes(client, [substitute.clone()]).await?.get(substitute) else {
+            warn!("No quote for {}, it will be bought by the regular funding", substitute);
+            continue;
+        };
+        let buy_limit = settings.order_price(state, order::Side::Buy, ask, None);
+        let scale = if state.fractional_shares { 100.0 } else { 1.0 };
+        let buy_qty = (qty * price / buy_limit * scale).floor() / scale;
+        if buy_qty > 0.0 {
+            state
+                .place_order(client, shutdown, substitute, order::Side::Buy, buy_limit, buy_qty, settings)
+                .await?;
+        }
+    }
+
+    Ok(harvested)
+}
@@ -0,0 +1,136 @@
+use rusqlite::{params, Connection};
+use std::collections::HashMap;
+
+use crate::error::Result;
+use crate::rebalancing_report::TriggerType;
+use crate::HistoryFormat;
+
+// One funding cycle's outcome. The allocations are fractions of the virtual
+// equity once the cycle's orders were monitored.
+pub struct HistoryRow {
+    pub date: String,
+    pub equity: f64,
+    pub cash: f64,
+    pub allocations: HashMap<String, f64>,
+    pub rmse: f64,
+    pub orders_placed: usize,
+    pub cash_deployed: f64,
+    pub trigger_type: TriggerType,
+}
+
+fn open(path: &str) -> Result<Connection> {
+    let conn = Connection::open(path)?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS portfolio_history (
+            date TEXT NOT NULL,
+            equity REAL NOT NULL,
+            cash REAL NOT NULL,
+            allocations TEXT NOT NULL,
+            rmse REAL NOT NULL,
+            orders_placed INTEGER NOT NULL,
+            cash_deployed REAL NOT NULL,
+            trigger TEXT NOT NULL DEFAULT 'scheduled',
+            max_drift REAL
+        )",
+        [],
+    )?;
+    // databases from before drift-triggered cycles lack the trigger columns
+    let has_trigger: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info('portfolio_history') WHERE name = 'trigger'",
+        [],
+        |r| r.get(0),
+    )?;
+    if !has_trigger {
+        conn.execute_batch(
+            "ALTER TABLE portfolio_history ADD COLUMN trigger TEXT NOT NULL DEFAULT 'scheduled';
+             ALTER TABLE portfolio_history ADD COLUMN max_drift REAL;",
+        )?;
+    }
+    Ok(conn)
+}
+
+// Appends the row, keeping every earlier one so the file doubles as an audit log.
+pub fn append(path: &str, row: &HistoryRow) -> Result<()> {
+    let conn = open(path)?;
+    let (trigger, max_drift) = match row.trigger_type {
+        TriggerType::Scheduled => ("scheduled", None),
+        TriggerType::DriftTriggered { max_drift } => ("drift_triggered", Some(max_drift)),
+    };
+    conn.execute(
+        "INSERT INTO portfolio_history (date, equity, cash, allocations, rmse, orders_placed, cash_deployed, trigger, max_drift)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![
+            row.date,
+            row.equity,
+            row.cash,
+            serde_json::to_string(&row.allocations)?,
+            row.rmse,
+            row.orders_placed as i64,
+            row.cash_deployed,
+            trigger,
+            max_drift,
+        ],
+    )?;
+    Ok(())
+}
+
+pub fn print_history(path: &str, format: HistoryFormat) -> Result<()> {
+    let conn = open(path)?;
+    let mut stmt = conn.prepare(
+        "SELECT date, equity, cash, allocations, rmse, orders_placed, cash_deployed, trigger
+         FROM portfolio_history ORDER BY rowid",
+    )?;
+    let rows = stmt.query_map([], |r| {
+        Ok((
+            r.get::<_, String>(0)?,
+            r.get::<_, f64>(1)?,
+            r.get::<_, f64>(2)?,
+            r.get::<_, String>(3)?,
+            r.get::<_, f64>(4)?,
+            r.get::<_, i64>(5)?,
+            r.get::<_, f64>(6)?,
+            r.get::<_, String>(7)?,
+        ))
+    })?;
+
+    if let HistoryFormat::Csv = format {
+        let mut writer = csv::Writer::from_writer(std::io::stdout());
+        writer.write_record([
+            "date",
+            "equity",
+            "cash",
+            "allocations",
+            "rmse",
+            "orders_placed",
+            "cash_deployed",
+            "trigger",
+        ])?;
+        for row in rows {
+            let (date, equity, cash, allocations, rmse, orders_placed, cash_deployed, trigger) = row?;
+            writer.write_record([
+                date,
+                format!("{:.2}", equity),
+                format!("{:.2}", cash),
+                allocations,
+                format!("{:.6}", rmse),
+                orders_placed.to_string(),
+                format!("{:.2}", cash_deployed),
+                trigger,
+            ])?;
+        }
+        writer.flush()?;
+    } else {
+        println!(
+            "{:<12}{:>14}{:>12}{:>10}{:>8}{:>12}  {:<17}Allocations",
+            "Date", "Equity", "Cash", "RMSE", "Orders", "Deployed", "Trigger"
+        );
+        for row in rows {
+            let (date, equity, cash, allocations, rmse, orders_placed, cash_deployed, trigger) = row?;
+            println!(
+                "{:<12}{:>14.2}{:>12.2}{:>10.4}{:>8}{:>12.2}  {:<17}{}",
+                date, equity, cash, rmse, orders_placed, cash_deployed, trigger, allocations
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,162 @@
+use crate::api::TimedClient;
+use crate::broker::Broker;
+use crate::error::Result;
+use chrono::{Datelike, Months, NaiveDate, Utc};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use tracing::warn;
+
+// Estimated dividend schedules for common ETFs, bundled into the binary.
+const DIVIDEND_SCHEDULES: &str = include_str!("../data/dividend_schedules.json");
+
+// Most US payers distribute quarterly, so assume this when the schedule is unknown.
+const QUARTERLY_MONTHS: [u32; 4] = [3, 6, 9, 12];
+
+// Payments are assumed to land mid-month of the ex-dividend month.
+const PAYMENT_DAY: u32 = 15;
+
+#[derive(Deserialize)]
+pub struct DividendSchedule {
+    pub annual_yield: f64,
+    pub ex_div_months: Vec<u32>,
+}
+
+pub fn bundled_schedules() -> Result<HashMap<String, DividendSchedule>> {
+    Ok(serde_json::from_str(DIVIDEND_SCHEDULES)?)
+}
+
+// The projection window covers the twelve calendar months following the one of `today`.
+fn projection_start(today: NaiveDate) -> NaiveDate {
+    NaiveDate::from_ymd_opt(today.year(), today.month(), 1).unwrap() + Months::new(1)
+}
+
+// Payments over the twelve months from the month of `start`.
+pub fn project_income_12m(
+    positions: &HashMap<String, f64>,
+    annual_yields: &HashMap<String, f64>,
+    ex_div_months: &HashMap<String, Vec<u32>>,
+    start: NaiveDate,
+) -> Vec<(NaiveDate, f64, String)> {
+    let first_month = start.with_day(1).unwrap();
+
+    let mut payments: Vec<_> = positions
+        .iter()
+        .filter_map(|(sym, &equity)| {
+            let annual_yield = *annual_yields.get(sym)?;
+            let months = ex_div_months
+                .get(sym)
+                .filter(|m| !m.is_empty())
+                .map(|m| m.as_slice())
+                .unwrap_or(&QUARTERLY_MONTHS);
+            let amount = equity * annual_yield / months.len() as f64;
+
+            Some((0..12).filter_map(move |offset| {
+                let month = first_month + Months::new(offset);
+                months.contains(&month.month()).then(|| {
+                    (month.with_day(PAYMENT_DAY).unwrap(), amount, sym.clone())
+                })
+            }))
+        })
+        .flatten()
+        .collect();
+
+    payments.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.2.cmp(&b.2)));
+    payments
+}
+
+pub fn monthly_income(payments: &[(NaiveDate, f64, String)], start: NaiveDate) -> Vec<(NaiveDate, f64)> {
+    let first_month = start.with_day(1).unwrap();
+    let mut months: BTreeMap<_, _> = (0..12)
+        .map(|offset| (first_month + Months::new(offset), 0.0))
+        .collect();
+
+    for (date, amount, _) in payments {
+        *months.entry(date.with_day(1).unwrap()).or_insert(0.0) += amount;
+    }
+
+    months.into_iter().collect()
+}
+
+pub async fn print_income_calendar(client: &TimedClient) -> Result<()> {
+    let schedules = bundled_schedules()?;
+
+    let pos: Vec<_> = client.get_positions().await?;
+    let positions: HashMap<_, _> = pos
+        .iter()
+        .map(|pos| {
+            (
+                pos.symbol.clone(),
+                pos.market_value.as_ref().unwrap().to_f64().unwrap(),
+            )
+        })
+        .collect();
+
+    let annual_yields = schedules
+        .iter()
+        .map(|(sym, s)| (sym.clone(), s.annual_yield))
+        .collect();
+    let ex_div_months = schedules
+        .iter()
+        .map(|(sym, s)| (sym.clone(), s.ex_div_months.clone()))
+        .collect();
+
+    let start = projection_start(Utc::now().date_naive());
+    let payments = project_income_12m(&positions, &annual_yields, &ex_div_months, start);
+
+    for sym in positions.keys().filter(|sym| !schedules.contains_key(*sym)) {
+        warn!("No dividend estimate bundled for {}, excluded from the projection", sym);
+    }
+
+    println!("{:<10}{:>14}", "Month", "Income");
+    for (month, amount) in monthly_income(&payments, start) {
+        println!("{:<10}{:>14.2}", month.format("%Y-%m"), amount);
+    }
+    println!(
+        "{:<10}{:>14.2}",
+        "Total",
+        payments.iter().map(|(_, amount, _)| amount).sum::<f64>()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn monthly_income_adds_up_to_the_annual_income() {
+        let positions = HashMap::from([
+            ("VTI".to_string(), 10000.0),
+            ("BND".to_string(), 6000.0),
+            ("XYZ".to_string(), 2000.0),
+            ("NODIV".to_string(), 5000.0),
+        ]);
+        let annual_yields = HashMap::from([
+            ("VTI".to_string(), 0.015),
+            ("BND".to_string(), 0.036),
+            ("XYZ".to_string(), 0.05),
+        ]);
+        // XYZ falls back to quarterly payments
+        let ex_div_months = HashMap::from([
+            ("VTI".to_string(), vec![3, 6, 9, 12]),
+            ("BND".to_string(), (1..=12).collect()),
+        ]);
+
+        let today = NaiveDate::from_ymd_opt(2024, 6, 20).unwrap();
+        let start = projection_start(today);
+        assert_eq!(start, NaiveDate::from_ymd_opt(2024, 7, 1).unwrap());
+
+        let payments = project_income_12m(&positions, &annual_yields, &ex_div_months, start);
+        let months = monthly_income(&payments, start);
+        assert_eq!(months.len(), 12);
+        assert_eq!(months[0].0, start);
+        assert_eq!(months[11].0, NaiveDate::from_ymd_opt(2025, 6, 1).unwrap());
+
+        let annual = 10000.0 * 0.015 + 6000.0 * 0.036 + 2000.0 * 0.05;
+        let total: f64 = months.iter().map(|(_, amount)| amount).sum();
+        assert!((total - annual).abs() < 1e-9, "{} != {}", total, annual);
+        // BND alone pays in August
+        assert!((months[1].1 - 6000.0 * 0.036 / 12.0).abs() < 1e-9);
+    }
+}
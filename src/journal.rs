@@ -0,0 +1,114 @@
+use apca::api::v2::order;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use tracing::error;
+
+use crate::error::Result;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JournalEvent {
+    Submitted,
+    Filled,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub timestamp: DateTime<Utc>,
+    pub event: JournalEvent,
+    pub symbol: String,
+    pub side: order::Side,
+    pub quantity: f64,
+    pub price: f64,
+    pub order_id: String,
+    // The order's parameters and fill time, missing from entries written
+    // before they were recorded.
+    #[serde(default)]
+    pub client_order_id: Option<String>,
+    #[serde(default)]
+    pub order_type: Option<order::Type>,
+    #[serde(default)]
+    pub time_in_force: Option<order::TimeInForce>,
+    #[serde(default)]
+    pub limit_price: Option<f64>,
+    #[serde(default)]
+    pub extended_hours: bool,
+    #[serde(default)]
+    pub filled_at: Option<DateTime<Utc>>,
+    // Only on the submissions of orders a funding cycle planned.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub drift_error: Option<DriftError>,
+}
+
+// The allocation error `best_asset_to_fund` minimizes, before and after an
+// order's funding is added to the projected equities.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DriftError {
+    pub before: f64,
+    pub after: f64,
+}
+
+// Appends one JSON object per line. Each line is written in a single call so
+// a reader tailing the file never sees a partial entry.
+pub fn append_to_journal(
+    path: &str,
+    event: JournalEvent,
+    order: &order::Order,
+    quantity: f64,
+    price: f64,
+    drift_error: Option<DriftError>,
+) -> Result<()> {
+    let entry = JournalEntry {
+        timestamp: Utc::now(),
+        event,
+        symbol: order.symbol.clone(),
+        side: order.side,
+        quantity,
+        price,
+        order_id: order.id.to_string(),
+        client_order_id: Some(order.client_order_id.clone()),
+        order_type: Some(order.type_),
+        time_in_force: Some(order.time_in_force),
+        limit_price: order.limit_price.as_ref().and_then(|p| p.to_f64()),
+        extended_hours: order.extended_hours,
+        filled_at: order.filled_at,
+        drift_error,
+    };
+    let mut line = serde_json::to_string(&entry)?;
+    line.push('\n');
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+// The order has already been placed by the time it is journaled, so a failed
+// write is logged rather than aborting the funding cycle.
+pub fn record(
+    path: Option<&str>,
+    event: JournalEvent,
+    order: &order::Order,
+    quantity: f64,
+    price: f64,
+    drift_error: Option<DriftError>,
+) {
+    if let Some(path) = path {
+        if let Err(e) = append_to_journal(path, event, order, quantity, price, drift_error) {
+            error!("Failed to append to journal {}: {}", path, e);
+        }
+    }
+}
+
+// The filled entries in the order they were written.
+pub fn read_fills(path: &str) -> Result<Vec<JournalEntry>> {
+    let mut fills = Vec::new();
+    for line in fs::read_to_string(path)?.lines().filter(|l| !l.trim().is_empty()) {
+        let entry: JournalEntry = serde_json::from_str(line)?;
+        if entry.event == JournalEvent::Filled {
+            fills.push(entry);
+        }
+    }
+    Ok(fills)
+}
@@ -0,0 +1,4388 @@
+mod allocation;
+mod allocator;
+mod api;
+mod backtest;
+mod broker;
+mod config;
+mod cost_basis;
+mod encryption;
+mod error;
+mod fill_session;
+mod harvest;
+mod history;
+mod income;
+mod journal;
+mod log_analysis;
+mod performance;
+mod persistence;
+mod planner;
+mod pricing;
+mod rebalancing_report;
+mod reconcile;
+mod rounding;
+mod schedule;
+mod shutdown;
+mod simulator;
+mod slack;
+mod snapshot;
+mod stats;
+mod stress_test;
+mod sweep;
+#[cfg(test)]
+mod testing;
+mod trade_search;
+mod trade_updates;
+mod universe;
+mod volatility;
+mod watchlist;
+
+use apca::ApiInfo;
+use apca::Client;
+use apca::RequestError;
+
+use api::{AlpacaClient, TimedClient};
+use broker::Broker;
+use config::RebalanceWeights;
+use rebalancing_report::TriggerType;
+use shutdown::Shutdown;
+use simulator::Simulator;
+use trade_updates::TradeUpdates;
+use stats::mean;
+pub use allocator::Allocator;
+pub use error::{Error, Result};
+pub use persistence::{SqliteStateStore, StateBackend, StatePersistence, StateStore};
+pub use planner::FundingPlanner;
+pub use schedule::FundingFrequency;
+pub use trade_search::TradeSearch;
+
+use apca::api::v2::{asset, calendar, order, orders, position};
+use chrono::{DateTime, Duration, Months, NaiveDate, TimeZone, Utc};
+use chrono_tz::US::Eastern;
+use num_decimal::Num;
+use std::str::FromStr;
+use std::time;
+use uuid::Uuid;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use clap::{Parser, Subcommand, ValueEnum};
+use tracing::{debug, error, info, warn, Instrument};
+
+
+fn normalize_vec(mut v: Vec<f64>) -> Vec<f64> {
+    let sum = v.iter().cloned().sum::<f64>();
+    for val in &mut v {
+        *val /= sum;
+    }
+    v
+}
+
+fn normalize_map(m: &mut HashMap<String, f64>) {
+    let sum = m.values().sum::<f64>();
+    if sum > 0.0 {
+        for val in m.values_mut() {
+            *val /= sum;
+        }
+    }
+}
+
+fn error(
+    stock_fractions: impl Iterator<Item = f64>,
+    ideal_fractions: impl Iterator<Item = f64>,
+) -> Option<f64> {
+    mean(
+        stock_fractions
+            .zip(ideal_fractions)
+            .map(|(v1, v2)| v1 - v2)
+            .map(|v| v * v),
+    )
+}
+
+// Weights each squared deviation by `weights`, so with the ideal fractions as
+// the weights a miss on a large position costs more than one on a small one.
+fn weighted_error(
+    stock_fractions: impl Iterator<Item = f64>,
+    ideal_fractions: impl Iterator<Item = f64>,
+    weights: impl Iterator<Item = f64>,
+) -> f64 {
+    stock_fractions
+        .zip(ideal_fractions)
+        .zip(weights)
+        .map(|((f, a), w)| w * (f - a) * (f - a))
+        .sum()
+}
+
+// Evaluates buying `buy_sizes` worth of each asset, and selling one share of
+// each asset that `can_sell`, returning the trade that minimizes the error.
+// Sells are only considered when they reduce the error. Trades with the same
+// error go to the lower index, so the assets should be ordered by symbol.
+//
+// The error is the mean squared error, or the `weighted_error` with the ideal
+// allocations as weights when `weighted`. A trade changes one equity and the
+// total, so its error follows from the sums below in constant time instead of
+// recomputing every fraction:
+// err = sum(w * e^2) / t^2 - 2 * sum(w * e * a) / t + sum(w * a^2)
+pub fn best_asset_to_fund(
+    stock_equities: impl Iterator<Item = f64> + Clone,
+    stock_prices: impl Iterator<Item = f64>,
+    buy_sizes: impl Iterator<Item = f64>,
+    ideal_allocations: impl Iterator<Item = f64> + Clone,
+    can_buy: impl Fn(usize) -> bool,
+    can_sell: impl Fn(usize) -> bool,
+    weighted: bool,
+) -> Option<(usize, order::Side, f64)> {
+    let equities: Vec<_> = stock_equities.collect();
+    let ideal: Vec<_> = ideal_allocations.take(equities.len()).collect();
+    let n = ideal.len() as f64;
+    let weights: Vec<_> = if weighted { ideal.clone() } else { vec![1.0 / n; ideal.len()] };
+
+    let total: f64 = equities.iter().sum();
+    let sum_ee: f64 = equities.iter().zip(&weights).map(|(e, w)| w * e * e).sum();
+    let sum_ea: f64 = equities.iter().zip(&ideal).zip(&weights).map(|((e, a), w)| w * e * a).sum();
+    let sum_aa: f64 = ideal.iter().zip(&weights).map(|(a, w)| w * a * a).sum();
+    let err_of = |sum_ee: f64, sum_ea: f64, total: f64| {
+        sum_ee / (total * total) - 2.0 * sum_ea / total + sum_aa
+    };
+
+    let current_err = planning_error(&equities, &ideal, weighted);
+
+    min_by_key_f64(
+        stock_prices
+            .zip(buy_sizes)
+            .take(ideal.len())
+            .enumerate()
+            .flat_map(|(i, (p, b))| [(i, order::Side::Buy, b), (i, order::Side::Sell, -p)])
+            .filter(|&(i, side, _)| match side {
+                order::Side::Buy => can_buy(i),
+                order::Side::Sell => can_sell(i),
+            })
+            .map(|(i, side, delta)| {
+                let (e, a, w) = (equities[i], ideal[i], weights[i]);
+                let err = err_of(
+                    sum_ee + w * (2.0 * e * delta + delta * delta),
+                    sum_ea + w * a * delta,
+                    total + delta,
+                );
+
+                (i, side, err)
+            })
+            .filter(|&(_, side, err)| side == order::Side::Buy || err < current_err),
+        |&(_, _, e)| e,
+    )
+}
+
+// The error `best_asset_to_fund` minimizes, for `equities` as they are.
+fn planning_error(equities: &[f64], ideal_allocations: &[f64], weighted: bool) -> f64 {
+    let total: f64 = equities.iter().sum();
+    let n = ideal_allocations.len() as f64;
+    weighted_error(
+        equities.iter().map(|e| if total > 0.0 { e / total } else { 0.0 }),
+        ideal_allocations.iter().cloned(),
+        ideal_allocations.iter().map(|&a| if weighted { a } else { 1.0 / n }),
+    )
+}
+
+// Keys within `f64::EPSILON` of the minimum so far count as ties, which the
+// earlier item wins.
+fn min_by_key_f64<B>(x: impl Iterator<Item = B>, key: impl Fn(&B) -> f64) -> Option<B> {
+    x.fold((f64::INFINITY, None), |(min, min_item), item| {
+        let k = key(&item);
+        if k < min - f64::EPSILON {
+            (k, Some(item))
+        } else {
+            (min, min_item)
+        }
+    })
+    .1
+}
+
+// The virtual equity of a position is the part of it bought by this program.
+fn virtual_equities(pos: &[position::Position], state: &State) -> Vec<f64> {
+    pos.iter()
+        .map(|pos| {
+            let e = pos.market_value.as_ref().unwrap().to_f64().unwrap();
+            let ref_e = state
+                .reference_equities
+                .get(&pos.symbol)
+                .cloned()
+                .unwrap_or(0.0);
+
+            (e - ref_e).max(0.0)
+        })
+        .collect()
+}
+
+// A portfolio only sees the symbols it allocates to, the account's other
+// positions belong to the other portfolios.
+fn retain_portfolio_positions(pos: &mut Vec<position::Position>, state: &State, config: Option<&config::Config>) {
+    if config.is_some_and(|c| c.capital_share.is_some()) {
+        pos.retain(|pos| state.ideal_allocations.contains_key(&pos.symbol) || state.watchlist.contains_key(&pos.symbol));
+    }
+}
+
+fn normalized_ideal_allocations(pos: &[position::Position], state: &State) -> Vec<f64> {
+    let ideal_allocations: Vec<_> = pos
+        .iter()
+        .map(|pos| {
+            state
+                .ideal_allocations
+                .get(&pos.symbol)
+                .cloned()
+                .unwrap_or(0.0)
+        })
+        .collect();
+    normalize_vec(ideal_allocations)
+}
+
+// Per-position weight limits, defaulting to `default` for symbols without one.
+fn allocation_bounds(pos: &[position::Position], bounds: &HashMap<String, f64>, default: f64) -> Vec<f64> {
+    pos.iter()
+        .map(|pos| bounds.get(&pos.symbol).cloned().unwrap_or(default))
+        .collect()
+}
+
+fn allocation_error(virtual_equities: &[f64], ideal_allocations: &[f64]) -> f64 {
+    let total: f64 = virtual_equities.iter().sum();
+    let fractions = virtual_equities
+        .iter()
+        .map(|e| if total > 0.0 { e / total } else { 0.0 });
+    error(fractions, ideal_allocations.iter().cloned()).unwrap_or(0.0)
+}
+
+// Levels at which each urgency component saturates.
+const URGENT_RMSE: f64 = 0.05;
+const URGENT_CASH_DRAG_DAYS: f64 = 30.0;
+const URGENT_MONTHLY_TURNOVER: f64 = 1.0;
+
+const URGENT_SCORE: f64 = 80.0;
+// Used when the turnover can't be fetched, so a failed lookup never raises an alert.
+const NEUTRAL_URGENCY: f64 = 1.0;
+
+fn urgency_score(
+    mse: f64,
+    cash_drag_days: f64,
+    monthly_turnover: f64,
+    weights: Option<RebalanceWeights>,
+) -> f64 {
+    let weights = weights.unwrap_or_default();
+    let components = [
+        (weights.mse, mse.max(0.0).sqrt() / URGENT_RMSE),
+        (weights.cash_drag, cash_drag_days.max(0.0) / URGENT_CASH_DRAG_DAYS),
+        (weights.turnover, monthly_turnover.max(0.0) / URGENT_MONTHLY_TURNOVER),
+    ];
+
+    let total_weight: f64 = components.iter().map(|(w, _)| w).sum();
+    if total_weight <= 0.0 {
+        return 0.0;
+    }
+
+    100.0 * components.iter().map(|(w, c)| w * c.min(1.0)).sum::<f64>() / total_weight
+}
+
+fn urgency_tag(score: f64) -> &'static str {
+    if score > URGENT_SCORE {
+        "[URGENT] "
+    } else {
+        ""
+    }
+}
+
+fn current_mse(pos: &[position::Position], state: &State) -> f64 {
+    allocation_error(
+        &virtual_equities(pos, state),
+        &normalized_ideal_allocations(pos, state),
+    )
+}
+
+// Largest gap between a position's share of the virtual equity and its ideal allocation.
+fn max_drift(pos: &[position::Position], state: &State) -> f64 {
+    let equities = virtual_equities(pos, state);
+    let total: f64 = equities.iter().sum();
+    if total <= 0.0 {
+        return 0.0;
+    }
+    equities
+        .iter()
+        .zip(normalized_ideal_allocations(pos, state))
+        .map(|(e, ideal)| (e / total - ideal).abs())
+        .fold(0.0, f64::max)
+}
+
+async fn portfolio_urgency(
+    client: &TimedClient,
+    state: &State,
+    mse: f64,
+    equity: f64,
+    weights: Option<RebalanceWeights>,
+) -> Result<f64> {
+    let cash_drag_days = state
+        .last_funding_date
+        .map(|dt| (Utc::now() - dt).num_days() as f64)
+        .unwrap_or(0.0);
+    let monthly_turnover = performance::fetch_monthly_turnover(client, equity).await?;
+
+    Ok(urgency_score(mse, cash_drag_days, monthly_turnover, weights))
+}
+
+use std::ops::ControlFlow;
+
+// An order's position index, side and funds.
+pub type PlannedOrder = (usize, order::Side, Num);
+
+// Converts a dollar amount to the exact decimal the order planning works in,
+// to the hundredth of a cent.
+fn to_money(amount: f64) -> Num {
+    Num::from_str(&format!("{:.4}", amount)).unwrap()
+}
+
+fn sum_money<'a>(amounts: impl IntoIterator<Item = &'a Num>) -> Num {
+    amounts.into_iter().fold(Num::from(0), |total, amount| total + amount)
+}
+
+fn to_f64s(amounts: &[Num]) -> Vec<f64> {
+    amounts.iter().map(|amount| amount.to_f64().unwrap()).collect()
+}
+
+// Sells never reduce a position below its reference equity, and a symbol is
+// only traded in one direction per batch so the orders can't oscillate. Buys
+// never push a position above its maximum weight, and funds left after the
+// greedy allocation top up positions below their minimum weight. Symbols with
+// a zero buy size, such as a zero price, are never bought. The equities and
+// the remaining funds are kept as exact decimals, so thousands of small buys
+// don't drift from the budget; only the error of each candidate is estimated
+// in floating point.
+#[allow(clippy::too_many_arguments)]
+pub fn generate_orders(
+    stock_equities: &[Num],
+    prices: &[Num],
+    buy_sizes: &[Num],
+    ideal_allocations: impl Iterator<Item = f64> + Clone,
+    min_allocations: &[f64],
+    max_allocations: &[f64],
+    max_fund: Num,
+    sell_enabled: bool,
+    fractional: bool,
+    weighted_error: bool,
+) -> Result<(Vec<PlannedOrder>, Vec<Num>)> {
+    let zero = Num::from(0);
+    let price_values = to_f64s(prices);
+    let buy_size_values = to_f64s(buy_sizes);
+    let mut orders: Vec<PlannedOrder> = Vec::new();
+    // selling is only used to rebalance on days with funding
+    let sell_enabled = sell_enabled && max_fund > zero;
+
+    // every step places an order, so the search runs until the budget or the
+    // candidates run out, however many cheap shares that takes
+    let mut traded = vec![None; prices.len()];
+    let mut stock_equities = stock_equities.to_vec();
+    let mut max_fund = max_fund;
+    let mut equities = to_f64s(&stock_equities);
+    let mut total: f64 = equities.iter().sum();
+    let mut search = TradeSearch::new(&equities, &price_values, &buy_size_values, ideal_allocations, weighted_error);
+
+    while let Some((idx, side)) = search.next(
+        |i| {
+            traded[i] != Some(order::Side::Sell)
+                && buy_sizes[i] > zero
+                && (equities[i] + buy_size_values[i]) / (total + buy_size_values[i]) <= max_allocations[i] + 1e-9
+        },
+        |i| sell_enabled && traded[i] != Some(order::Side::Buy) && stock_equities[i] >= prices[i],
+    ) {
+        let order_amount = match side {
+            order::Side::Buy => buy_sizes[idx].clone(),
+            order::Side::Sell => prices[idx].clone(),
+        };
+        // an order for nothing leaves the budget and the error as they
+        // were, so the search would pick it forever
+        if order_amount <= zero {
+            return Err(Error::UnexpectedData(format!(
+                "order generation stalled on a {:?} of position {} for {}",
+                side, idx, order_amount
+            )));
+        }
+        match side {
+            // with fractional shares the remaining funds buy part of a share
+            order::Side::Buy if order_amount > max_fund && fractional && max_fund > zero => {
+                stock_equities[idx] += &max_fund;
+                orders.push((idx, side, max_fund));
+                max_fund = zero.clone();
+                break;
+            }
+            order::Side::Buy if order_amount > max_fund => break,
+            order::Side::Buy => {
+                stock_equities[idx] += &order_amount;
+                max_fund -= &order_amount;
+            }
+            order::Side::Sell => {
+                stock_equities[idx] -= &order_amount;
+                max_fund += &order_amount;
+            }
+        }
+        orders.push((idx, side, order_amount));
+        traded[idx] = Some(side);
+
+        let equity = stock_equities[idx].to_f64().unwrap();
+        total += equity - equities[idx];
+        equities[idx] = equity;
+        search.apply(idx, side, equity);
+    }
+
+    for (idx, size) in buy_sizes.iter().enumerate() {
+        if *size <= zero || orders.iter().any(|&(i, s, _)| i == idx && s == order::Side::Sell) {
+            continue;
+        }
+
+        loop {
+            let total = sum_money(&stock_equities);
+            let fraction = if total > zero { (&stock_equities[idx] / total).to_f64().unwrap() } else { 0.0 };
+            if fraction >= min_allocations[idx] {
+                break;
+            }
+
+            let order_amount = if *size <= max_fund {
+                size.clone()
+            } else if fractional && max_fund > zero {
+                max_fund.clone()
+            } else {
+                break;
+            };
+            stock_equities[idx] += &order_amount;
+            max_fund -= &order_amount;
+            orders.push((idx, order::Side::Buy, order_amount));
+        }
+    }
+
+    Ok((orders, stock_equities))
+}
+
+// Sells of overweight symbols are placed slightly above the market.
+const TRIM_SELL_LIMIT_FACTOR: f64 = 1.001;
+
+// Sells enough of each symbol whose fraction is more than `threshold` above
+// its ideal allocation to bring it back to the ideal, counting the smaller
+// total after the sale. Only virtual equity is sold.
+fn trim_orders(
+    stock_equities: &[f64],
+    prices: &[f64],
+    ideal_allocations: &[f64],
+    threshold: f64,
+    fractional: bool,
+) -> Vec<PlannedOrder> {
+    let total: f64 = stock_equities.iter().sum();
+    if total <= 0.0 {
+        return Vec::new();
+    }
+    let scale = if fractional { 100.0 } else { 1.0 };
+
+    (0..stock_equities.len())
+        .filter(|&i| stock_equities[i] / total - ideal_allocations[i] > threshold)
+        .filter_map(|i| {
+            let (e, a) = (stock_equities[i], ideal_allocations[i]);
+            let excess = ((e - a * total) / (1.0 - a)).min(e);
+            let qty = (excess / prices[i] * scale).floor() / scale;
+            (qty > 0.0).then(|| (i, order::Side::Sell, to_money(qty * prices[i])))
+        })
+        .collect()
+}
+
+// Merges the orders for the same symbol and side into one, in the order each
+// first appears, so every symbol is submitted at most once per side.
+fn consolidate_orders(orders: Vec<PlannedOrder>) -> Vec<PlannedOrder> {
+    let mut consolidated: Vec<PlannedOrder> = Vec::new();
+    for (idx, side, funding) in orders {
+        match consolidated.iter_mut().find(|(i, s, _)| *i == idx && *s == side) {
+            Some((_, _, total)) => *total += funding,
+            None => consolidated.push((idx, side, funding)),
+        }
+    }
+    consolidated
+}
+
+// Alpaca rejects notional orders for less than a dollar.
+const MIN_NOTIONAL: f64 = 1.0;
+
+// Order options the config sets for a whole funding cycle.
+#[derive(Clone, Copy, Default)]
+struct OrderSettings {
+    order_type: pricing::OrderType,
+    extended_hours: bool,
+    // Buys of fractionable symbols are placed for their funding in dollars.
+    notional: bool,
+    marketable_limit_collar: f64,
+}
+
+impl OrderSettings {
+    // Market orders are sized at the current price, without a limit.
+    fn order_price(&self, state: &State, side: order::Side, price: f64, quote: Option<(f64, f64)>) -> f64 {
+        match self.order_type {
+            pricing::OrderType::Limit => {
+                state
+                    .limit_price_strategy
+                    .limit_price(side, price, quote, state.limit_price_factor)
+            }
+            pricing::OrderType::Market => price,
+            pricing::OrderType::MarketableLimit => {
+                pricing::marketable_limit_price(side, price, quote, self.marketable_limit_collar)
+            }
+        }
+    }
+}
+
+// `limit_price` is ignored for market orders.
+fn order_request(
+    sym: &str,
+    side: order::Side,
+    limit_price: f64,
+    qty: f64,
+    fractional: bool,
+    settings: OrderSettings,
+) -> Result<order::OrderReq> {
+    // fractional quantities are submitted to the hundredth of a share, rounded
+    // down so the order never costs more than planned; the epsilon keeps
+    // quantities like 0.29 from flooring to 0.28
+    let qty = if fractional { (qty * 100.0 + 1e-9).floor() / 100.0 } else { qty };
+    // Alpaca rejects these with an unhelpful error
+    if (fractional && qty < 0.01) || (!fractional && qty < 1.0) {
+        return Err(Error::OrderRejected {
+            symbol: sym.to_string(),
+            reason: "quantity below minimum".into(),
+        });
+    }
+
+    let qty = if fractional {
+        Num::from_str(&format!("{:.2}", qty)).unwrap()
+    } else {
+        Num::from(qty as usize)
+    };
+
+    let request = match settings.order_type {
+        // Alpaca only fills day limit orders outside regular hours
+        pricing::OrderType::Limit | pricing::OrderType::MarketableLimit => order::OrderReqInit {
+            type_: order::Type::Limit,
+            limit_price: Some(Num::from_str(&format!("{:.2}", limit_price)).unwrap()),
+            time_in_force: order::TimeInForce::Day,
+            extended_hours: settings.extended_hours,
+            ..Default::default()
+        },
+        pricing::OrderType::Market => order::OrderReqInit {
+            type_: order::Type::Market,
+            time_in_force: order::TimeInForce::Day,
+            ..Default::default()
+        },
+    }
+    .init(sym, side, order::Amount::quantity(qty));
+
+    Ok(request)
+}
+
+// Orders `amount` dollars of `sym` at market, the only way Alpaca takes
+// notional orders, so the funding is spent exactly. The asset must be
+// fractionable.
+fn notional_order_request(sym: &str, side: order::Side, amount: f64) -> Result<order::OrderReq> {
+    if amount < MIN_NOTIONAL {
+        return Err(Error::OrderRejected {
+            symbol: sym.to_string(),
+            reason: "notional below minimum".into(),
+        });
+    }
+
+    let request = order::OrderReqInit {
+        type_: order::Type::Market,
+        time_in_force: order::TimeInForce::Day,
+        ..Default::default()
+    }
+    .init(sym, side, order::Amount::notional(Num::from_str(&format!("{:.2}", amount)).unwrap()));
+
+    Ok(request)
+}
+
+// An order is a duplicate of an open one on the same side of the same symbol
+// when their quantities, or their notional amounts, are within 1% of each other.
+fn is_duplicate_order(open: &order::Order, side: order::Side, amount: f64, notional: bool) -> bool {
+    let open_amount = match (&open.amount, notional) {
+        (order::Amount::Quantity { quantity }, false) => quantity.to_f64().unwrap(),
+        (order::Amount::Notional { notional }, true) => notional.to_f64().unwrap(),
+        _ => return false,
+    };
+    open.side == side && (open_amount - amount).abs() <= 0.01 * amount
+}
+
+// Whether a matching order is already open, e.g. after a restart partway
+// through the day.
+async fn has_open_duplicate(
+    client: &TimedClient,
+    sym: &str,
+    side: order::Side,
+    amount: f64,
+    notional: bool,
+) -> Result<bool> {
+    let request = orders::OrdersReq {
+        symbols: vec![sym.to_string()],
+        ..Default::default()
+    };
+    let open_orders = client.issue::<orders::Get>(&request).await?;
+
+    let Some(open) = open_orders.iter().find(|o| is_duplicate_order(o, side, amount, notional)) else {
+        return Ok(false);
+    };
+    let amount = if notional { format!("${:.2} of", amount) } else { amount.to_string() };
+    info!(
+        "A matching {:?} order for {} {} is already open ({}), not submitting another",
+        side,
+        amount,
+        sym,
+        open.id.as_hyphenated()
+    );
+    Ok(true)
+}
+
+// Submits the request unless a matching order is already open, e.g. after a
+// restart partway through the day, in which case it returns `None`.
+async fn submit_order_idempotent(
+    client: &TimedClient,
+    request: &order::OrderReq,
+    shutdown: &Shutdown,
+) -> Result<Option<order::Order>> {
+    let (amount, notional) = match &request.amount {
+        order::Amount::Quantity { quantity } => (quantity.to_f64().unwrap(), false),
+        order::Amount::Notional { notional } => (notional.to_f64().unwrap(), true),
+    };
+    if has_open_duplicate(client, &request.symbol.to_string(), request.side, amount, notional).await? {
+        return Ok(None);
+    }
+
+    submit_unless_stopped(client, request, shutdown).await
+}
+
+// The last check before an order reaches the broker. Returns `None` without
+// submitting once a shutdown is requested or the stop file exists.
+async fn submit_unless_stopped(
+    broker: &impl Broker,
+    request: &order::OrderReq,
+    shutdown: &Shutdown,
+) -> Result<Option<order::Order>> {
+    if shutdown.trading_stopped().await {
+        warn!("Trading stopped, not submitting the {:?} of {}", request.side, request.symbol);
+        return Ok(None);
+    }
+    broker.submit_order(request).await.map(Some)
+}
+
+// The client order id of a funding cycle's order, the same for every run on
+// the same day so a resubmission after a restart can be recognized. Alpaca
+// rejects a client order id used twice in an account, so the id starts with a
+// hash of the state file, which portfolios sharing an account never share.
+fn funding_order_id(state_filename: &str, date: NaiveDate, sym: &str, side: order::Side) -> String {
+    let portfolio: String = Sha256::digest(state_filename.as_bytes())[..4]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    let side = match side {
+        order::Side::Buy => "buy",
+        order::Side::Sell => "sell",
+    };
+    format!("apca_balancer-{}-{}-{}-{}", portfolio, date, sym, side)
+}
+
+// The order Alpaca has under `client_order_id`, if any.
+async fn find_order_by_client_id(client: &impl AlpacaClient, client_order_id: &str) -> Result<Option<order::Order>> {
+    match client.issue::<order::GetByClientId>(&client_order_id.to_string()).await {
+        Ok(order) => Ok(Some(order)),
+        Err(RequestError::Endpoint(order::GetByClientIdError::NotFound(_))) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// The symbols Alpaca takes fractional, and so notional, orders for.
+async fn fractionable_symbols(client: &TimedClient, syms: impl IntoIterator<Item = String>) -> Result<HashSet<String>> {
+    let mut fractionable = HashSet::new();
+    for sym in syms {
+        let asset = client.issue::<asset::Get>(&asset::Symbol::Sym(sym.clone())).await?;
+        if asset.fractionable {
+            fractionable.insert(sym);
+        }
+    }
+    Ok(fractionable)
+}
+
+// A submitted order whose fill hasn't been confirmed yet.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PendingOrder {
+    id: String,
+    symbol: String,
+    quantity: f64,
+    submitted_at: DateTime<Utc>,
+    // Times the order's limit price has been moved toward the market.
+    reprices: u32,
+}
+
+impl PendingOrder {
+    fn new(order: &order::Order, quantity: f64) -> Self {
+        PendingOrder {
+            id: order.id.to_string(),
+            symbol: order.symbol.clone(),
+            quantity,
+            submitted_at: Utc::now(),
+            reprices: 0,
+        }
+    }
+}
+
+// The unfilled part of an order that ended partially filled, submitted again
+// at market on the next trading day.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RequeuedOrder {
+    symbol: String,
+    side: order::Side,
+    quantity: f64,
+}
+
+// Cancels and stops tracking orders submitted more than `ttl` ago, so an order
+// left over from a crash can't fill days later on top of new orders.
+async fn expire_stale_orders(client: &TimedClient, pending_orders: &mut Vec<PendingOrder>, ttl: Duration) -> Result<()> {
+    let now = Utc::now();
+    let (stale, fresh): (Vec<_>, Vec<_>) = pending_orders
+        .drain(..)
+        .partition(|pending| now - pending.submitted_at > ttl);
+    *pending_orders = fresh;
+
+    for pending in stale {
+        let id = order::Id(Uuid::parse_str(&pending.id)?);
+        let order = client.issue::<order::Get>(&id).await?;
+        if !order.status.is_terminal() {
+            client.issue::<order::Delete>(&id).await?;
+            warn!(
+                "Canceled order {} for {} {} submitted at {}",
+                pending.id, pending.quantity, pending.symbol, pending.submitted_at
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn fill_price(order: &order::Order) -> f64 {
+    order.average_fill_price.as_ref().and_then(|p| p.to_f64()).unwrap_or(0.0)
+}
+
+// The quantity an order left unfilled, or `None` for notional orders.
+fn unfilled_quantity(order: &order::Order) -> Option<Num> {
+    let order::Amount::Quantity { quantity } = &order.amount else {
+        return None;
+    };
+    Some(quantity.clone() - order.filled_quantity.clone())
+}
+
+// How long `monitor_and_fill` waits on limit orders, and how it replaces them.
+#[derive(Clone, Copy)]
+struct FillSettings {
+    poll_interval: time::Duration,
+    timeout: time::Duration,
+    // Times a canceled limit order is resubmitted at a price `reprice_step`
+    // closer to the market before falling back to a market order.
+    reprice_attempts: u32,
+    reprice_step: f64,
+}
+
+// The limit price `step` past `limit_price` toward the other side of the market.
+fn repriced_limit(side: order::Side, limit_price: f64, step: f64) -> f64 {
+    let price = match side {
+        order::Side::Buy => limit_price * (1.0 + step),
+        order::Side::Sell => limit_price * (1.0 - step),
+    };
+    (price * 100.0).round() / 100.0
+}
+
+// Polls the orders until they fill. Limit orders still open at the deadline are
+// canceled and the unfilled quantity is resubmitted at a limit price moved
+// toward the market, up to `reprice_attempts` times, and then as a market
+// order. Each repriced order gets a new timeout. Fills are
+// accounted at Alpaca's average fill price, and only the quantity filled. Orders
+// that remain unconfirmed are left in `pending_orders`, and the unfilled part
+// of other orders that ended partially filled is returned to be requeued.
+// With `updates`, orders are confirmed as the trade updates stream reports
+// them, and only polled every `poll_interval` and at the deadline in case an
+// update was missed.
+async fn monitor_and_fill(
+    client: &impl AlpacaClient,
+    pending_orders: &mut Vec<PendingOrder>,
+    settings: FillSettings,
+    mut updates: Option<&mut TradeUpdates>,
+    journal_path: Option<&str>,
+    shutdown: &Shutdown,
+    mut on_fill: impl FnMut(&str, order::Side, f64, f64),
+) -> Result<Vec<RequeuedOrder>> {
+    let mut deadline = time::Instant::now() + settings.timeout;
+    let mut next_poll = time::Instant::now();
+    let mut canceled = false;
+    let mut requeued = Vec::new();
+    let mut record_fill = |order: &order::Order| {
+        let (qty, price) = (order.filled_quantity.to_f64().unwrap(), fill_price(order));
+        if qty > 0.0 {
+            journal::record(journal_path, journal::JournalEvent::Filled, order, qty, price, None);
+            on_fill(&order.symbol, order.side, qty, price);
+        }
+    };
+
+    while !pending_orders.is_empty() {
+        let mut still_pending = Vec::new();
+        let mut open_limit_orders = Vec::new();
+        let now = time::Instant::now();
+        let poll = now >= next_poll || (now >= deadline && !canceled);
+        if poll {
+            next_poll = now + settings.poll_interval;
+        }
+
+        for pending in pending_orders.iter() {
+            let id = &pending.id;
+            let order_id = order::Id(Uuid::parse_str(id)?);
+            let order = match updates.as_mut().and_then(|updates| updates.take(&order_id)) {
+                Some(order) => order,
+                None if poll => client.issue::<order::Get>(&order_id).await?,
+                None => {
+                    still_pending.push(pending.clone());
+                    continue;
+                }
+            };
+
+            match order.status {
+                order::Status::Filled => {
+                    info!("Order {} for {} filled", id, order.symbol);
+                    record_fill(&order);
+                }
+                order::Status::Canceled | order::Status::Expired if order.type_ == order::Type::Limit => {
+                    // the part filled before the cancellation is still held
+                    record_fill(&order);
+
+                    let Some(remaining) = unfilled_quantity(&order) else {
+                        continue;
+                    };
+                    let quantity = remaining.to_f64().unwrap();
+                    if quantity <= 0.0 {
+                        continue;
+                    }
+
+                    let limit_price = order.limit_price.as_ref().and_then(|p| p.to_f64());
+                    if let Some(limit_price) = limit_price.filter(|_| pending.reprices < settings.reprice_attempts) {
+                        let new_limit = repriced_limit(order.side, limit_price, settings.reprice_step);
+                        warn!(
+                            "Resubmitting {} {} at {:.2} instead of {:.2} (attempt {} of {})",
+                            remaining,
+                            order.symbol,
+                            new_limit,
+                            limit_price,
+                            pending.reprices + 1,
+                            settings.reprice_attempts
+                        );
+                        let request = order::OrderReqInit {
+                            type_: order::Type::Limit,
+                            limit_price: Some(Num::from_str(&format!("{:.2}", new_limit)).unwrap()),
+                            time_in_force: order::TimeInForce::Day,
+                            ..Default::default()
+                        }
+                        .init(&order.symbol, order.side, order::Amount::quantity(remaining));
+                        let limit_order = client.submit_order(&request).await?;
+                        journal::record(
+                            journal_path,
+                            journal::JournalEvent::Submitted,
+                            &limit_order,
+                            quantity,
+                            new_limit,
+                            None,
+                        );
+                        still_pending.push(PendingOrder {
+                            reprices: pending.reprices + 1,
+                            ..PendingOrder::new(&limit_order, quantity)
+                        });
+                        // the repriced order gets the full timeout to fill
+                        deadline = time::Instant::now() + settings.timeout;
+                        canceled = false;
+                        continue;
+                    }
+
+                    warn!("Resubmitting {} {} as a market order", remaining, order.symbol);
+                    let request = order::OrderReqInit {
+                        type_: order::Type::Market,
+                        time_in_force: order::TimeInForce::Day,
+                        ..Default::default()
+                    }
+                    .init(&order.symbol, order.side, order::Amount::quantity(remaining));
+                    let market_order = client.submit_order(&request).await?;
+                    // the market order has no limit, so the canceled order's is journaled
+                    let price = limit_price.unwrap_or(0.0);
+                    journal::record(
+                        journal_path,
+                        journal::JournalEvent::Submitted,
+                        &market_order,
+                        quantity,
+                        price,
+                        None,
+                    );
+                    still_pending.push(PendingOrder::new(&market_order, quantity));
+                }
+                status if status.is_terminal() && order.filled_quantity.to_f64().unwrap() > 0.0 => {
+                    record_fill(&order);
+                    let quantity = unfilled_quantity(&order).and_then(|q| q.to_f64()).unwrap_or(0.0);
+                    if quantity > 0.0 {
+                        warn!(
+                            "Order {} for {} ended as {:?} with {} unfilled, requeuing it for the next trading day",
+                            id, order.symbol, status, quantity
+                        );
+                        requeued.push(RequeuedOrder {
+                            symbol: order.symbol.clone(),
+                            side: order.side,
+                            quantity,
+                        });
+                    }
+                }
+                status if status.is_terminal() => {
+                    warn!("Order {} for {} ended as {:?} without filling", id, order.symbol, status)
+                }
+                _ => {
+                    if order.type_ == order::Type::Limit {
+                        open_limit_orders.push(order.id);
+                    }
+                    still_pending.push(pending.clone());
+                }
+            }
+        }
+
+        *pending_orders = still_pending;
+
+        if time::Instant::now() >= deadline {
+            // market orders and cancellations that haven't settled are rechecked next iteration
+            if canceled || open_limit_orders.is_empty() {
+                break;
+            }
+            for id in &open_limit_orders {
+                client.issue::<order::Delete>(id).await?;
+            }
+            canceled = true;
+        }
+
+        // on shutdown the remaining orders are rechecked on the next start
+        if pending_orders.is_empty() {
+            break;
+        }
+        let waited = match updates.as_mut() {
+            Some(updates) => {
+                let until = if canceled { next_poll } else { next_poll.min(deadline) };
+                let timeout = until.saturating_duration_since(time::Instant::now());
+                shutdown.run_until(updates.wait(timeout)).await
+            }
+            None => shutdown.run_until(tokio::time::sleep(settings.poll_interval)).await,
+        };
+        if waited.is_none() {
+            break;
+        }
+    }
+
+    Ok(requeued)
+}
+
+// Stands in for `submit_order` in a dry run, adding the order to the projected equity.
+fn simulate_order(sym: &str, side: order::Side, limit_price: f64, qty: f64, projected_equity: &mut f64) {
+    let cost = limit_price * qty;
+    info!("Would {:?} {} {} at {:.2} for about ${:.2}", side, qty, sym, limit_price, cost);
+
+    match side {
+        order::Side::Buy => *projected_equity += cost,
+        order::Side::Sell => *projected_equity -= cost,
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct State {
+    pub version: u32,
+    pub fund_accum: f64, 
+    pub last_funding_date: Option<DateTime<Utc>>, 
+    pub reference_equities: HashMap<String, f64>, 
+    pub ideal_allocations: HashMap<String, f64>,
+    pub target_investment_equity_ratio: f64,
+    pub finish_date: DateTime<Utc>,
+    pub limit_price_strategy: pricing::LimitPriceStrategy,
+    pub limit_price_factor: f64,
+    pub rounding_strategy: rounding::RoundingStrategy,
+    pub sell_enabled: bool,
+    pub fractional_shares: bool,
+    pub funding_frequency: schedule::FundingFrequency,
+    // Minimum RMSE between current and ideal allocations required to place orders.
+    pub min_rebalance_drift: f64,
+    // Weight limits per symbol, as fractions of the virtual equity.
+    pub min_allocations: HashMap<String, f64>,
+    pub max_allocations: HashMap<String, f64>,
+    pub universe: Option<universe::DynamicUniverse>,
+    pub equity_history: Vec<(DateTime<Utc>, f64)>,
+    pub api_latency_avg_ms: HashMap<String, f64>,
+    pub api_latency_p99_ms: HashMap<String, f64>,
+    pub pending_orders: Vec<PendingOrder>,
+    // Orders still pending this many hours after submission are canceled.
+    pub pending_order_ttl_hours: u64,
+    pub fill_poll_interval_secs: u64,
+    pub fill_timeout_minutes: u64,
+    // Times a limit order unfilled at the timeout is repriced by `reprice_step`
+    // before it is resubmitted at market.
+    pub reprice_attempts: u32,
+    pub reprice_step: f64,
+    // Fraction of a reference equity a position can fall short of before it is reported.
+    pub reconciliation_threshold: f64,
+    pub reconcile_reference_equities: bool,
+    // Orders are appended here as JSON lines when submitted and when filled.
+    pub journal_path: Option<String>,
+    // Number of previous state files kept when saving.
+    pub state_backups: usize,
+    // Highest account equity seen, and the largest fraction it has fallen below it.
+    pub equity_high_watermark: f64,
+    pub max_drawdown: f64,
+    // Symbols waiting to join `ideal_allocations`, with their target allocations.
+    pub watchlist: HashMap<String, f64>,
+    // Returns are compared against this symbol's price and the equity as of
+    // the first funding cycle.
+    pub benchmark_symbol: String,
+    pub benchmark_reference_price: Option<f64>,
+    pub initial_equity: Option<f64>,
+    // Dollars paid for the shares of each symbol this program still holds.
+    pub cost_basis: HashMap<String, f64>,
+    pub shares_held: HashMap<String, f64>,
+    // Symbols sold at a loss, and when they may be bought again.
+    pub harvest_cooldowns: HashMap<String, DateTime<Utc>>,
+    // Account cash left after the last funding cycle's orders filled.
+    pub expected_cash: Option<f64>,
+    // Running average fill price of every buy, and the shares they bought.
+    pub average_purchase_price: HashMap<String, f64>,
+    pub total_shares_purchased: HashMap<String, f64>,
+    // Close of the trading day the last funding cycle traded on.
+    pub last_market_close: Option<DateTime<Utc>>,
+    pub requeued_orders: Vec<RequeuedOrder>,
+    // Client order ids of the last funding cycle's orders, saved before they
+    // are submitted.
+    pub client_order_ids: Vec<String>,
+    // The last funding cycle's orders while `notify_on_all_fills` is set.
+    pub fill_session: Option<fill_session::FillSession>,
+    // When the drift last set off a funding cycle. Cleared by the next
+    // scheduled cycle, and until then the drift can't set off another.
+    pub last_drift_trigger: Option<DateTime<Utc>>,
+}
+
+fn default_limit_price_factor() -> f64 {
+    pricing::DEFAULT_LIMIT_PRICE_FACTOR
+}
+
+fn default_benchmark_symbol() -> String {
+    "SPY".to_string()
+}
+
+fn default_reconciliation_threshold() -> f64 {
+    0.05
+}
+
+fn default_state_backups() -> usize {
+    5
+}
+
+fn default_pending_order_ttl_hours() -> u64 {
+    72
+}
+
+fn default_fill_poll_interval_secs() -> u64 {
+    30
+}
+
+fn default_fill_timeout_minutes() -> u64 {
+    10
+}
+
+fn default_reprice_step() -> f64 {
+    0.002
+}
+
+impl State {
+    // A state with the generated defaults.
+    fn new(reference_equities: HashMap<String, f64>, ideal_allocations: HashMap<String, f64>) -> Self {
+        State {
+            version: STATE_VERSION,
+            fund_accum: 0.0,
+            last_funding_date: None,
+            reference_equities,
+            ideal_allocations,
+            target_investment_equity_ratio: 1.0,
+            finish_date: Utc::now() + Duration::days(365),
+            limit_price_strategy: pricing::LimitPriceStrategy::default(),
+            limit_price_factor: default_limit_price_factor(),
+            rounding_strategy: rounding::RoundingStrategy::default(),
+            sell_enabled: false,
+            fractional_shares: false,
+            funding_frequency: schedule::FundingFrequency::default(),
+            min_rebalance_drift: 0.0,
+            min_allocations: HashMap::new(),
+            max_allocations: HashMap::new(),
+            universe: None,
+            equity_history: Vec::new(),
+            api_latency_avg_ms: HashMap::new(),
+            api_latency_p99_ms: HashMap::new(),
+            pending_orders: Vec::new(),
+            pending_order_ttl_hours: default_pending_order_ttl_hours(),
+            fill_poll_interval_secs: default_fill_poll_interval_secs(),
+            fill_timeout_minutes: default_fill_timeout_minutes(),
+            reprice_attempts: 0,
+            reprice_step: default_reprice_step(),
+            reconciliation_threshold: default_reconciliation_threshold(),
+            reconcile_reference_equities: false,
+            journal_path: None,
+            state_backups: default_state_backups(),
+            equity_high_watermark: 0.0,
+            max_drawdown: 0.0,
+            watchlist: HashMap::new(),
+            benchmark_symbol: default_benchmark_symbol(),
+            benchmark_reference_price: None,
+            initial_equity: None,
+            cost_basis: HashMap::new(),
+            shares_held: HashMap::new(),
+            harvest_cooldowns: HashMap::new(),
+            expected_cash: None,
+            average_purchase_price: HashMap::new(),
+            total_shares_purchased: HashMap::new(),
+            last_market_close: None,
+            requeued_orders: Vec::new(),
+            client_order_ids: Vec::new(),
+            fill_session: None,
+            last_drift_trigger: None,
+        }
+    }
+
+    // Submits an order unless a matching one is already open, journaling it
+    // and tracking it until it fills.
+    #[allow(clippy::too_many_arguments)]
+    async fn place_order(
+        &mut self,
+        client: &TimedClient,
+        shutdown: &Shutdown,
+        sym: &str,
+        side: order::Side,
+        limit_price: f64,
+        qty: f64,
+        settings: OrderSettings,
+    ) -> Result<Option<order::Order>> {
+        let request = order_request(sym, side, limit_price, qty, self.fractional_shares, settings)?;
+        let Some(order) = submit_order_idempotent(client, &request, shutdown).await? else {
+            return Ok(None);
+        };
+        info!(
+            symbol = %sym, side = ?side, qty, limit_price, order_id = %order.id.as_hyphenated(),
+            "Submitted order"
+        );
+        journal::record(
+            self.journal_path.as_deref(),
+            journal::JournalEvent::Submitted,
+            &order,
+            qty,
+            limit_price,
+            None,
+        );
+        self.pending_orders.push(PendingOrder::new(&order, qty));
+        Ok(Some(order))
+    }
+
+    // The portfolio and benchmark returns since the first funding cycle, which
+    // records the reference equity and price.
+    fn benchmark_returns(&mut self, equity: f64, benchmark_price: f64) -> (f64, f64) {
+        let initial_equity = *self.initial_equity.get_or_insert(equity);
+        let reference_price = *self.benchmark_reference_price.get_or_insert(benchmark_price);
+        (equity / initial_equity - 1.0, benchmark_price / reference_price - 1.0)
+    }
+
+    // Returns how far `equity` is below the highest equity seen.
+    fn record_drawdown(&mut self, equity: f64) -> f64 {
+        self.equity_high_watermark = self.equity_high_watermark.max(equity);
+        let drawdown = if self.equity_high_watermark > 0.0 {
+            (self.equity_high_watermark - equity) / self.equity_high_watermark
+        } else {
+            0.0
+        };
+
+        if drawdown > self.max_drawdown {
+            warn!(
+                "New maximum drawdown {:.2}% below the high watermark {:.2}",
+                drawdown * 100.0,
+                self.equity_high_watermark
+            );
+            self.max_drawdown = drawdown;
+        }
+        drawdown
+    }
+
+    // Posts to `slack` once the last funding cycle's orders have all finished
+    // filling, if `notify_on_all_fills` started a fill session for them.
+    async fn monitor_pending_orders(
+        &mut self,
+        client: &TimedClient,
+        shutdown: &Shutdown,
+        slack: Option<&slack::SlackWebhookConfig>,
+    ) -> Result<()> {
+        if self.pending_orders.is_empty() {
+            return Ok(());
+        }
+        // fills are still found by polling when the stream can't be opened
+        let mut updates = match TradeUpdates::connect(client).await {
+            Ok(updates) => Some(updates),
+            Err(e) => {
+                warn!("Couldn't subscribe to trade updates, polling orders instead: {}", e);
+                None
+            }
+        };
+        let requeued = monitor_and_fill(
+            client,
+            &mut self.pending_orders,
+            FillSettings {
+                poll_interval: time::Duration::from_secs(self.fill_poll_interval_secs),
+                timeout: time::Duration::from_secs(self.fill_timeout_minutes * 60),
+                reprice_attempts: self.reprice_attempts,
+                reprice_step: self.reprice_step,
+            },
+            updates.as_mut(),
+            self.journal_path.as_deref(),
+            shutdown,
+            |sym, side, qty, price| {
+                cost_basis::record_fill(&mut self.cost_basis, &mut self.shares_held, sym, side, qty, price);
+                if let Some(session) = &mut self.fill_session {
+                    session.record_fill(sym, side, qty, price);
+                }
+                if side == order::Side::Buy {
+                    cost_basis::record_purchase(
+                        &mut self.average_purchase_price,
+                        &mut self.total_shares_purchased,
+                        sym,
+                        qty,
+                        price,
+                    );
+                }
+            },
+        )
+        .await?;
+        self.requeued_orders.extend(requeued);
+
+        if let (Some(session), Some(slack_config)) = (self.completed_fill_session(), slack) {
+            if let Err(e) = slack::send_fills_notification(slack_config, &session).await {
+                error!("Failed to send the fill notification: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    // The fill session once none of its orders is pending any more, the first
+    // time only.
+    fn completed_fill_session(&mut self) -> Option<fill_session::FillSession> {
+        if !self.pending_orders.is_empty() {
+            return None;
+        }
+        let session = self.fill_session.as_mut().filter(|s| !s.notified && !s.orders.is_empty())?;
+        session.notified = true;
+        Some(session.clone())
+    }
+
+    // Submits the unfilled parts of partially filled orders at market. Ones
+    // too small to submit are dropped.
+    async fn submit_requeued_orders(&mut self, client: &TimedClient, shutdown: &Shutdown) -> Result<()> {
+        let settings = OrderSettings {
+            order_type: pricing::OrderType::Market,
+            ..Default::default()
+        };
+        let mut requeued_orders = std::mem::take(&mut self.requeued_orders).into_iter();
+        while let Some(requeued) = requeued_orders.next() {
+            // the rest are kept for the next cycle
+            if shutdown.trading_stopped().await {
+                self.requeued_orders.push(requeued);
+                self.requeued_orders.extend(requeued_orders);
+                break;
+            }
+            info!("Resubmitting the unfilled {} {}", requeued.quantity, requeued.symbol);
+            // only journaled, the market order has no limit
+            let price = pricing::mid_price(client, &requeued.symbol).await?.unwrap_or(0.0);
+            let placed = self
+                .place_order(client, shutdown, &requeued.symbol, requeued.side, price, requeued.quantity, settings)
+                .await;
+            if let Err(e @ Error::OrderRejected { .. }) = placed {
+                warn!("{}", e);
+            } else {
+                placed?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// A finish date this close would squeeze the remaining funding into a few
+// huge orders, so with `auto_extend_days` it's pushed back a year at a time
+// while fewer days remain. Without it a passed finish date is an error.
+fn extend_finish_date(state: &mut State, now: DateTime<Utc>, auto_extend_days: Option<u32>) -> Result<()> {
+    if let Some(extend_days) = auto_extend_days {
+        while schedule::days_between(now, state.finish_date) < extend_days as f64 {
+            let extended = state.finish_date.checked_add_months(Months::new(12)).unwrap();
+            info!("Extending the finish date from {} to {}", state.finish_date, extended);
+            state.finish_date = extended;
+        }
+    }
+    if schedule::days_between(now, state.finish_date) <= 0.0 {
+        return Err(Error::InvalidConfig(format!(
+            "finish_date {} has passed, set a later one or auto_extend_days",
+            state.finish_date
+        )));
+    }
+    Ok(())
+}
+
+async fn wait_until_datetime(dt: DateTime<Utc>, granularity: Duration) {
+    debug!("Waiting until {} in steps of {}s", dt, granularity.num_seconds());
+    while Utc::now() < dt {
+        tokio::time::sleep(granularity.to_std().unwrap()).await;
+    }
+}
+
+// How often `drift_trigger_threshold` has the positions checked while waiting for the funding time.
+const DRIFT_CHECK_INTERVAL_MINUTES: i64 = 15;
+
+// The held positions' largest drift, or `None` outside the regular session,
+// when prices don't move enough to be worth an early funding cycle.
+async fn session_drift(
+    broker: &impl Broker,
+    state: &State,
+    config: Option<&config::Config>,
+    now: DateTime<Utc>,
+) -> Result<Option<f64>> {
+    let today = now.with_timezone(&Eastern).date_naive();
+    let calendar_req = calendar::CalendarReq {
+        start: today,
+        end: today + Duration::days(1),
+    };
+    let eastern = |time| Eastern.from_local_datetime(&today.and_time(time)).unwrap().with_timezone(&Utc);
+    let in_session = broker
+        .get_calendar(&calendar_req)
+        .await?
+        .iter()
+        .any(|oc| oc.date == today && eastern(oc.open) <= now && now < eastern(oc.close));
+    if !in_session {
+        return Ok(None);
+    }
+
+    let mut pos = broker.get_positions().await?;
+    retain_portfolio_positions(&mut pos, state, config);
+    if let Some(sym) = config.and_then(|c| c.idle_cash_symbol.as_deref()) {
+        pos.retain(|pos| pos.symbol != sym);
+    }
+    Ok(Some(max_drift(&pos, state)))
+}
+
+// Waits until the funding time `dt`, or with `drift_trigger_threshold` set
+// until the drift is beyond it, which may already be the case when the wait
+// starts. After a drift-triggered cycle only the scheduled one follows, so
+// drift that cycle couldn't buy away doesn't set off a cycle every check.
+async fn wait_for_funding_time(
+    broker: &impl Broker,
+    state: &State,
+    config: Option<&config::Config>,
+    dt: DateTime<Utc>,
+    check_interval: Duration,
+) -> TriggerType {
+    let threshold = config.and_then(|c| c.drift_trigger_threshold);
+    let threshold = match (threshold, state.last_drift_trigger) {
+        (Some(_), Some(triggered)) => {
+            info!("The drift set off a funding cycle at {}, waiting for the scheduled one", triggered);
+            None
+        }
+        (threshold, _) => threshold,
+    };
+    let Some(threshold) = threshold else {
+        wait_until_datetime(dt, check_interval.min(Duration::seconds(10))).await;
+        return TriggerType::Scheduled;
+    };
+
+    loop {
+        let now = Utc::now();
+        if now >= dt {
+            return TriggerType::Scheduled;
+        }
+        match session_drift(broker, state, config, now).await {
+            Ok(Some(drift)) if drift > threshold => {
+                info!("Drift {:.4} is beyond drift_trigger_threshold {:.4}, funding early", drift, threshold);
+                return TriggerType::DriftTriggered { max_drift: drift };
+            }
+            Ok(_) => {}
+            // the funding time still comes, so a failed check only costs an early start
+            Err(e) => warn!("Failed to check the drift: {}", e),
+        }
+        tokio::time::sleep(check_interval.min(dt - now).to_std().unwrap()).await;
+    }
+}
+
+use std::fs;
+use tokio::io::AsyncWriteExt;
+
+// Bumped whenever a field is added to `State`, with a matching step in `migrate_state`.
+const STATE_VERSION: u32 = 25;
+const DEFAULT_STATE_FILE: &str = "state.json";
+
+// Upgrades a state file written by an older version one version at a time.
+// Files without a version predate versioning and count as version 0.
+fn migrate_state(mut value: serde_json::Value) -> Result<State> {
+    let obj = value
+        .as_object_mut()
+        .ok_or_else(|| Error::InvalidState("state file is not a JSON object".to_string()))?;
+    let version = obj.get("version").and_then(|v| v.as_u64()).unwrap_or(0) as u32;
+
+    if version > STATE_VERSION {
+        return Err(Error::InvalidState(format!(
+            "state file version {} is newer than the supported version {}",
+            version, STATE_VERSION
+        )));
+    }
+
+    if version < STATE_VERSION {
+        info!("Upgrading the state file from version {} to {}", version, STATE_VERSION);
+    }
+
+    if version < 1 {
+        let defaults = [
+            ("limit_price_strategy", serde_json::to_value(pricing::LimitPriceStrategy::default())?),
+            ("limit_price_factor", default_limit_price_factor().into()),
+            ("rounding_strategy", serde_json::to_value(rounding::RoundingStrategy::default())?),
+            ("sell_enabled", false.into()),
+            ("fractional_shares", false.into()),
+            ("min_rebalance_drift", 0.0.into()),
+            ("universe", serde_json::Value::Null),
+            ("equity_history", serde_json::json!([])),
+            ("api_latency_avg_ms", serde_json::json!({})),
+            ("api_latency_p99_ms", serde_json::json!({})),
+            ("pending_order_ids", serde_json::json!([])),
+            ("fill_poll_interval_secs", default_fill_poll_interval_secs().into()),
+            ("fill_timeout_minutes", default_fill_timeout_minutes().into()),
+        ];
+        for (field, default) in defaults {
+            obj.entry(field).or_insert(default);
+        }
+    }
+
+    if version < 2 {
+        obj.entry("reconciliation_threshold").or_insert(default_reconciliation_threshold().into());
+        obj.entry("reconcile_reference_equities").or_insert(false.into());
+    }
+
+    if version < 3 {
+        obj.entry("state_backups").or_insert(default_state_backups().into());
+    }
+
+    // pending order ids gained the symbol, quantity and submission time, which
+    // older files don't record
+    if version < 4 {
+        let ids = obj.remove("pending_order_ids").unwrap_or_else(|| serde_json::json!([]));
+        let pending_orders: Vec<_> = ids
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|id| id.as_str())
+            .map(|id| PendingOrder {
+                id: id.to_string(),
+                symbol: String::new(),
+                quantity: 0.0,
+                submitted_at: Utc::now(),
+                reprices: 0,
+            })
+            .collect();
+        obj.insert("pending_orders".to_string(), serde_json::to_value(pending_orders)?);
+        obj.entry("pending_order_ttl_hours").or_insert(default_pending_order_ttl_hours().into());
+    }
+
+    if version < 5 {
+        obj.entry("min_allocations").or_insert(serde_json::json!({}));
+        obj.entry("max_allocations").or_insert(serde_json::json!({}));
+    }
+
+    if version < 6 {
+        obj.entry("funding_frequency")
+            .or_insert(serde_json::to_value(schedule::FundingFrequency::default())?);
+    }
+
+    if version < 7 {
+        obj.entry("journal_path").or_insert(serde_json::Value::Null);
+    }
+
+    // the watermark starts from the recorded history, the drawdown from now on
+    if version < 8 {
+        let watermark = obj
+            .get("equity_history")
+            .and_then(|h| h.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|sample| sample.get(1)?.as_f64())
+            .fold(0.0, f64::max);
+        obj.entry("equity_high_watermark").or_insert(watermark.into());
+        obj.entry("max_drawdown").or_insert(0.0.into());
+    }
+
+    if version < 9 {
+        obj.entry("watchlist").or_insert(serde_json::json!({}));
+    }
+
+    if version < 10 {
+        obj.entry("benchmark_symbol").or_insert(default_benchmark_symbol().into());
+        obj.entry("benchmark_reference_price").or_insert(serde_json::Value::Null);
+        obj.entry("initial_equity").or_insert(serde_json::Value::Null);
+    }
+
+    // earlier fills are only known from the journal
+    if version < 11 && !obj.contains_key("cost_basis") {
+        let journal_path = obj.get("journal_path").and_then(|p| p.as_str());
+        let (cost_basis, shares_held) = match journal_path.map(cost_basis::from_journal) {
+            Some(Ok(basis)) => basis,
+            Some(Err(e)) => {
+                warn!("Could not read the cost basis from the journal: {}", e);
+                Default::default()
+            }
+            None => Default::default(),
+        };
+        obj.insert("cost_basis".to_string(), serde_json::to_value(cost_basis)?);
+        obj.insert("shares_held".to_string(), serde_json::to_value(shares_held)?);
+    }
+
+    if version < 12 {
+        obj.entry("harvest_cooldowns").or_insert(serde_json::json!({}));
+    }
+
+    if version < 13 {
+        obj.entry("expected_cash").or_insert(serde_json::Value::Null);
+    }
+
+    if version < 14 && !obj.contains_key("average_purchase_price") {
+        let journal_path = obj.get("journal_path").and_then(|p| p.as_str());
+        let (average_purchase_price, total_shares_purchased) =
+            match journal_path.map(cost_basis::purchases_from_journal) {
+                Some(Ok(purchases)) => purchases,
+                Some(Err(e)) => {
+                    warn!("Could not read the purchases from the journal: {}", e);
+                    Default::default()
+                }
+                None => Default::default(),
+            };
+        obj.insert("average_purchase_price".to_string(), serde_json::to_value(average_purchase_price)?);
+        obj.insert("total_shares_purchased".to_string(), serde_json::to_value(total_shares_purchased)?);
+    }
+
+    if version < 15 {
+        obj.entry("last_market_close").or_insert(serde_json::Value::Null);
+    }
+
+    if version < 16 {
+        obj.entry("requeued_orders").or_insert(serde_json::json!([]));
+    }
+
+    // pending orders count the times their limit price was moved
+    if version < 17 {
+        obj.entry("reprice_attempts").or_insert(0.into());
+        obj.entry("reprice_step").or_insert(default_reprice_step().into());
+        if let Some(pending_orders) = obj.get_mut("pending_orders").and_then(|p| p.as_array_mut()) {
+            for pending in pending_orders.iter_mut().filter_map(|p| p.as_object_mut()) {
+                pending.entry("reprices").or_insert(0.into());
+            }
+        }
+    }
+
+    if version < 18 {
+        obj.entry("client_order_ids").or_insert(serde_json::json!([]));
+    }
+
+    // target_fill_probability priced buys within the spread like NarrowSpread
+    // does before limit_price_strategy replaced it, and the earlier steps fill
+    // in FixedDiscount for it
+    if version < 19 {
+        if let Some(probability) = obj.remove("target_fill_probability").and_then(|p| p.as_f64()) {
+            let strategy = pricing::LimitPriceStrategy::NarrowSpread {
+                max_pct_from_bid: probability,
+            };
+            obj.insert("limit_price_strategy".to_string(), serde_json::to_value(strategy)?);
+        }
+    }
+
+    if version < 20 {
+        obj.entry("fill_session").or_insert(serde_json::Value::Null);
+    }
+
+    // the holiday eves are computed for the year being looked up, so only the
+    // dates added by hand are kept
+    if version < 21 {
+        let dates = obj
+            .get_mut("thin_liquidity")
+            .and_then(|t| t.get_mut("dates"))
+            .and_then(|d| d.as_array_mut());
+        if let Some(dates) = dates {
+            dates.retain(|date| {
+                let date = date.as_str().and_then(|d| d.parse::<NaiveDate>().ok());
+                !date.is_some_and(schedule::is_holiday_eve)
+            });
+        }
+    }
+
+    if version < 22 {
+        obj.entry("last_drift_trigger").or_insert(serde_json::Value::Null);
+    }
+
+    // the slack webhook is set in the config
+    if version < 23 && obj.remove("slack").is_some_and(|slack| !slack.is_null()) {
+        warn!("The state's slack webhook is no longer used, move it to the slack table of config.toml");
+    }
+
+    // so are the urgency score's weights
+    if version < 24 && obj.remove("rebalance_weights").is_some_and(|weights| !weights.is_null()) {
+        warn!("The state's rebalance_weights are no longer used, move them to config.toml");
+    }
+
+    // and the thin-liquidity dates, which new state files all had with the defaults
+    if version < 25 {
+        let customized = obj.remove("thin_liquidity").is_some_and(|thin| {
+            thin.get("dates").and_then(|d| d.as_array()).is_some_and(|d| !d.is_empty())
+                || thin.get("skip_thin_liquidity_days").and_then(|s| s.as_bool()) == Some(true)
+                || thin.get("extra_wait_hours").and_then(|h| h.as_u64()).is_some_and(|h| h != 2)
+        });
+        if customized {
+            warn!("The state's thin_liquidity settings are no longer used, move them to config.toml");
+        }
+    }
+
+    obj.insert("version".to_string(), STATE_VERSION.into());
+    Ok(serde_json::from_value(value)?)
+}
+
+async fn load_state(filename: &str) -> Result<State> {
+    let data = match StateBackend::of_path(filename) {
+        StateBackend::Json => tokio::fs::read(filename).await?,
+        StateBackend::Sqlite => SqliteStateStore::new(filename).read()?,
+    };
+    let data = encryption::decrypt(data, filename)?;
+    let state = migrate_state(serde_json::from_slice(&data)?)?;
+    validate_state(&state)?;
+    validate_limit_price_factor(state.limit_price_factor)?;
+    validate_allocation_bounds(&state.min_allocations, &state.max_allocations)?;
+    Ok(state)
+}
+
+// Catches hand edits the balancer would otherwise silently work around.
+fn validate_state(state: &State) -> Result<()> {
+    let total: f64 = state.ideal_allocations.values().sum();
+    if (total - 1.0).abs() > 0.001 {
+        return Err(Error::InvalidConfig(format!(
+            "ideal_allocations must sum to 1, got {}",
+            total
+        )));
+    }
+
+    for (sym, &allocation) in &state.ideal_allocations {
+        if allocation < 0.0 {
+            return Err(Error::InvalidConfig(format!(
+                "ideal allocation for {} must not be negative, got {}",
+                sym, allocation
+            )));
+        }
+        if !state.reference_equities.contains_key(sym) {
+            return Err(Error::InvalidConfig(format!(
+                "{} is in ideal_allocations but missing from reference_equities",
+                sym
+            )));
+        }
+    }
+
+    for (sym, &allocation) in &state.watchlist {
+        if !(allocation > 0.0 && allocation < 1.0) {
+            return Err(Error::InvalidConfig(format!(
+                "watchlist allocation for {} must be in (0, 1), got {}",
+                sym, allocation
+            )));
+        }
+    }
+
+    // a passed finish_date is only an error once auto_extend_days can't move
+    // it, so it doesn't keep the state from loading to be fixed
+    validate_target_investment_equity_ratio(state.target_investment_equity_ratio)?;
+
+    Ok(())
+}
+
+fn validate_buying_power_buffer_fraction(fraction: f64) -> Result<()> {
+    if (0.0..1.0).contains(&fraction) {
+        Ok(())
+    } else {
+        Err(Error::InvalidConfig(format!(
+            "buying_power_buffer_fraction must be in [0, 1), got {}",
+            fraction
+        )))
+    }
+}
+
+fn validate_reinvestment_rate(rate: f64) -> Result<()> {
+    if rate >= 0.0 {
+        Ok(())
+    } else {
+        Err(Error::InvalidConfig(format!(
+            "reinvestment_rate must not be negative, got {}",
+            rate
+        )))
+    }
+}
+
+fn validate_target_investment_equity_ratio(ratio: f64) -> Result<()> {
+    if ratio > 0.0 && ratio <= 1.0 {
+        Ok(())
+    } else {
+        Err(Error::InvalidConfig(format!(
+            "target_investment_equity_ratio must be in (0, 1], got {}",
+            ratio
+        )))
+    }
+}
+
+fn validate_limit_price_factor(factor: f64) -> Result<()> {
+    if factor > 0.0 && factor <= 1.0 {
+        Ok(())
+    } else {
+        Err(Error::InvalidConfig(format!(
+            "limit_price_factor must be in (0, 1], got {}; buy limits are placed at the last price times this factor",
+            factor
+        )))
+    }
+}
+
+fn validate_allocation_bounds(
+    min_allocations: &HashMap<String, f64>,
+    max_allocations: &HashMap<String, f64>,
+) -> Result<()> {
+    for (sym, &bound) in min_allocations.iter().chain(max_allocations) {
+        if !(0.0..=1.0).contains(&bound) {
+            return Err(Error::InvalidConfig(format!(
+                "allocation bound for {} must be in [0, 1], got {}",
+                sym, bound
+            )));
+        }
+    }
+
+    for (sym, &min) in min_allocations {
+        if let Some(&max) = max_allocations.get(sym) {
+            if min > max {
+                return Err(Error::InvalidConfig(format!(
+                    "minimum allocation {} for {} is above its maximum {}",
+                    min, sym, max
+                )));
+            }
+        }
+    }
+
+    let total_min: f64 = min_allocations.values().sum();
+    if total_min > 1.0 + 1e-9 {
+        return Err(Error::InvalidConfig(format!(
+            "minimum allocations sum to {}, more than the whole portfolio",
+            total_min
+        )));
+    }
+
+    Ok(())
+}
+
+// Backups sort by name in the order they were saved.
+const BACKUP_TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%S%.6fZ";
+
+fn backup_filename(filename: &str, saved_at: DateTime<Utc>) -> String {
+    format!("{}.{}", filename, saved_at.format(BACKUP_TIMESTAMP_FORMAT))
+}
+
+// The backups of `filename` from newest to oldest. The numbered backups older
+// versions rotated through `<filename>.1` come after the timestamped ones.
+async fn list_backups(filename: &str) -> Result<Vec<String>> {
+    let path = std::path::Path::new(filename);
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(std::path::Path::new("."));
+    let prefix = format!("{}.", path.file_name().unwrap_or_default().to_string_lossy());
+
+    let (mut timestamped, mut numbered) = (Vec::new(), Vec::new());
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some(suffix) = name.strip_prefix(&prefix) else {
+            continue;
+        };
+        let backup = path.with_file_name(&name).to_string_lossy().into_owned();
+        if let Ok(n) = suffix.parse::<usize>() {
+            numbered.push((n, backup));
+        } else if chrono::NaiveDateTime::parse_from_str(suffix, BACKUP_TIMESTAMP_FORMAT).is_ok() {
+            timestamped.push(backup);
+        }
+    }
+    timestamped.sort_by(|a, b| b.cmp(a));
+    numbered.sort();
+    Ok(timestamped.into_iter().chain(numbered.into_iter().map(|(_, backup)| backup)).collect())
+}
+
+// Tries the state file, then each backup from newest to oldest. Only a file
+// that is missing or can't be read or parsed falls back; a state that fails
+// validation is returned for the user to fix, since an older backup would fund
+// or submit orders a second time.
+async fn load_state_with_fallback(filename: &str) -> Result<State> {
+    let error = match load_state(filename).await {
+        Ok(state) => return Ok(state),
+        Err(e @ (Error::StateIo(_) | Error::StateParse(_))) => e,
+        Err(e) => return Err(e),
+    };
+
+    for backup in list_backups(filename).await.unwrap_or_default() {
+        match load_state(&backup).await {
+            Ok(state) => {
+                warn!("Could not load {} ({}), using the backup {}", filename, error, backup);
+                return Ok(state);
+            }
+            Err(e) => warn!("Could not load the backup {}: {}", backup, e),
+        }
+    }
+
+    Err(error)
+}
+
+// The new state is written to a temporary file and renamed over the old one so
+// a crash never leaves a partially written state file. The previous file is
+// first copied to `<filename>.<time saved>`, and only the newest
+// `state_backups` of those are kept. SQLite stores are written in a
+// transaction instead and keep no backups.
+async fn save_state(filename: &str, state: &State) -> Result<()> {
+    if StateBackend::of_path(filename) == StateBackend::Sqlite {
+        SqliteStateStore::new(filename).write(state, &encryption::encrypt(serde_json::to_vec(state)?))?;
+        debug!("Saved state to {}", filename);
+        return Ok(());
+    }
+
+    let tmp_filename = format!("{}.tmp", filename);
+    let mut file = tokio::fs::File::create(&tmp_filename).await?;
+    file.write_all(&encryption::encrypt(serde_json::to_vec(state)?)).await?;
+    file.sync_all().await?;
+
+    if state.state_backups > 0 && tokio::fs::metadata(filename).await.is_ok() {
+        tokio::fs::copy(filename, backup_filename(filename, Utc::now())).await?;
+        for old in list_backups(filename).await?.into_iter().skip(state.state_backups) {
+            tokio::fs::remove_file(&old).await?;
+        }
+    }
+
+    tokio::fs::rename(&tmp_filename, filename).await?;
+    debug!("Saved state to {}", filename);
+    Ok(())
+}
+
+// Replaces the state file with `backup`, or the newest backup that loads. The
+// replaced file is kept as a backup itself, so a restore can be undone.
+async fn restore_state(state_filename: &str, backup: Option<&str>) -> Result<()> {
+    let (backup, state) = match backup {
+        Some(backup) => (backup.to_string(), load_state(backup).await?),
+        None => {
+            let mut restored = None;
+            for backup in list_backups(state_filename).await? {
+                match load_state(&backup).await {
+                    Ok(state) => {
+                        restored = Some((backup, state));
+                        break;
+                    }
+                    Err(e) => warn!("Could not load the backup {}: {}", backup, e),
+                }
+            }
+            restored.ok_or_else(|| Error::InvalidState(format!("no backup of {} could be loaded", state_filename)))?
+        }
+    };
+
+    save_state(state_filename, &state).await?;
+    info!("Restored {} from {}", state_filename, backup);
+    Ok(())
+}
+
+enum StateSource {
+    Generated, 
+    FromFile, 
+}
+
+async fn get_state(
+    client: &TimedClient,
+    state_filename: &str,
+    config: Option<&config::Config>,
+) -> Result<(State, StateSource)> {
+    match load_state_with_fallback(state_filename).await {
+        Ok(state) => Ok( (state, StateSource::FromFile) ), 
+        // don't overwrite a state file that exists but is invalid
+        Err(e) if tokio::fs::metadata(state_filename).await.is_ok() => Err(e),
+        _ => {
+            let state = generate_default_state(client, config).await?;
+            save_state(state_filename, &state).await?;
+            Ok( (state, StateSource::Generated) )
+        }, 
+    }
+}
+
+// Allocations follow the current positions unless the config declares them.
+async fn generate_default_state(client: &TimedClient, config: Option<&config::Config>) -> Result<State> {
+    let pos: Vec<_> = client.get_positions().await?;
+    let stock_equities: Vec<_> = pos
+        .iter()
+        .map(|pos| pos.market_value.as_ref().unwrap().to_f64().unwrap())
+        .collect();
+
+    let syms: Vec<_> = pos.iter().map(|pos| pos.symbol.clone()).collect();
+    // declared allocations are used as is, so a portfolio of only cash can be started
+    let ideal_allocations = match config.and_then(|c| c.allocations()) {
+        Some(allocations) => allocations,
+        None => {
+            config
+                .and_then(|c| c.allocation_strategy.as_ref())
+                .cloned()
+                .unwrap_or_default()
+                .allocations(client, &syms, &pos)
+                .await?
+        }
+    };
+    if ideal_allocations.is_empty() {
+        warn!("No positions are held, so set symbols or ideal_allocations in the config to choose what to buy");
+    }
+
+    let reference_equities = HashMap::from_iter(syms.into_iter().zip(stock_equities));
+    let mut state = State::new(reference_equities, ideal_allocations);
+
+    if let Some(config) = config {
+        config.apply(&mut state);
+    }
+
+    Ok(state)
+}
+
+// Reweights the symbols already in the state's ideal allocations.
+async fn recalculate_allocations(
+    client: &TimedClient,
+    state_filename: &str,
+    config: Option<&config::Config>,
+) -> Result<()> {
+    let strategy = config.and_then(|c| c.allocation_strategy.as_ref()).ok_or_else(|| {
+        Error::InvalidConfig("--recalculate-allocations needs an allocation_strategy in the config".to_string())
+    })?;
+
+    let mut state = load_state(state_filename).await?;
+    let pos: Vec<_> = client.get_positions().await?;
+    let mut syms: Vec<_> = state.ideal_allocations.keys().cloned().collect();
+    syms.sort();
+
+    state.ideal_allocations = strategy.allocations(client, &syms, &pos).await?;
+    for sym in &syms {
+        info!("{} ideal allocation set to {:.4}", sym, state.ideal_allocations[sym]);
+    }
+    save_state(state_filename, &state).await
+}
+
+#[derive(Parser)]
+#[command(
+    about = "Dollar cost averages an Alpaca account towards a target allocation",
+    long_about = "Dollar cost averages an Alpaca account towards a target allocation.
+
+Once per trading day the balancer works out how much to invest so the account \
+reaches its target equity by the finish date, then buys whichever symbols bring \
+its holdings closest to the ideal allocations. Positions held before the first \
+run are left alone. All settings and history live in the state file, which is \
+generated from the current positions or from a config file on the first run.
+
+Run without a subcommand, or with `run`, to start the daily loop."
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Path to the state file
+    #[arg(long, global = true, visible_alias = "state-file", default_value = DEFAULT_STATE_FILE)]
+    state: String,
+    /// TOML file seeding a newly generated state, config.toml by default if it exists
+    #[arg(long, global = true)]
+    config: Option<String>,
+    /// Trade on the paper trading API, whatever APCA_API_BASE_URL or the config says
+    #[arg(long, global = true, conflicts_with = "live")]
+    paper: bool,
+    /// Trade on the live API, whatever APCA_API_BASE_URL or the config says
+    #[arg(long, global = true)]
+    live: bool,
+    /// Order submission halts while this file exists
+    #[arg(long, global = true, default_value = "STOP_TRADING")]
+    stop_file: String,
+    /// Print the orders that would be placed today without placing them or saving the state
+    #[arg(long, global = true)]
+    dry_run: bool,
+    /// Run the daily loop but log orders instead of submitting them, paying for them from a virtual cash balance
+    #[arg(long, global = true, conflicts_with = "dry_run")]
+    simulate: bool,
+    /// Log output format
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Pretty)]
+    log_format: LogFormat,
+    /// Log filter such as info or debug, overriding the RUST_LOG environment variable
+    #[arg(long, global = true)]
+    log_level: Option<String>,
+    /// Overrides the state's limit_price_factor, the fraction of the last price buy limits are placed at
+    #[arg(long, global = true)]
+    slippage: Option<f64>,
+    /// Recompute the ideal allocations with the config's allocation_strategy and save them without placing orders
+    #[arg(long, global = true)]
+    recalculate_allocations: bool,
+    /// Encrypt the state file with this passphrase, read from APCA_BALANCER_PASSPHRASE when not given
+    #[arg(long, global = true)]
+    passphrase: Option<String>,
+}
+
+impl Cli {
+    fn base_url(&self) -> Option<&'static str> {
+        if self.paper {
+            Some(config::PAPER_API_BASE_URL)
+        } else if self.live {
+            Some(config::LIVE_API_BASE_URL)
+        } else {
+            None
+        }
+    }
+
+    // The environment's credentials, at the base URL `--paper` or `--live` picks.
+    fn api_info(&self) -> Result<ApiInfo> {
+        let Some(base_url) = self.base_url() else {
+            return ApiInfo::from_env().map_err(|e| Error::InvalidConfig(e.to_string()));
+        };
+        let var = |name: &str| std::env::var(name).map_err(|_| Error::InvalidConfig(format!("{} is not set", name)));
+        ApiInfo::from_parts(base_url, var("APCA_API_KEY_ID")?, var("APCA_API_SECRET_KEY")?)
+            .map_err(|e| Error::InvalidConfig(e.to_string()))
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Start the daily funding loop, the same as running without a subcommand
+    Run,
+    /// Print the orders the next funding cycle would place without placing them, like --dry-run
+    Plan,
+    /// Generate the state file from the config or the current positions and exit
+    Init,
+    /// Print the funding progress and outstanding orders recorded in the state file
+    Status,
+    /// Print the expected dividend income of current positions over the next 12 months
+    IncomeCalendar,
+    /// Show current versus ideal allocations and how urgently the portfolio needs attention
+    Show,
+    /// Report statistics recorded by previous funding cycles
+    Report,
+    /// Estimate what NarrowSpread limits would have saved on recent buys
+    SimulateLimitSavings {
+        /// Number of days of fills to replay
+        #[arg(long, default_value_t = 30)]
+        days: i64,
+        #[arg(long, default_value_t = 0.3)]
+        max_pct_from_bid: f64,
+        /// Fixed discount off the last trade the NarrowSpread limits are compared against
+        #[arg(long, default_value_t = pricing::DEFAULT_SAVINGS_BASELINE_FACTOR)]
+        baseline_factor: f64,
+    },
+    /// Refresh the tracked symbols from the configured universe source
+    RefreshUniverse,
+    /// Remove the emergency stop file so trading resumes
+    ClearStop,
+    /// Export data derived from the recorded account history
+    Export {
+        #[arg(long, value_enum)]
+        format: ExportFormat,
+        #[arg(long)]
+        output: String,
+    },
+    /// Replay the funding strategy over historical closes without calling the Alpaca API
+    StressTest {
+        /// CSV of daily closes with date, symbol and close columns
+        #[arg(long)]
+        scenario: String,
+        /// Cash the replay starts with
+        #[arg(long)]
+        initial_equity: f64,
+    },
+    /// Replay the funding strategy over Alpaca's daily bars, filling orders at the open
+    Backtest {
+        /// First day of the replay, such as 2023-01-03
+        #[arg(long)]
+        start: NaiveDate,
+        /// Day the replay stops before
+        #[arg(long)]
+        end: NaiveDate,
+        /// Cash the replay starts with
+        #[arg(long)]
+        initial_equity: f64,
+    },
+    /// Set one symbol's ideal allocation, scaling the others so they still sum to 1
+    SetAllocation {
+        symbol: String,
+        /// Fraction of the portfolio, such as 0.15
+        weight: f64,
+    },
+    /// Print the ideal allocations beside the live ones and their deviation
+    ShowAllocations,
+    /// Replace the state file with a backup, the newest one that loads unless given
+    RestoreState {
+        /// Backup such as state.json.20240304T150000.000000Z
+        #[arg(long)]
+        backup: Option<String>,
+    },
+    /// Find when funding cycles improved the allocation most, from their reports
+    AnalyzeLog {
+        /// Directory the funding cycles wrote their JSON reports to
+        #[arg(long, default_value = rebalancing_report::DEFAULT_REPORTS_DIR)]
+        reports_dir: String,
+    },
+    /// Print the funding cycles recorded in a history database
+    QueryHistory {
+        /// SQLite file written through the history_db config field
+        #[arg(long)]
+        db: String,
+        #[arg(long, value_enum, default_value_t = HistoryFormat::Ascii)]
+        format: HistoryFormat,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    /// Human-readable lines
+    Pretty,
+    /// One JSON object per line
+    Json,
+}
+
+fn init_logging(format: LogFormat, level: Option<&str>) -> Result<()> {
+    let filter = match level {
+        Some(level) => tracing_subscriber::EnvFilter::try_new(level)
+            .map_err(|e| Error::InvalidConfig(format!("invalid --log-level {}: {}", level, e)))?,
+        None => tracing_subscriber::EnvFilter::try_from_default_env()
+            .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
+    };
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+
+    match format {
+        LogFormat::Pretty => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ExportFormat {
+    /// Contribution-adjusted growth index starting at 100
+    NavSeries,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum HistoryFormat {
+    /// Aligned columns
+    Ascii,
+    /// Comma-separated values with a header row
+    Csv,
+}
+
+async fn show(client: &TimedClient, state_filename: &str, weights: Option<RebalanceWeights>) -> Result<()> {
+    let state = load_state(state_filename).await?;
+
+    let account = client.get_account().await?;
+    let equity = account.equity.to_f64().unwrap();
+    let cash = account.cash.to_f64().unwrap();
+    let pos: Vec<_> = client.get_positions().await?;
+
+    let score = portfolio_urgency(client, &state, current_mse(&pos, &state), equity, weights).await?;
+    println!("{}Urgency score = {:.0} / 100", urgency_tag(score), score);
+    println!("Account equity = {}", equity);
+    println!("Account cash = {}", cash);
+
+    print_allocations(&pos, &virtual_equities(&pos, &state), &state, "Actual %");
+
+    Ok(())
+}
+
+// Sets `sym` to `weight` and scales the other allocations proportionally so
+// they fill the rest.
+fn set_allocation_weight(allocations: &mut HashMap<String, f64>, sym: &str, weight: f64) -> Result<()> {
+    if !(0.0..=1.0).contains(&weight) {
+        return Err(Error::InvalidConfig(format!(
+            "allocation weight must be in [0, 1], got {}",
+            weight
+        )));
+    }
+
+    let others: f64 = allocations.iter().filter(|&(s, _)| s != sym).map(|(_, &a)| a).sum();
+    if others <= 0.0 && weight < 1.0 {
+        return Err(Error::InvalidConfig(format!(
+            "no other symbol has an allocation to make up the remaining {}",
+            1.0 - weight
+        )));
+    }
+    for (s, a) in allocations.iter_mut() {
+        if s != sym {
+            *a *= (1.0 - weight) / others;
+        }
+    }
+    allocations.insert(sym.to_string(), weight);
+    Ok(())
+}
+
+async fn set_allocation(state_filename: &str, sym: &str, weight: f64) -> Result<()> {
+    let mut state = load_state(state_filename).await?;
+    set_allocation_weight(&mut state.ideal_allocations, sym, weight)?;
+    // a new symbol isn't held yet
+    state.reference_equities.entry(sym.to_string()).or_insert(0.0);
+    validate_state(&state)?;
+    save_state(state_filename, &state).await?;
+
+    let mut allocations: Vec<_> = state.ideal_allocations.iter().collect();
+    allocations.sort_by(|a, b| a.0.cmp(b.0));
+    for (sym, allocation) in allocations {
+        println!("{:<8}{:>10.2}", sym, allocation * 100.0);
+    }
+    Ok(())
+}
+
+async fn show_allocations(client: &TimedClient, state_filename: &str) -> Result<()> {
+    let state = load_state(state_filename).await?;
+    let pos: Vec<_> = client.get_positions().await?;
+
+    let equities: HashMap<_, _> = pos
+        .iter()
+        .zip(virtual_equities(&pos, &state))
+        .filter(|(pos, _)| state.ideal_allocations.contains_key(&pos.symbol))
+        .map(|(pos, e)| (pos.symbol.as_str(), e))
+        .collect();
+    let total: f64 = equities.values().sum();
+    let mut syms: Vec<_> = state.ideal_allocations.keys().collect();
+    syms.sort();
+
+    println!(
+        "{:<8}{:>10}{:>10}{:>12}{:>12}{:>12}{:>12}",
+        "Symbol", "Ideal %", "Actual %", "Deviation %", "Avg price", "Bought", "Cost basis"
+    );
+    for sym in syms {
+        let ideal = state.ideal_allocations[sym];
+        let e = equities.get(sym.as_str()).cloned().unwrap_or(0.0);
+        let actual = if total > 0.0 { e / total } else { 0.0 };
+        let avg_price = state
+            .average_purchase_price
+            .get(sym)
+            .map_or("-".to_string(), |p| format!("{:.2}", p));
+        println!(
+            "{:<8}{:>10.2}{:>10.2}{:>+12.2}{:>12}{:>12.2}{:>12.2}",
+            sym,
+            ideal * 100.0,
+            actual * 100.0,
+            (actual - ideal) * 100.0,
+            avg_price,
+            state.total_shares_purchased.get(sym).cloned().unwrap_or(0.0),
+            state.cost_basis.get(sym).cloned().unwrap_or(0.0)
+        );
+    }
+    println!("Total cost basis = {:.2}", state.cost_basis.values().sum::<f64>());
+    Ok(())
+}
+
+async fn status(state_filename: &str, stop_file: &str) -> Result<()> {
+    let state = load_state(state_filename).await?;
+    let date = |dt: Option<DateTime<Utc>>| dt.map_or("never".to_string(), |dt| dt.to_rfc3339());
+
+    println!("Last funding = {}", date(state.last_funding_date));
+    println!(
+        "Next funding due = {}",
+        date(state.last_funding_date.map(|dt| state.funding_frequency.next_funding_dt(dt)))
+    );
+    println!("Finish date = {}", state.finish_date.to_rfc3339());
+    println!("Accumulated funding = {:.2}", state.fund_accum);
+    println!("Pending orders = {}", state.pending_orders.len());
+    for order in &state.requeued_orders {
+        println!("Requeued {:?} {} {}", order.side, order.quantity, order.symbol);
+    }
+    if shutdown::stop_file_exists(stop_file).await {
+        println!("Stop file {} exists, trading is paused", stop_file);
+    }
+    Ok(())
+}
+
+fn print_allocations(pos: &[position::Position], equities: &[f64], state: &State, label: &str) {
+    let total: f64 = equities.iter().sum();
+    let ideal_allocations = normalized_ideal_allocations(pos, state);
+
+    println!("{:<8}{:>10}{:>10}{:>10}", "Symbol", label, "Ideal %", "Avg cost");
+    for ((pos, e), ideal) in pos.iter().zip(equities).zip(&ideal_allocations) {
+        let actual = if total > 0.0 { e / total } else { 0.0 };
+        let avg_cost = cost_basis::average_cost(&state.cost_basis, &state.shares_held, &pos.symbol)
+            .map_or("-".to_string(), |c| format!("{:.2}", c));
+        println!("{:<8}{:>10.2}{:>10.2}{:>10}", pos.symbol, actual * 100.0, ideal * 100.0, avg_cost);
+    }
+}
+
+async fn report(client: &TimedClient, state_filename: &str) -> Result<()> {
+    let state = load_state(state_filename).await?;
+
+    let contributions = match state.equity_history.first() {
+        Some(&(start, _)) => performance::fetch_contributions(client, start).await?,
+        None => Vec::new(),
+    };
+    match performance::contribution_adjusted_return(&state.equity_history, &contributions) {
+        Some(r) => {
+            println!("Returns since {}", state.equity_history[0].0.format("%Y-%m-%d"));
+            println!("  Time-weighted return (investment performance) = {:.2}%", r.time_weighted_return * 100.0);
+            println!("  Money-weighted return (your experience) = {:.2}%", r.money_weighted_return * 100.0);
+            println!("  Net contributions = {:.2}", r.total_contributions);
+            println!("  Investment gain excluding contributions = {:.2}", r.total_investment_gain);
+        }
+        None => println!("No equity history recorded yet"),
+    }
+    println!();
+
+    if let (Some(initial_equity), Some(reference_price)) = (state.initial_equity, state.benchmark_reference_price) {
+        let equity = client.get_account().await?.equity.to_f64().unwrap();
+        match pricing::mid_price(client, &state.benchmark_symbol).await? {
+            Some(price) => println!(
+                "Portfolio {:+.2}% vs {} {:+.2}% since the first funding cycle",
+                (equity / initial_equity - 1.0) * 100.0,
+                state.benchmark_symbol,
+                (price / reference_price - 1.0) * 100.0
+            ),
+            None => println!("No quote for benchmark {}", state.benchmark_symbol),
+        }
+        println!();
+    }
+
+    if let Some(journal_path) = &state.journal_path {
+        let pos: Vec<_> = client.get_positions().await?;
+        println!("Returns of the journaled trades");
+        match performance::compute_twr(journal_path, &pos) {
+            Ok(twr) => println!("  Time-weighted return = {:.2}%", twr * 100.0),
+            Err(e) => println!("  Time-weighted return unavailable: {}", e),
+        }
+        match performance::compute_irr(journal_path, &pos) {
+            Ok(irr) => println!("  Internal rate of return (annualized) = {:.2}%", irr * 100.0),
+            Err(e) => println!("  Internal rate of return unavailable: {}", e),
+        }
+        println!();
+    }
+
+    let mut endpoints: Vec<_> = state.api_latency_avg_ms.keys().collect();
+    endpoints.sort();
+
+    println!("{:<24}{:>12}{:>12}", "Endpoint", "Avg ms", "P99 ms");
+    for endpoint in endpoints {
+        println!(
+            "{:<24}{:>12.0}{:>12.0}",
+            endpoint,
+            state.api_latency_avg_ms[endpoint],
+            state.api_latency_p99_ms.get(endpoint).cloned().unwrap_or(f64::NAN)
+        );
+    }
+
+    Ok(())
+}
+
+async fn clear_stop(stop_file: &str) -> Result<()> {
+    if !shutdown::stop_file_exists(stop_file).await {
+        info!("No stop file {} present", stop_file);
+        return Ok(());
+    }
+
+    tokio::fs::remove_file(stop_file).await?;
+    let user = std::env::var("USER").unwrap_or_else(|_| "unknown".to_string());
+    info!("Stop file {} cleared by {} at {}", stop_file, user, Utc::now());
+    Ok(())
+}
+
+async fn export(client: &TimedClient, state_filename: &str, format: ExportFormat, output: &str) -> Result<()> {
+    let state = load_state(state_filename).await?;
+
+    match format {
+        ExportFormat::NavSeries => {
+            let contributions = match state.equity_history.first() {
+                Some(&(start, _)) => performance::fetch_contributions(client, start).await?,
+                None => Vec::new(),
+            };
+            let series = performance::total_return_index(&state.equity_history, &contributions);
+            performance::write_nav_series(output, &series)?;
+            info!("Wrote {} NAV points to {}", series.len(), output);
+        }
+    }
+
+    Ok(())
+}
+
+// Share of the buying power kept back from the day's orders.
+const DEFAULT_BUYING_POWER_BUFFER_FRACTION: f64 = 0.02;
+
+// One day of funding: waits for the next trading time, then places the day's orders.
+async fn funding_cycle(
+    cli: &Cli,
+    client: &TimedClient,
+    state_filename: &str,
+    config: Option<&config::Config>,
+    shutdown: &Shutdown,
+    mut simulator: Option<&mut Simulator>,
+) -> Result<ControlFlow<()>> {
+    info!("Starting funding cycle");
+    let mut state = match simulator.as_mut().and_then(|sim| sim.state.take()) {
+        Some(state) => state,
+        None => get_state(client, state_filename, config).await?.0,
+    };
+    // neither dry nor simulated runs touch the account or the state file
+    let simulating = cli.dry_run || simulator.is_some();
+    let slack = config.and_then(|c| c.slack.as_ref());
+    if let Some(factor) = cli.slippage {
+        state.limit_price_factor = factor;
+    }
+
+    let warnings = reconcile::reconcile_positions(client, &mut state).await?;
+    if !warnings.is_empty() && state.reconcile_reference_equities && !simulating {
+        save_state(state_filename, &state).await?;
+    }
+    if !warnings.is_empty() && !state.reconcile_reference_equities && config.is_some_and(|c| c.halt_on_reconciliation) {
+        return Err(Error::Reconciliation(warnings));
+    }
+
+    // the source is a third-party site, so funding goes on with the last universe
+    // and the refresh is retried on the next cycle
+    if state.universe.as_ref().is_some_and(|u| u.refresh_due()) {
+        match universe::refresh_universe(&mut state).await {
+            Ok(()) if !simulating => save_state(state_filename, &state).await?,
+            Ok(()) => {}
+            Err(e) => warn!("Failed to refresh the universe, keeping the current one: {}", e),
+        }
+    }
+
+    let current_dt = Utc::now();
+    let order_settings = config.map(|c| c.order_settings()).unwrap_or_default();
+
+    // wait until next trading time
+    let earliest_next_trading_dt = if let Some(dt) = state.last_funding_date {
+        current_dt.max(state.funding_frequency.next_funding_dt(dt))
+    } else {
+        current_dt
+    };
+
+    let mut trigger_type = TriggerType::Scheduled;
+    // a dry run projects the next orders right away
+    if !cli.dry_run {
+        let earliest_next_trading_date_eastern = earliest_next_trading_dt.with_timezone(&Eastern).date_naive();
+        let trading_offset_minutes = config
+            .and_then(|c| c.trading_offset_minutes)
+            .unwrap_or(schedule::DEFAULT_TRADING_OFFSET_MINUTES);
+        let max_lookahead_days = config
+            .and_then(|c| c.calendar_max_lookahead_days)
+            .unwrap_or(schedule::DEFAULT_CALENDAR_MAX_LOOKAHEAD_DAYS);
+        let mut lookahead_days = config
+            .and_then(|c| c.calendar_lookahead_days)
+            .unwrap_or(schedule::DEFAULT_CALENDAR_LOOKAHEAD_DAYS)
+            .clamp(1, max_lookahead_days.max(1));
+        let pre_market_minutes = order_settings.extended_hours.then(|| {
+            config
+                .and_then(|c| c.extended_hours_offset_minutes)
+                .unwrap_or(schedule::DEFAULT_EXTENDED_HOURS_OFFSET_MINUTES)
+        });
+
+        let thin_liquidity = config.and_then(|c| c.thin_liquidity.clone()).unwrap_or_default();
+
+        // long closures can leave a window without a trading day, so it is widened until one turns up
+        let (next_trading_dt, market_close) = loop {
+            let calendar_req = calendar::CalendarReq {
+                start: earliest_next_trading_date_eastern,
+                end: earliest_next_trading_date_eastern + Duration::days(lookahead_days as i64),
+            };
+            let open_close = client.get_calendar(&calendar_req).await?;
+            if let Some(dts) = schedule::next_trading_dt(
+                &open_close,
+                &thin_liquidity,
+                trading_offset_minutes,
+                pre_market_minutes,
+            ) {
+                break dts;
+            }
+
+            if lookahead_days >= max_lookahead_days {
+                return Err(Error::UnexpectedData(format!(
+                    "no trading day in the {} days from {}",
+                    lookahead_days, earliest_next_trading_date_eastern
+                )));
+            }
+            lookahead_days = (lookahead_days * 2).min(max_lookahead_days);
+            warn!("No trading day found, widening the calendar window to {} days", lookahead_days);
+        };
+
+        let eastern_dt = next_trading_dt.with_timezone(&Eastern);
+        match config.and_then(|c| c.display_tz()) {
+            Some(tz) => info!(
+                "Waiting until next trading time {} ({})",
+                eastern_dt,
+                next_trading_dt.with_timezone(&tz)
+            ),
+            None => info!("Waiting until next trading time {}", eastern_dt),
+        }
+        info!("The market closes at {} that day", market_close.with_timezone(&Eastern));
+        state.last_market_close = Some(market_close);
+        // nothing else has changed since the state was loaded, and the close is
+        // only kept for the logs, so there is nothing to save
+        let wait = wait_for_funding_time(
+            client,
+            &state,
+            config,
+            next_trading_dt,
+            Duration::minutes(DRIFT_CHECK_INTERVAL_MINUTES),
+        );
+        match shutdown.run_until(wait).await {
+            Some(trigger) => trigger_type = trigger,
+            None => return Ok(ControlFlow::Break(())),
+        }
+    }
+
+    if !simulating && !shutdown::wait_while_stopped(shutdown, shutdown::STOP_FILE_POLL_INTERVAL).await {
+        return Ok(ControlFlow::Break(()));
+    }
+
+    if !simulating && !state.pending_orders.is_empty() {
+        info!("Rechecking {} pending orders", state.pending_orders.len());
+        let ttl = Duration::hours(state.pending_order_ttl_hours as i64);
+        expire_stale_orders(client, &mut state.pending_orders, ttl).await?;
+        state.monitor_pending_orders(client, shutdown, slack).await?;
+        save_state(state_filename, &state).await?;
+    }
+
+    if !simulating && !state.requeued_orders.is_empty() {
+        state.submit_requeued_orders(client, shutdown).await?;
+        save_state(state_filename, &state).await?;
+    }
+
+    if config.is_some_and(|c| c.sell_removed_symbols) {
+        let keep = config.and_then(|c| c.idle_cash_symbol.as_deref());
+        let tracked_only = config.is_some_and(|c| c.capital_share.is_some());
+        if reconcile::sell_removed_positions(client, shutdown, &mut state, keep, tracked_only, simulating).await? {
+            state.monitor_pending_orders(client, shutdown, slack).await?;
+            save_state(state_filename, &state).await?;
+        }
+    }
+
+    let account = client.get_account().await?;
+    // a portfolio is funded from its share of the account, recalculated every cycle
+    let capital_share = config.and_then(|c| c.capital_share).unwrap_or(1.0);
+
+    let equity = account.equity.to_f64().unwrap() * capital_share; info!("Account equity = {}", equity);
+    state.equity_history.push((Utc::now(), equity));
+    let drawdown = state.record_drawdown(equity);
+    // the benchmark is informational, so failing to price it doesn't stop trading
+    match pricing::mid_price(client, &state.benchmark_symbol).await {
+        Ok(Some(price)) => {
+            let (portfolio_return, benchmark_return) = state.benchmark_returns(equity, price);
+            info!(
+                "Portfolio return = {:.2}%, {} return = {:.2}%",
+                portfolio_return * 100.0,
+                state.benchmark_symbol,
+                benchmark_return * 100.0
+            );
+        }
+        Ok(None) => warn!("No quote for benchmark {}", state.benchmark_symbol),
+        Err(e) => warn!("Failed to price benchmark {}: {}", state.benchmark_symbol, e),
+    }
+    let halted = config
+        .and_then(|c| c.halt_on_drawdown)
+        .is_some_and(|threshold| drawdown > threshold);
+    let reference_equity = state.reference_equities.values().sum::<f64>();
+    let cash = account.cash.to_f64().unwrap() * capital_share; info!("Account cash = {}", cash);
+    let buying_power = account.buying_power.to_f64().unwrap() * capital_share; info!("Account buying power = {}", buying_power);
+    if let Some(sim) = simulator.as_deref_mut() {
+        sim.seed_cash(cash);
+    }
+
+    let idle_cash = config.and_then(|c| c.idle_cash_sweep());
+    let mut pos: Vec<_> = client.get_positions().await?;
+    // the idle cash position is spent like cash
+    let idle_value = idle_cash.as_ref().map_or(0.0, |sweep| sweep.held_value(&pos));
+
+    let total_invested = if config.is_some_and(|c| c.capital_share.is_some()) {
+        retain_portfolio_positions(&mut pos, &state, config);
+        pos.iter().map(|pos| pos.market_value.as_ref().unwrap().to_f64().unwrap()).sum()
+    } else {
+        equity - cash - idle_value
+    };
+
+    extend_finish_date(&mut state, current_dt, config.and_then(|c| c.auto_extend_days))?;
+
+    let total_additional_funding =
+        reference_equity * state.target_investment_equity_ratio - total_invested;
+    let planner = FundingPlanner::from_state(&state, config.and_then(|c| c.reinvestment_rate).unwrap_or(0.0));
+    let periodic_funding = planner.periodic_funding(current_dt, total_additional_funding);
+
+    info!("Periodic funding ({:?}) = {}", state.funding_frequency, periodic_funding);
+
+    // cash beyond what the last cycle left and a period's deposit is taken to be dividends
+    let dividends = state
+        .expected_cash
+        .map_or(0.0, |expected| (cash - expected - periodic_funding).max(0.0));
+    if dividends > 0.0 {
+        info!("Reinvesting {:.2} of dividends", dividends);
+    }
+
+    let funding_today = planner.funding_due(current_dt, periodic_funding) + state.fund_accum + dividends;
+
+    info!("Funding today = {}", funding_today);
+
+    let mut buying_power = buying_power;
+    if let Some(sweep) = &idle_cash {
+        if let Some(amount) = sweep.unwind_amount(idle_value, cash, funding_today).filter(|_| !halted) {
+            if simulating {
+                info!("Would sell ${:.2} of {} to fund today's orders", amount, sweep.symbol);
+            } else {
+                info!("Selling ${:.2} of {} to fund today's orders", amount, sweep.symbol);
+                let held = sweep.position(&pos).unwrap().clone();
+                if let Err(e) = sweep.sell(client, shutdown, &mut state, &held, amount).await {
+                    error!("Failed to sell idle cash: {}", e);
+                }
+                state.monitor_pending_orders(client, shutdown, slack).await?;
+                buying_power = client.get_account().await?.buying_power.to_f64().unwrap() * capital_share;
+                pos = client.get_positions().await?;
+            }
+        }
+        pos.retain(|pos| pos.symbol != sweep.symbol);
+    }
+
+    if config.is_some_and(|c| c.auto_discover_new_positions) {
+        reconcile::discover_new_positions(&mut state, &pos);
+    }
+
+    let min_equity = config.and_then(|c| c.watchlist_min_equity).unwrap_or(0.0);
+    watchlist::graduate_watchlist(&mut state, &pos, equity, min_equity);
+
+    if let Some(config) = config.filter(|c| !c.tax_loss_pairs.is_empty() && !halted) {
+        let threshold = config.harvest_threshold.unwrap_or(harvest::DEFAULT_HARVEST_THRESHOLD);
+        if simulating {
+            info!("Dry or simulated run, skipping tax-loss harvesting");
+        } else if harvest::harvest_losses(
+            client,
+            &mut state,
+            &pos,
+            &config.tax_loss_pairs,
+            threshold,
+            order_settings,
+            shutdown,
+            config.slack.as_ref(),
+        )
+        .await?
+        {
+            let mut harvested_pos: Vec<_> = client.get_positions().await?;
+            if let Some(sweep) = &idle_cash {
+                harvested_pos.retain(|pos| pos.symbol != sweep.symbol);
+            }
+            retain_portfolio_positions(&mut harvested_pos, &state, Some(config));
+            pos = harvested_pos;
+        }
+    }
+
+    reconcile::add_unheld_targets(client, &state, &mut pos).await?;
+    // the order search breaks ties by position, so the API's ordering mustn't matter
+    pos.sort_by(|a, b| a.symbol.cmp(&b.symbol));
+
+    // limit prices and rounding can push the orders slightly past the funding
+    let buffer_fraction = config
+        .and_then(|c| c.buying_power_buffer_fraction)
+        .unwrap_or(DEFAULT_BUYING_POWER_BUFFER_FRACTION);
+    let budget = if funding_today > buying_power {
+        let capped = buying_power * (1.0 - buffer_fraction);
+        warn!(
+            "Funding today {:.2} exceeds buying power {:.2}, capping orders at {:.2}",
+            funding_today, buying_power, capped
+        );
+        capped
+    } else {
+        funding_today.min(buying_power * (1.0 - buffer_fraction))
+    };
+
+    let mse = current_mse(&pos, &state);
+    // the score is informational, so failing to fetch the turnover doesn't stop trading
+    let score = match portfolio_urgency(client, &state, mse, equity, config.and_then(|c| c.rebalance_weights)).await {
+        Ok(score) => score,
+        Err(e) => {
+            warn!("Failed to compute the urgency score: {}", e);
+            NEUTRAL_URGENCY
+        }
+    };
+    if score > URGENT_SCORE {
+        warn!("{}Urgency score = {:.0}", urgency_tag(score), score);
+    } else {
+        info!("Urgency score = {:.0}", score);
+    }
+
+    let drift = mse.sqrt();
+    let mut projected_equities = virtual_equities(&pos, &state);
+    let mut orders_planned = 0;
+    let mut orders_placed = 0;
+    let mut order_summaries = Vec::new();
+    let funds_used = if halted {
+        warn!("Drawdown {:.2}% is beyond halt_on_drawdown, skipping orders", drawdown * 100.0);
+        0.0
+    } else if drift < state.min_rebalance_drift {
+        // the unspent funding carries over in fund_accum
+        info!(
+            "Drift {:.4} is below min_rebalance_drift {:.4}, skipping orders",
+            drift, state.min_rebalance_drift
+        );
+        0.0
+    } else if budget > 0.0 {
+        let virtual_equities = virtual_equities(&pos, &state);
+        let prices: Vec<_> = pos
+            .iter()
+            .map(|pos| pos.current_price.as_ref().unwrap().to_f64().unwrap())
+            .collect();
+        // orders are sized at the smoothed prices but still limited off the live ones
+        let smoothed_prices = match config.and_then(|c| c.price_ema_days) {
+            Some(days) => Some(pricing::ema_prices(client, &pos, &prices, days).await?),
+            None => None,
+        };
+        let sizing_prices = smoothed_prices.clone().unwrap_or_else(|| prices.clone());
+
+        let normalized_ideal_allocations = normalized_ideal_allocations(&pos, &state);
+
+        // overweight symbols are trimmed first so the proceeds fund today's buys
+        let trims = match config.and_then(|c| c.sell_rebalance_threshold) {
+            Some(threshold) => trim_orders(
+                &virtual_equities,
+                &sizing_prices,
+                &normalized_ideal_allocations,
+                threshold,
+                state.fractional_shares,
+            ),
+            None => Vec::new(),
+        };
+        let mut trimmed_equities = virtual_equities;
+        let mut min_allocations = allocation_bounds(&pos, &state.min_allocations, 0.0);
+        let mut max_allocations = allocation_bounds(&pos, &state.max_allocations, 1.0);
+        for (idx, _, amount) in &trims {
+            let idx = *idx;
+            trimmed_equities[idx] -= amount.to_f64().unwrap();
+            // a symbol is only traded in one direction per batch
+            min_allocations[idx] = 0.0;
+            max_allocations[idx] = 0.0;
+        }
+        let trim_proceeds = sum_money(trims.iter().map(|(_, _, amount)| amount)).to_f64().unwrap();
+        let trimmed: HashSet<_> = trims.iter().map(|&(idx, _, _)| idx).collect();
+
+        let buy_sizes = match config.filter(|c| c.volatility_scaling) {
+            Some(c) => {
+                let target_vol = c.target_vol.unwrap_or(volatility::DEFAULT_TARGET_VOL);
+                volatility::scaled_buy_sizes(client, &pos, &sizing_prices, target_vol).await?
+            }
+            None => sizing_prices.clone(),
+        };
+
+        let allocator = Allocator {
+            ideal_allocations: normalized_ideal_allocations.clone(),
+            min_allocations,
+            max_allocations,
+            sell_enabled: state.sell_enabled,
+            fractional_shares: state.fractional_shares,
+            weighted_error: config.is_some_and(|c| c.use_weighted_error),
+        };
+        let (buys, _) = allocator.generate_orders(&trimmed_equities, &sizing_prices, &buy_sizes, budget + trim_proceeds)?;
+        let orders = consolidate_orders(trims.into_iter().chain(buys).collect());
+        orders_planned = orders.len();
+
+        // sell proceeds fund additional buys
+        let sell_proceeds = sum_money(
+            orders
+                .iter()
+                .filter(|&&(_, side, _)| side == order::Side::Sell)
+                .map(|(_, _, f)| f),
+        );
+        let funds_used = sum_money(
+            orders
+                .iter()
+                .filter(|&&(_, side, _)| side == order::Side::Buy)
+                .map(|(_, _, f)| f),
+        ) - &sell_proceeds;
+
+        debug!("Orders: {:?}", orders);
+
+        let uses_limits = order_settings.order_type == pricing::OrderType::Limit;
+        let notional_syms = if order_settings.notional {
+            let syms: HashSet<_> = orders
+                .iter()
+                .filter(|&&(_, side, _)| side == order::Side::Buy)
+                .map(|&(idx, _, _)| pos[idx].symbol.clone())
+                .collect();
+            fractionable_symbols(client, syms).await?
+        } else {
+            HashSet::new()
+        };
+        let marketable = order_settings.order_type == pricing::OrderType::MarketableLimit;
+        let quotes = if (state.limit_price_strategy.needs_quotes() && uses_limits) || marketable {
+            let syms: HashSet<_> = orders.iter().map(|&(idx, _, _)| pos[idx].symbol.clone()).collect();
+            pricing::get_quotes(client, syms).await?
+        } else {
+            HashMap::new()
+        };
+
+        let limit_prices: Vec<_> = orders
+            .iter()
+            .map(|&(idx, side, _)| {
+                let price = prices[idx];
+                if side == order::Side::Sell && trimmed.contains(&idx) && uses_limits {
+                    return price * TRIM_SELL_LIMIT_FACTOR;
+                }
+                order_settings.order_price(&state, side, price, quotes.get(&pos[idx].symbol).cloned())
+            })
+            .collect();
+
+        // sells are sized in whole shares, or cents of a share, at the current
+        // price, so only the buys need rounding and their budget includes the
+        // sell proceeds
+        let sized_buys: Vec<_> = orders
+            .iter()
+            .zip(&limit_prices)
+            .filter(|(&(_, side, _), _)| side == order::Side::Buy)
+            .map(|((idx, _, funding), &limit_price)| {
+                (funding.to_f64().unwrap(), smoothed_prices.as_ref().map_or(limit_price, |p| p[*idx]))
+            })
+            .collect();
+        let sell_proceeds = sell_proceeds.to_f64().unwrap();
+        let buy_quantities: Vec<f64> = if state.fractional_shares {
+            // floored to the cent so the orders never spend more than their funds
+            sized_buys
+                .iter()
+                .map(|&(funds, limit_price)| (funds / limit_price * 100.0).floor() / 100.0)
+                .collect()
+        } else {
+            rounding::order_quantities(&sized_buys, budget + sell_proceeds, state.rounding_strategy)
+                .into_iter()
+                .map(|q| q as f64)
+                .collect()
+        };
+        let mut buy_quantities = buy_quantities.into_iter();
+
+        // funds of orders that weren't placed carry over to the next day
+        let mut unplaced_funds = Num::from(0);
+
+        // the order ids are saved before submitting, so a run restarted after a
+        // crash looks up the ones it may already have submitted instead of
+        // submitting them twice
+        let today = Utc::now().with_timezone(&Eastern).date_naive();
+        let client_order_ids: Vec<_> = orders
+            .iter()
+            .map(|&(idx, side, _)| funding_order_id(state_filename, today, &pos[idx].symbol, side))
+            .collect();
+        let resumable_ids: HashSet<_> = client_order_ids
+            .iter()
+            .filter(|id| state.client_order_ids.contains(id))
+            .cloned()
+            .collect();
+        if !simulating && !orders.is_empty() {
+            state.client_order_ids = client_order_ids;
+            let session_id = today.to_string();
+            state.fill_session = match state.fill_session.take() {
+                _ if !config.is_some_and(|c| c.notify_on_all_fills) => None,
+                Some(session) if session.id == session_id => Some(session),
+                _ => Some(fill_session::FillSession::new(session_id)),
+            };
+            save_state(state_filename, &state).await?;
+        }
+
+        for ((idx, side, funding), &limit_price) in orders.iter().zip(&limit_prices) {
+            let (idx, side) = (*idx, *side);
+            let signed_funding = match side {
+                order::Side::Buy => funding.clone(),
+                order::Side::Sell => -funding,
+            };
+            let funding = funding.to_f64().unwrap();
+            let qty = match side {
+                order::Side::Buy => buy_quantities.next().unwrap(),
+                order::Side::Sell => (funding / sizing_prices[idx] * 100.0).round() / 100.0,
+            };
+            // notional buys spend their funding exactly, whatever quantity it buys
+            let notional = side == order::Side::Buy && notional_syms.contains(&pos[idx].symbol);
+            let qty = if notional { funding / limit_price } else { qty };
+            if qty <= 0.0 {
+                continue;
+            }
+            let summary = rebalancing_report::OrderSummary {
+                symbol: pos[idx].symbol.clone(),
+                side,
+                qty,
+                price: limit_price,
+                estimated_cost: qty * limit_price,
+            };
+
+            if cli.dry_run {
+                simulate_order(&pos[idx].symbol, side, limit_price, qty, &mut projected_equities[idx]);
+                order_summaries.push(summary);
+                continue;
+            }
+
+            if let Some(sim) = simulator.as_deref_mut() {
+                if sim.submit(&pos[idx].symbol, side, limit_price, qty) {
+                    projected_equities[idx] += match side {
+                        order::Side::Buy => summary.estimated_cost,
+                        order::Side::Sell => -summary.estimated_cost,
+                    };
+                    order_summaries.push(summary);
+                } else {
+                    unplaced_funds += signed_funding;
+                }
+                continue;
+            }
+
+            if shutdown.trading_stopped().await {
+                unplaced_funds += signed_funding;
+                continue;
+            }
+
+            // the day's state must still be saved, so a failed order only skips that order
+            let client_order_id = funding_order_id(state_filename, today, &pos[idx].symbol, side);
+            let submitted = if resumable_ids.contains(&client_order_id) {
+                find_order_by_client_id(client, &client_order_id).await
+            } else {
+                Ok(None)
+            };
+            let submitted = match submitted {
+                Ok(Some(order)) => {
+                    info!(
+                        "Order {} for {} was already submitted before a restart",
+                        client_order_id, pos[idx].symbol
+                    );
+                    if !state.pending_orders.iter().any(|p| p.id == order.id.to_string()) {
+                        state.pending_orders.push(PendingOrder::new(&order, qty));
+                    }
+                    if let Some(session) = &mut state.fill_session {
+                        session.record_submission(&pos[idx].symbol, side, qty, limit_price);
+                    }
+                    Ok(None)
+                }
+                Ok(None) => {
+                    let request = if notional {
+                        notional_order_request(&pos[idx].symbol, side, funding)
+                    } else {
+                        order_request(&pos[idx].symbol, side, limit_price, qty, state.fractional_shares, order_settings)
+                    };
+                    match request {
+                        Ok(request) => {
+                            let request = order::OrderReq {
+                                client_order_id: Some(client_order_id),
+                                ..request
+                            };
+                            submit_order_idempotent(client, &request, shutdown).await
+                        }
+                        Err(e) => Err(e),
+                    }
+                }
+                Err(e) => Err(e),
+            };
+            let order =
+                match submitted {
+                    Ok(Some(order)) => order,
+                    // the open or earlier order already spends these funds
+                    Ok(None) => continue,
+                    Err(e @ Error::OrderRejected { .. }) => {
+                        warn!("{}", e);
+                        unplaced_funds += signed_funding;
+                        continue;
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        unplaced_funds += signed_funding;
+                        continue;
+                    }
+                };
+            info!(
+                symbol = %pos[idx].symbol, side = ?side, qty, limit_price, order_id = %order.id.as_hyphenated(),
+                "Submitted order"
+            );
+            let error_before = planning_error(&projected_equities, &allocator.ideal_allocations, allocator.weighted_error);
+            projected_equities[idx] += match side {
+                order::Side::Buy => summary.estimated_cost,
+                order::Side::Sell => -summary.estimated_cost,
+            };
+            let drift_error = journal::DriftError {
+                before: error_before,
+                after: planning_error(&projected_equities, &allocator.ideal_allocations, allocator.weighted_error),
+            };
+            journal::record(
+                state.journal_path.as_deref(),
+                journal::JournalEvent::Submitted,
+                &order,
+                qty,
+                limit_price,
+                Some(drift_error),
+            );
+            state.pending_orders.push(PendingOrder::new(&order, qty));
+            if let Some(session) = &mut state.fill_session {
+                session.record_submission(&pos[idx].symbol, side, qty, limit_price);
+            }
+            orders_placed += 1;
+            order_summaries.push(summary);
+        }
+
+        if shutdown.trading_stopped().await {
+            warn!("Trading stopped, the remaining orders were not submitted");
+        }
+
+        (funds_used - unplaced_funds).to_f64().unwrap()
+    } else {
+        0.0
+    };
+
+    state.fund_accum = funding_today - funds_used;
+    state.last_funding_date = Some(Utc::now());
+    state.last_drift_trigger = match trigger_type {
+        TriggerType::DriftTriggered { .. } => state.last_funding_date,
+        TriggerType::Scheduled => None,
+    };
+
+    // only kept for analyzing the reports later, so it's left out when unavailable
+    let market_vol = match volatility::market_volatility(client).await {
+        Ok(vol) => Some(vol),
+        Err(e) => {
+            warn!("Failed to measure the market volatility: {}", e);
+            None
+        }
+    };
+    let report = rebalancing_report::RebalancingReport {
+        timestamp: Utc::now(),
+        dry_run: simulating,
+        trigger_type,
+        total_equity: equity,
+        cash_deployed: funds_used,
+        orders: order_summaries,
+        pre_allocation: rebalancing_report::allocation_fractions(&pos, &virtual_equities(&pos, &state)),
+        post_allocation: rebalancing_report::allocation_fractions(&pos, &projected_equities),
+        allocation_rmse_before: drift,
+        allocation_rmse_after: allocation_error(&projected_equities, &normalized_ideal_allocations(&pos, &state)).sqrt(),
+        market_vol,
+    };
+    let reports_dir = config
+        .and_then(|c| c.reports_dir.as_deref())
+        .unwrap_or(rebalancing_report::DEFAULT_REPORTS_DIR);
+    match report.write(reports_dir) {
+        Ok(path) => debug!("Wrote the rebalancing report to {}", path.display()),
+        Err(e) => error!("Failed to write the rebalancing report to {}: {}", reports_dir, e),
+    }
+
+    if cli.dry_run {
+        info!("Dry run, the state file was not updated");
+        print_allocations(&pos, &projected_equities, &state, "Projected %");
+        return Ok(ControlFlow::Break(()));
+    }
+
+    if let Some(sim) = simulator {
+        info!("Simulated funding cycle done, virtual cash = {:.2}", sim.cash());
+        print_allocations(&pos, &projected_equities, &state, "Projected %");
+        sim.state = Some(state);
+        return Ok(ControlFlow::Continue(()));
+    }
+
+    // cash is only swept while the balancer has nothing to buy. Orders skipped
+    // as already submitted before a restart, or rejected, were still planned
+    // and their funds are still meant for them.
+    if let Some(sweep) = idle_cash.filter(|_| orders_planned == 0 && !halted && !shutdown.is_requested()) {
+        let account = client.get_account().await?;
+        let available = (account.cash.to_f64().unwrap(), account.buying_power.to_f64().unwrap());
+        if let Some(amount) = sweep.sweep_amount(available.0, available.1) {
+            info!("No orders planned, sweeping ${:.2} of idle cash into {}", amount, sweep.symbol);
+            if let Err(e) = sweep.buy(client, shutdown, &mut state, amount).await {
+                error!("Failed to sweep idle cash: {}", e);
+            }
+        }
+    }
+
+    client
+        .timer
+        .update_averages(&mut state.api_latency_avg_ms, &mut state.api_latency_p99_ms);
+    save_state(state_filename, &state).await?;
+
+    if let Some(slack_config) = slack {
+        let snapshot = slack::PortfolioSnapshot {
+            timestamp: Utc::now(),
+            equity,
+            cash,
+            drift,
+            urgency: score,
+            alert: score > URGENT_SCORE,
+        };
+        if let Err(e) = slack::send_slack_summary(slack_config, &snapshot).await {
+            error!("Failed to send Slack summary: {}", e);
+        }
+    }
+
+    if !state.pending_orders.is_empty() {
+        state.monitor_pending_orders(client, shutdown, slack).await?;
+    }
+    let account = client.get_account().await?;
+    state.expected_cash = Some(account.cash.to_f64().unwrap() * capital_share);
+    save_state(state_filename, &state).await?;
+
+    let snapshot_path = config
+        .and_then(|c| c.snapshot_path.as_deref())
+        .unwrap_or(snapshot::DEFAULT_SNAPSHOT_PATH);
+    let mut pos: Vec<_> = client.get_positions().await?;
+    retain_portfolio_positions(&mut pos, &state, config);
+    if let Err(e) = snapshot::export_snapshot(snapshot_path, &pos, &state) {
+        error!("Failed to export snapshot to {}: {}", snapshot_path, e);
+    }
+
+    if let Some(path) = config.and_then(|c| c.history_db.as_deref()) {
+        let row = history::HistoryRow {
+            date: Utc::now().format("%Y-%m-%d").to_string(),
+            equity: account.equity.to_f64().unwrap() * capital_share,
+            cash: account.cash.to_f64().unwrap() * capital_share,
+            allocations: rebalancing_report::allocation_fractions(&pos, &virtual_equities(&pos, &state)),
+            rmse: current_mse(&pos, &state).sqrt(),
+            orders_placed,
+            cash_deployed: funds_used,
+            trigger_type,
+        };
+        if let Err(e) = history::append(path, &row) {
+            error!("Failed to append to the history database {}: {}", path, e);
+        }
+    }
+
+    if shutdown.is_requested() {
+        return Ok(ControlFlow::Break(()));
+    }
+
+    Ok(ControlFlow::Continue(()))
+}
+
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+// The config is optional unless `--config` names one or the environment sets
+// some of its fields.
+fn load_optional_config(cli: &Cli) -> Result<Option<config::Config>> {
+    let config_filename = cli.config.as_deref().unwrap_or(DEFAULT_CONFIG_FILE);
+    if cli.config.is_some() || fs::metadata(config_filename).is_ok() || !config::env_overrides().is_empty() {
+        Ok(Some(config::load_config(config_filename)?))
+    } else {
+        Ok(None)
+    }
+}
+
+// Runs the subcommand, or the daily funding loop without one.
+pub async fn run(mut cli: Cli) -> Result<()> {
+    init_logging(cli.log_format, cli.log_level.as_deref())?;
+    if let Some(Command::Plan) = cli.command {
+        cli.dry_run = true;
+    }
+    if let Some(factor) = cli.slippage {
+        validate_limit_price_factor(factor)?;
+    }
+    encryption::init(cli.passphrase.clone());
+
+    // Assumes credentials to be present in the `APCA_API_KEY_ID` and
+    // `APCA_API_SECRET_KEY` environment variables unless the config lists accounts.
+    let state_filename = cli.state.as_str();
+
+    // these don't call the Alpaca API
+    match &cli.command {
+        Some(Command::StressTest { scenario, initial_equity }) => {
+            let mut state = load_state(state_filename).await?;
+            if let Some(factor) = cli.slippage {
+                state.limit_price_factor = factor;
+            }
+            return stress_test::stress_test(&state, scenario, *initial_equity);
+        }
+        Some(Command::SetAllocation { symbol, weight }) => {
+            return set_allocation(state_filename, symbol, *weight).await;
+        }
+        Some(Command::QueryHistory { db, format }) => return history::print_history(db, *format),
+        Some(Command::AnalyzeLog { reports_dir }) => {
+            let reports = rebalancing_report::read_reports(reports_dir)?;
+            log_analysis::print_analysis(&log_analysis::analyze_rebalance_log(&reports)?);
+            return Ok(());
+        }
+        Some(Command::Status) => return status(state_filename, &cli.stop_file).await,
+        Some(Command::RestoreState { backup }) => return restore_state(state_filename, backup.as_deref()).await,
+        _ => {}
+    }
+
+    let loop_command = matches!(cli.command, None | Some(Command::Run | Command::Plan | Command::Init));
+    if let Some(command) = cli.command.as_ref().filter(|_| !loop_command) {
+        let client = TimedClient::new(Client::new(cli.api_info()?));
+        return match command {
+            Command::IncomeCalendar => income::print_income_calendar(&client).await,
+            Command::Show => {
+                let weights = load_optional_config(&cli)?.and_then(|c| c.rebalance_weights);
+                show(&client, state_filename, weights).await
+            }
+            Command::Report => report(&client, state_filename).await,
+            Command::SimulateLimitSavings { days, max_pct_from_bid, baseline_factor } => {
+                validate_limit_price_factor(*baseline_factor)?;
+                pricing::simulate_narrow_spread_savings(&client, *days, *max_pct_from_bid, *baseline_factor).await
+            }
+            Command::RefreshUniverse => {
+                let mut state = load_state(state_filename).await?;
+                universe::refresh_universe(&mut state).await?;
+                save_state(state_filename, &state).await
+            }
+            Command::ClearStop => clear_stop(&cli.stop_file).await,
+            Command::Export { format, output } => export(&client, state_filename, *format, output).await,
+            Command::ShowAllocations => show_allocations(&client, state_filename).await,
+            Command::Backtest { start, end, initial_equity } => {
+                let state = load_state(state_filename).await?;
+                backtest::backtest(&client, &state, *start, *end, *initial_equity).await
+            }
+            Command::StressTest { .. }
+            | Command::SetAllocation { .. }
+            | Command::QueryHistory { .. }
+            | Command::AnalyzeLog { .. }
+            | Command::RestoreState { .. }
+            | Command::Status
+            | Command::Run
+            | Command::Plan
+            | Command::Init => unreachable!(),
+        };
+    }
+
+    let config_filename = cli.config.as_deref().unwrap_or(DEFAULT_CONFIG_FILE);
+    let config = load_optional_config(&cli)?;
+
+    let shutdown = Arc::new(Shutdown::with_stop_file(&cli.stop_file));
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move { shutdown::listen_for_signals(&shutdown).await }
+    });
+
+    // the SQLite backend keeps the state in state.db unless --state says otherwise
+    let state_filename = match config.as_ref().and_then(|c| c.state_backend) {
+        Some(StateBackend::Sqlite) if state_filename == DEFAULT_STATE_FILE => persistence::DEFAULT_SQLITE_STATE_FILE,
+        _ => state_filename,
+    };
+
+    let Some(accounts) = config.as_ref().map(|c| &c.accounts).filter(|accounts| !accounts.is_empty()) else {
+        let client = TimedClient::new(Client::new(cli.api_info()?));
+        let Some(portfolios) = config.as_ref().map(|c| &c.portfolios).filter(|p| !p.is_empty()) else {
+            return run_account(&cli, &client, state_filename, config.as_ref(), config_filename, &shutdown).await;
+        };
+
+        // the portfolios share the client and the account, but keep their own schedules
+        let runs = portfolios.iter().map(|portfolio| {
+            run_account(&cli, &client, &portfolio.state_file, Some(&portfolio.config), config_filename, &shutdown)
+                .instrument(tracing::info_span!("portfolio", state = %portfolio.state_file))
+        });
+        futures::future::try_join_all(runs).await?;
+        return Ok(());
+    };
+
+    // each account trades on its own schedule, so they run side by side
+    let runs = accounts.iter().map(|account| {
+        let (cli, shutdown) = (&cli, &shutdown);
+        async move {
+            let client = TimedClient::new(Client::new(account.api_info(cli.base_url())?));
+            run_account(cli, &client, &account.state_file, Some(&account.config), config_filename, shutdown).await
+        }
+        .instrument(tracing::info_span!("account", state = %account.state_file))
+    });
+    futures::future::try_join_all(runs).await?;
+    Ok(())
+}
+
+// Sets up one account's state and funds it until the program stops.
+async fn run_account(
+    cli: &Cli,
+    client: &TimedClient,
+    state_filename: &str,
+    config: Option<&config::Config>,
+    config_filename: &str,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    if cli.recalculate_allocations {
+        return recalculate_allocations(client, state_filename, config).await;
+    }
+
+    match get_state(client, state_filename, config).await? {
+        (_, StateSource::Generated) => {
+            info!("No state file found so a default has been generated. Configure it according to your needs and rerun this program.");
+            return Ok(());
+        }
+        (_, StateSource::FromFile) if matches!(cli.command, Some(Command::Init)) => {
+            warn!("{} already exists, leaving it as is", state_filename);
+            return Ok(());
+        }
+        (state, StateSource::FromFile) => {
+            for field in config.iter().flat_map(|config| config.disagreements(&state)) {
+                warn!(
+                    "{} in {} differs from {}, which takes precedence",
+                    field, config_filename, state_filename
+                );
+            }
+        }
+    }
+
+    let mut simulator = cli.simulate.then(Simulator::default);
+    loop {
+        let cycle = funding_cycle(cli, client, state_filename, config, shutdown, simulator.as_mut())
+            .instrument(tracing::info_span!("funding_cycle"))
+            .await;
+
+        match cycle {
+            Ok(ControlFlow::Break(())) => {
+                if shutdown.is_requested() {
+                    info!("Shut down cleanly");
+                }
+                return Ok(());
+            }
+            Ok(ControlFlow::Continue(())) => {}
+            // the day's state hasn't been saved, so the whole cycle is retried
+            Err(e) if e.is_transient() => {
+                error!("{}, retrying the funding cycle in 5 minutes", e);
+                if shutdown.run_until(tokio::time::sleep(time::Duration::from_secs(5 * 60))).await.is_none() {
+                    return Ok(());
+                }
+            }
+            // a dry run only plans the one day, so it stops with the error
+            Err(e) if e.skips_day() && !cli.dry_run => {
+                let next = skip_day(client, state_filename, config, simulator.as_mut()).await?;
+                error!("{}, skipping today's funding cycle until {}", e, next.with_timezone(&Eastern));
+                // errors raised before the wait for the trading time would otherwise repeat right away
+                let wait = (next - Utc::now()).to_std().unwrap_or_default();
+                if shutdown.run_until(tokio::time::sleep(wait)).await.is_none() {
+                    return Ok(());
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Marks today's funding as done without placing its orders, so the next cycle
+// waits for the following funding date instead of failing the same way again.
+// Returns that date.
+async fn skip_day(
+    client: &TimedClient,
+    state_filename: &str,
+    config: Option<&config::Config>,
+    simulator: Option<&mut Simulator>,
+) -> Result<DateTime<Utc>> {
+    let mut state = get_state(client, state_filename, config).await?.0;
+    let now = Utc::now();
+    state.last_funding_date = Some(now);
+    let next = state.funding_frequency.next_funding_dt(now);
+    match simulator {
+        Some(sim) => sim.state = Some(state),
+        None => save_state(state_filename, &state).await?,
+    }
+    Ok(next)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::StatusCode;
+    use chrono::NaiveDate;
+    use testing::{order_json, MockBroker, MockClient};
+
+    fn money(amounts: &[f64]) -> Vec<Num> {
+        amounts.iter().map(|&amount| to_money(amount)).collect()
+    }
+
+    #[test]
+    fn zero_budget_places_no_orders() {
+        let (orders, equities) = generate_orders(
+            &money(&[100.0, 50.0]),
+            &money(&[10.0, 20.0]),
+            &money(&[10.0, 20.0]),
+            [0.5, 0.5].into_iter(),
+            &[0.0, 0.0],
+            &[1.0, 1.0],
+            to_money(0.0),
+            true,
+            true,
+            false,
+        )
+        .unwrap();
+        assert!(orders.is_empty());
+        assert_eq!(equities, money(&[100.0, 50.0]));
+    }
+
+    #[test]
+    fn single_symbol_gets_the_whole_budget() {
+        let buy = |fractional| {
+            generate_orders(
+                &money(&[0.0]),
+                &money(&[10.0]),
+                &money(&[10.0]),
+                [1.0].into_iter(),
+                &[0.0],
+                &[1.0],
+                to_money(35.0),
+                false,
+                fractional,
+                false,
+            )
+            .unwrap()
+        };
+
+        let (orders, equities) = buy(false);
+        assert_eq!(consolidate_orders(orders), vec![(0, order::Side::Buy, to_money(30.0))]);
+        assert_eq!(equities, money(&[30.0]));
+
+        let (orders, equities) = buy(true);
+        assert_eq!(consolidate_orders(orders), vec![(0, order::Side::Buy, to_money(35.0))]);
+        assert_eq!(equities, money(&[35.0]));
+    }
+
+    #[test]
+    fn many_small_buys_spend_the_budget_exactly() {
+        let (orders, equities) = generate_orders(
+            &money(&[0.0]),
+            &money(&[0.1]),
+            &money(&[0.1]),
+            [1.0].into_iter(),
+            &[0.0],
+            &[1.0],
+            to_money(100.0),
+            false,
+            false,
+            false,
+        )
+        .unwrap();
+        // adding 0.1 a thousand times in floating point comes to 99.9999999999986
+        assert_eq!(orders.len(), 1000);
+        assert_eq!(consolidate_orders(orders), vec![(0, order::Side::Buy, to_money(100.0))]);
+        assert_eq!(equities, money(&[100.0]));
+    }
+
+    #[test]
+    fn large_budgets_of_cheap_shares_are_planned_in_full() {
+        // more shares than any fixed cap on the search would have allowed
+        let (orders, equities) = generate_orders(
+            &money(&[0.0, 0.0]),
+            &money(&[1.0, 2.0]),
+            &money(&[1.0, 2.0]),
+            [0.5, 0.5].into_iter(),
+            &[0.0, 0.0],
+            &[1.0, 1.0],
+            to_money(30_000.0),
+            false,
+            false,
+            false,
+        )
+        .unwrap();
+        assert!(orders.len() > 20_000, "{}", orders.len());
+        assert_eq!(sum_money(&equities), to_money(30_000.0));
+    }
+
+    #[test]
+    fn zero_buy_size_is_never_bought() {
+        let (orders, _) = generate_orders(
+            &money(&[0.0, 0.0]),
+            &money(&[0.0, 10.0]),
+            &money(&[0.0, 10.0]),
+            [0.5, 0.5].into_iter(),
+            &[0.5, 0.0],
+            &[1.0, 1.0],
+            to_money(25.0),
+            false,
+            false,
+            false,
+        )
+        .unwrap();
+        assert_eq!(consolidate_orders(orders), vec![(1, order::Side::Buy, to_money(20.0))]);
+    }
+
+    #[test]
+    fn orders_are_merged_per_symbol_and_side_in_order_of_appearance() {
+        let (buy, sell) = (order::Side::Buy, order::Side::Sell);
+        let orders = vec![
+            (2, buy, to_money(10.0)),
+            (0, sell, to_money(5.0)),
+            (1, buy, to_money(7.5)),
+            (2, buy, to_money(0.01)),
+            (0, buy, to_money(3.0)),
+            (0, sell, to_money(2.5)),
+            (1, buy, to_money(7.5)),
+            (2, sell, to_money(4.0)),
+            (2, buy, to_money(10.0)),
+        ];
+        assert_eq!(
+            consolidate_orders(orders),
+            vec![
+                (2, buy, to_money(20.01)),
+                (0, sell, to_money(7.5)),
+                (1, buy, to_money(15.0)),
+                // the same symbol's orders on the other side stay apart
+                (0, buy, to_money(3.0)),
+                (2, sell, to_money(4.0)),
+            ]
+        );
+        assert!(consolidate_orders(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn unversioned_state_files_are_upgraded() {
+        let state = serde_json::json!({
+            "fund_accum": 12.5,
+            "last_funding_date": null,
+            "reference_equities": {"AAPL": 100.0},
+            "ideal_allocations": {"AAPL": 1.0},
+            "target_investment_equity_ratio": 0.5,
+            "finish_date": "2025-01-02T00:00:00Z",
+            "pending_order_ids": ["904837e3-3b76-47ec-b432-046db621571b"],
+            "target_fill_probability": 0.8,
+        });
+
+        let state = migrate_state(state).unwrap();
+        assert_eq!(state.version, STATE_VERSION);
+        assert_eq!(state.fund_accum, 12.5);
+        assert_eq!(state.pending_orders.len(), 1);
+        assert_eq!(state.pending_orders[0].reprices, 0);
+        assert!(state.client_order_ids.is_empty());
+        assert!(matches!(state.limit_price_strategy, pricing::LimitPriceStrategy::NarrowSpread { .. }));
+        assert!(state.fill_session.is_none());
+        assert!(state.last_drift_trigger.is_none());
+    }
+
+    #[test]
+    fn hand_added_thin_liquidity_dates_move_out_of_the_state() {
+        let mut state = serde_json::to_value(State::new(HashMap::new(), HashMap::new())).unwrap();
+        state["version"] = 20.into();
+        state["thin_liquidity"] = serde_json::json!({"dates": ["2024-11-27", "2024-07-03"], "extra_wait_hours": 2});
+
+        let state = serde_json::to_value(migrate_state(state).unwrap()).unwrap();
+        assert!(state.get("thin_liquidity").is_none());
+    }
+
+    #[test]
+    fn fill_sessions_are_reported_once_their_orders_settle() {
+        let mut state = State::new(HashMap::new(), HashMap::new());
+        let mut session = fill_session::FillSession::new("2024-01-02".to_string());
+        session.record_submission("AAPL", order::Side::Buy, 2.0, 100.0);
+        session.record_submission("MSFT", order::Side::Buy, 3.0, 50.0);
+        // resubmitted after a restart
+        session.record_submission("AAPL", order::Side::Buy, 2.0, 100.0);
+        assert_eq!(session.orders.len(), 2);
+        state.fill_session = Some(session);
+        state.pending_orders.push(PendingOrder {
+            id: "904837e3-3b76-47ec-b432-046db621571b".to_string(),
+            symbol: "MSFT".to_string(),
+            quantity: 3.0,
+            submitted_at: Utc::now(),
+            reprices: 0,
+        });
+
+        let session = state.fill_session.as_mut().unwrap();
+        session.record_fill("AAPL", order::Side::Buy, 2.0, 99.5);
+        session.record_fill("MSFT", order::Side::Buy, 1.0, 50.0);
+        // a harvest's fills aren't the session's
+        session.record_fill("VTI", order::Side::Sell, 5.0, 200.0);
+        assert!(state.completed_fill_session().is_none());
+
+        state.pending_orders.clear();
+        let session = state.completed_fill_session().unwrap();
+        assert_eq!(session.orders[0].avg_fill_price(), Some(99.5));
+        assert_eq!(
+            session.event(),
+            fill_session::FillsEvent::PartialFillsCompleted {
+                shares_bought: 3.0,
+                capital_deployed: 249.0,
+                unfilled: vec![("MSFT".to_string(), 2.0)],
+            }
+        );
+        assert!(state.completed_fill_session().is_none());
+
+        let mut session = fill_session::FillSession::new("2024-01-03".to_string());
+        session.record_submission("AAPL", order::Side::Buy, 2.0, 100.0);
+        session.record_fill("AAPL", order::Side::Buy, 1.0, 99.0);
+        session.record_fill("AAPL", order::Side::Buy, 1.0, 100.0);
+        assert_eq!(
+            session.event(),
+            fill_session::FillsEvent::FillsCompleted {
+                shares_bought: 2.0,
+                capital_deployed: 199.0,
+            }
+        );
+    }
+
+    #[test]
+    fn target_fill_probability_becomes_a_narrow_spread() {
+        let mut state = serde_json::to_value(State::new(HashMap::new(), HashMap::new())).unwrap();
+        state["version"] = 18.into();
+        state["target_fill_probability"] = 0.8.into();
+
+        let state = migrate_state(state).unwrap();
+        assert!(matches!(
+            state.limit_price_strategy,
+            pricing::LimitPriceStrategy::NarrowSpread { max_pct_from_bid } if max_pct_from_bid == 0.8
+        ));
+
+        let mut unset = serde_json::to_value(State::new(HashMap::new(), HashMap::new())).unwrap();
+        unset["version"] = 18.into();
+        let unset = migrate_state(unset).unwrap();
+        assert!(matches!(unset.limit_price_strategy, pricing::LimitPriceStrategy::FixedDiscount));
+    }
+
+    #[test]
+    fn newer_state_files_are_rejected() {
+        let mut state = serde_json::to_value(State::new(HashMap::new(), HashMap::new())).unwrap();
+        assert!(migrate_state(state.clone()).is_ok());
+
+        state["version"] = (STATE_VERSION + 1).into();
+        let e = migrate_state(state).err().unwrap();
+        assert!(e.to_string().contains("newer than the supported version"), "{}", e);
+    }
+
+    #[tokio::test]
+    async fn sqlite_state_stores_keep_the_funding_and_order_history() {
+        let path = std::env::temp_dir().join(format!("apca_balancer_state_{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        let store = SqliteStateStore::new(path);
+
+        let mut state = State::new(
+            HashMap::from([("AAPL".to_string(), 100.0)]),
+            HashMap::from([("AAPL".to_string(), 1.0)]),
+        );
+        state.fund_accum = 12.5;
+        state.last_funding_date = Some("2024-03-04T15:00:00Z".parse().unwrap());
+        state.pending_orders.push(PendingOrder {
+            id: "904837e3-3b76-47ec-b432-046db621571b".to_string(),
+            symbol: "AAPL".to_string(),
+            quantity: 3.0,
+            submitted_at: Utc::now(),
+            reprices: 0,
+        });
+        store.save(&state).await.unwrap();
+
+        // the order and the day's funding outlive the state that replaces them
+        state.fund_accum = 2.5;
+        state.pending_orders.clear();
+        store.save(&state).await.unwrap();
+
+        let loaded = store.load().await.unwrap();
+        assert_eq!(loaded.fund_accum, 2.5);
+        assert!(loaded.pending_orders.is_empty());
+
+        let conn = rusqlite::Connection::open(path).unwrap();
+        let orders: u32 = conn.query_row("SELECT COUNT(*) FROM orders", [], |r| r.get(0)).unwrap();
+        let (date, fund_accum): (String, f64) = conn
+            .query_row("SELECT date, fund_accum FROM funding_history", [], |r| Ok((r.get(0)?, r.get(1)?)))
+            .unwrap();
+        assert_eq!(orders, 1);
+        assert_eq!((date.as_str(), fund_accum), ("2024-03-04", 2.5));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn backups_are_pruned_and_can_be_restored() {
+        let dir = std::env::temp_dir().join(format!("apca_balancer_backups_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("state.json");
+        let path = path.to_str().unwrap();
+        // left behind by the numbered rotation of older versions
+        std::fs::write(format!("{}.1", path), "{}").unwrap();
+
+        let mut state = State::new(
+            HashMap::from([("AAPL".to_string(), 100.0)]),
+            HashMap::from([("AAPL".to_string(), 1.0)]),
+        );
+        state.state_backups = 2;
+        for fund_accum in [1.0, 2.0, 3.0, 4.0] {
+            state.fund_accum = fund_accum;
+            save_state(path, &state).await.unwrap();
+        }
+
+        let backups = list_backups(path).await.unwrap();
+        assert_eq!(backups.len(), 2, "{:?}", backups);
+        assert_eq!(load_state(&backups[0]).await.unwrap().fund_accum, 3.0);
+        assert_eq!(load_state(&backups[1]).await.unwrap().fund_accum, 2.0);
+
+        // a hand edit that breaks validation isn't papered over with an older state
+        state.ideal_allocations.insert("AAPL".to_string(), 0.5);
+        std::fs::write(path, serde_json::to_vec(&state).unwrap()).unwrap();
+        let e = load_state_with_fallback(path).await.err().unwrap();
+        assert!(matches!(e, Error::InvalidConfig(_)), "{}", e);
+
+        std::fs::write(path, "not json").unwrap();
+        assert_eq!(load_state_with_fallback(path).await.unwrap().fund_accum, 3.0);
+        restore_state(path, None).await.unwrap();
+        assert_eq!(load_state(path).await.unwrap().fund_accum, 3.0);
+        // the file the restore replaced is the newest backup
+        assert_eq!(std::fs::read_to_string(&list_backups(path).await.unwrap()[0]).unwrap(), "not json");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn passed_finish_dates_load_and_are_extended_before_funding() {
+        let mut state = State::new(
+            HashMap::from([("AAPL".to_string(), 100.0)]),
+            HashMap::from([("AAPL".to_string(), 1.0)]),
+        );
+        let now = Utc::now();
+        state.finish_date = now - Duration::days(10);
+        assert!(validate_state(&state).is_ok());
+
+        let e = extend_finish_date(&mut state, now, None).err().unwrap();
+        assert!(e.to_string().contains("has passed"), "{}", e);
+
+        extend_finish_date(&mut state, now, Some(30)).unwrap();
+        assert!(state.finish_date > now + Duration::days(30));
+        assert!(state.finish_date < now + Duration::days(366));
+    }
+
+    #[test]
+    fn urgency_spans_balanced_to_severely_off_target() {
+        assert_eq!(urgency_score(0.0, 0.0, 0.0, None), 0.0);
+
+        // 20% off target with a month and a half of uninvested cash
+        let score = urgency_score(0.2 * 0.2, 45.0, 0.0, None);
+        assert!(score > URGENT_SCORE, "{}", score);
+        assert_eq!(urgency_tag(score), "[URGENT] ");
+        assert_eq!(urgency_score(0.2 * 0.2, 45.0, 2.0, None), 100.0);
+
+        let config: config::Config =
+            toml::from_str("[rebalance_weights]\nmse = 0.0\ncash_drag = 1.0\nturnover = 0.0").unwrap();
+        assert_eq!(urgency_score(0.2 * 0.2, 0.0, 2.0, config.rebalance_weights), 0.0);
+    }
+
+    #[test]
+    fn ties_go_to_the_first_symbol() {
+        let best = best_asset_to_fund(
+            [100.0, 100.0].into_iter(),
+            [10.0, 10.0].into_iter(),
+            [10.0, 10.0].into_iter(),
+            [0.5, 0.5].into_iter(),
+            |_| true,
+            |_| true,
+            false,
+        );
+        assert!(matches!(best, Some((0, order::Side::Buy, _))));
+    }
+
+    #[test]
+    fn weighted_error_matches_the_incremental_sums() {
+        let best = |weighted| {
+            best_asset_to_fund(
+                [36.0, 1.0, 63.0].into_iter(),
+                [10.0, 10.0, 10.0].into_iter(),
+                [10.0, 10.0, 10.0].into_iter(),
+                [0.4, 0.02, 0.58].into_iter(),
+                |_| true,
+                |_| false,
+                weighted,
+            )
+            .unwrap()
+        };
+
+        let (idx, _, err) = best(true);
+        assert_eq!(idx, 0);
+        let mut equities = [36.0, 1.0, 63.0];
+        equities[idx] += 10.0;
+        let expected = weighted_error(
+            equities.iter().map(|e| e / 110.0),
+            [0.4, 0.02, 0.58].into_iter(),
+            [0.4, 0.02, 0.58].into_iter(),
+        );
+        assert!((err - expected).abs() < 1e-12);
+    }
+
+    #[test]
+    fn set_allocation_rescales_the_others() {
+        let mut allocations: HashMap<_, _> = [("A", 0.1), ("B", 0.6), ("C", 0.3)]
+            .into_iter()
+            .map(|(sym, a)| (sym.to_string(), a))
+            .collect();
+        set_allocation_weight(&mut allocations, "A", 0.15).unwrap();
+
+        assert!((allocations["A"] - 0.15).abs() < 1e-12);
+        assert!((allocations["B"] / allocations["C"] - 2.0).abs() < 1e-12);
+        assert!((allocations.values().sum::<f64>() - 1.0).abs() < 1e-12);
+        assert!(set_allocation_weight(&mut allocations, "A", 1.5).is_err());
+    }
+
+    #[test]
+    fn env_overrides_replace_config_values() {
+        let mut table: toml::Table = toml::from_str("limit_price_factor = 0.99\nsymbols = [\"VTI\"]").unwrap();
+        config::apply_env_overrides(
+            &mut table,
+            [
+                ("APCA_BALANCER_LIMIT_PRICE_FACTOR", "0.995"),
+                ("APCA_BALANCER_SYMBOLS", "[\"VTI\", \"BND\"]"),
+                ("APCA_BALANCER_FINISH_DATE", "2030-01-01"),
+                ("APCA_BALANCER_JOURNAL_PATH", "journal.csv"),
+                ("RUST_LOG", "debug"),
+            ]
+            .map(|(name, value)| (name.to_string(), value.to_string())),
+        );
+        let config: config::Config = table.try_into().unwrap();
+
+        assert_eq!(config.limit_price_factor, Some(0.995));
+        assert_eq!(config.symbols, vec!["VTI", "BND"]);
+        assert_eq!(config.journal_path.as_deref(), Some("journal.csv"));
+    }
+
+    #[test]
+    fn portfolios_post_to_the_top_level_slack_webhook() {
+        let path = std::env::temp_dir().join(format!("apca_balancer_slack_{}.toml", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(
+            path,
+            r##"
+            [slack]
+            webhook_url = "https://hooks.slack.com/services/T/B/X"
+            channel = "#portfolio"
+
+            [[portfolios]]
+            state_file = "growth.json"
+            capital_share = 1.0
+            symbols = ["AAPL"]
+            notify_on_all_fills = true
+            "##,
+        )
+        .unwrap();
+        let config = config::load_config(path).unwrap();
+        let slack = config.portfolios[0].config.slack.as_ref().unwrap();
+        assert_eq!(slack.channel, "#portfolio");
+
+        std::fs::write(path, "notify_on_all_fills = true").unwrap();
+        let e = config::load_config(path).err().unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert!(e.to_string().contains("needs a slack webhook"), "{}", e);
+    }
+
+    #[test]
+    fn simulated_buys_are_limited_by_the_virtual_cash() {
+        let mut sim = Simulator::default();
+        sim.seed_cash(1000.0);
+        sim.seed_cash(5000.0);
+
+        assert!(sim.submit("VTI", order::Side::Buy, 200.0, 4.0));
+        assert!(!sim.submit("VTI", order::Side::Buy, 200.0, 2.0));
+        assert!(sim.submit("BND", order::Side::Sell, 50.0, 2.0));
+        assert!(sim.submit("VTI", order::Side::Buy, 200.0, 1.0));
+        assert_eq!(sim.cash(), 100.0);
+    }
+
+    #[test]
+    fn marketable_limits_cross_the_spread_by_the_collar() {
+        let settings = OrderSettings {
+            order_type: pricing::OrderType::MarketableLimit,
+            marketable_limit_collar: 0.01,
+            ..Default::default()
+        };
+        let state = State::new(HashMap::new(), HashMap::new());
+        let quote = Some((100.0, 99.0));
+
+        let buy = settings.order_price(&state, order::Side::Buy, 99.5, quote);
+        let sell = settings.order_price(&state, order::Side::Sell, 99.5, quote);
+        assert!((buy - 101.0).abs() < 1e-9);
+        assert!((sell - 98.01).abs() < 1e-9);
+        assert!((settings.order_price(&state, order::Side::Buy, 50.0, None) - 50.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn near_ties_go_to_the_earlier_item() {
+        let items = [(0, 1e-3), (1, 1e-3 - 1e-18), (2, 5e-4)];
+        assert_eq!(min_by_key_f64(items[..2].iter(), |&&(_, k)| k), Some(&(0, 1e-3)));
+        assert_eq!(min_by_key_f64(items.iter(), |&&(_, k)| k), Some(&(2, 5e-4)));
+    }
+
+    #[test]
+    fn fractional_quantities_round_down_to_the_hundredth() {
+        let settings = OrderSettings::default();
+        for qty in [0.004, 0.009] {
+            let result = order_request("AAPL", order::Side::Buy, 100.0, qty, true, settings);
+            assert!(matches!(result, Err(Error::OrderRejected { .. })), "{}", qty);
+        }
+        let quantity = |qty: f64| match order_request("AAPL", order::Side::Buy, 100.0, qty, true, settings).unwrap().amount {
+            order::Amount::Quantity { quantity } => quantity,
+            amount => panic!("{:?}", amount),
+        };
+        assert_eq!(quantity(1.239), Num::from_str("1.23").unwrap());
+        assert_eq!(quantity(0.29), Num::from_str("0.29").unwrap());
+        assert_eq!(quantity(0.01), Num::from_str("0.01").unwrap());
+    }
+
+    #[tokio::test]
+    async fn zero_quantity_is_never_submitted() {
+        for fractional in [false, true] {
+            let settings = OrderSettings::default();
+            let result = order_request("AAPL", order::Side::Buy, 100.0, 0.0, fractional, settings);
+            assert!(matches!(result, Err(Error::OrderRejected { .. })));
+        }
+    }
+
+    #[tokio::test]
+    async fn notional_order_spends_the_exact_amount_at_market() {
+        let client = MockClient::default();
+        let result = notional_order_request("AAPL", order::Side::Buy, 0.5);
+        assert!(matches!(result, Err(Error::OrderRejected { .. })));
+
+        client.respond("order::Post", StatusCode::OK, order_json("AAPL", "buy", "1.23", "new", "0", None));
+        let request = notional_order_request("AAPL", order::Side::Buy, 123.456).unwrap();
+        client.submit_order(&request).await.unwrap();
+        let (_, body) = &client.calls()[0];
+        let body = body.as_ref().unwrap();
+        assert_eq!(body["type"], "market");
+        assert_eq!(body["notional"], "123.46");
+        assert!(body.get("qty").is_none());
+    }
+
+    #[tokio::test]
+    async fn orders_submitted_before_a_restart_are_found_by_client_id() {
+        let date = NaiveDate::from_ymd_opt(2024, 1, 2).unwrap();
+        let id = funding_order_id("state.json", date, "AAPL", order::Side::Buy);
+        assert!(id.starts_with("apca_balancer-") && id.ends_with("-2024-01-02-AAPL-buy"), "{}", id);
+        assert_eq!(id, funding_order_id("state.json", date, "AAPL", order::Side::Buy));
+        assert_ne!(id, funding_order_id("state.json", date, "AAPL", order::Side::Sell));
+
+        let client = MockClient::default();
+        client.respond("order::GetByClientId", StatusCode::NOT_FOUND, serde_json::json!({"message": "not found"}));
+        assert!(find_order_by_client_id(&client, &id).await.unwrap().is_none());
+
+        client.respond("order::GetByClientId", StatusCode::OK, order_json("AAPL", "buy", "2", "new", "0", None));
+        let order = find_order_by_client_id(&client, &id).await.unwrap().unwrap();
+        assert_eq!(order.symbol, "AAPL");
+    }
+
+    #[test]
+    fn portfolios_sharing_an_account_use_their_own_client_order_ids() {
+        let config: config::Config = toml::from_str(
+            r#"
+            [[portfolios]]
+            state_file = "growth.json"
+            capital_share = 0.5
+            symbols = ["AAPL"]
+
+            [[portfolios]]
+            state_file = "income.json"
+            capital_share = 0.5
+            symbols = ["VTI"]
+            "#,
+        )
+        .unwrap();
+        let date = NaiveDate::from_ymd_opt(2024, 1, 2).unwrap();
+        let ids: Vec<_> = config
+            .portfolios
+            .iter()
+            .map(|portfolio| funding_order_id(&portfolio.state_file, date, "AAPL", order::Side::Buy))
+            .collect();
+        assert_ne!(ids[0], ids[1]);
+        // Alpaca caps client order ids at 128 characters
+        assert!(ids.iter().all(|id| id.len() <= 128));
+    }
+
+    #[tokio::test]
+    async fn only_server_errors_are_transient() {
+        let client = MockClient::default();
+        client.respond("account::Get", StatusCode::SERVICE_UNAVAILABLE, serde_json::json!({"message": "unavailable"}));
+        client.respond("account::Get", StatusCode::UNAUTHORIZED, serde_json::json!({"message": "unauthorized"}));
+
+        let e = client.get_account().await.unwrap_err();
+        assert!(matches!(e, Error::Api(_)) && e.is_transient() && !e.skips_day());
+        let e = client.get_account().await.unwrap_err();
+        assert!(matches!(e, Error::ApiRejected(_)) && !e.is_transient() && !e.skips_day());
+
+        // callers can still branch on the endpoint's own error
+        let Error::ApiRejected(rejected) = &e else { unreachable!() };
+        let error::ApiError::Request(RequestError::Endpoint(endpoint)) = rejected.as_ref() else {
+            panic!("{:?}", rejected);
+        };
+        assert!(matches!(
+            endpoint.downcast_ref::<apca::api::v2::account::GetError>(),
+            Some(apca::api::v2::account::GetError::AuthenticationFailed(_))
+        ));
+    }
+
+    #[test]
+    fn reconciliation_errors_name_the_short_positions() {
+        let e = Error::Reconciliation(vec![
+            reconcile::ReconciliationWarning { symbol: "AAPL".to_string(), magnitude: 0.25 },
+            reconcile::ReconciliationWarning { symbol: "MSFT".to_string(), magnitude: 0.1 },
+        ]);
+        assert!(!e.is_transient());
+        // the next day's cycle checks the positions again
+        assert!(e.skips_day());
+        assert_eq!(
+            e.to_string(),
+            "positions fall short of their reference equities: AAPL (25.0%), MSFT (10.0%)"
+        );
+    }
+
+    #[tokio::test]
+    async fn mock_broker_fills_orders_from_its_cash() {
+        let broker = MockBroker::new(1000.0, &[("AAPL", 1.0, 100.0)]);
+        let settings = OrderSettings::default();
+
+        let request = order_request("AAPL", order::Side::Buy, 100.0, 2.0, false, settings).unwrap();
+        broker.submit_order(&request).await.unwrap();
+        let request = order_request("AAPL", order::Side::Buy, 100.0, 9.0, false, settings).unwrap();
+        let result = broker.submit_order(&request).await;
+        assert!(matches!(result, Err(Error::OrderRejected { .. })));
+
+        let account = broker.get_account().await.unwrap();
+        assert_eq!(account.cash.to_f64(), Some(800.0));
+        assert_eq!(account.equity.to_f64(), Some(1100.0));
+        let pos = broker.get_positions().await.unwrap();
+        assert_eq!((pos[0].symbol.as_str(), pos[0].quantity.to_f64()), ("AAPL", Some(3.0)));
+        assert_eq!(broker.orders.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_stop_file_created_mid_cycle_holds_back_the_remaining_orders() {
+        let broker = MockBroker::new(1000.0, &[("AAPL", 1.0, 100.0), ("MSFT", 1.0, 100.0)]);
+        let path = std::env::temp_dir().join(format!("apca_balancer_stop_mid_cycle_{}", std::process::id()));
+        let stop_file = path.to_str().unwrap();
+        let shutdown = Shutdown::with_stop_file(stop_file);
+        let settings = OrderSettings::default();
+
+        let mut submitted = Vec::new();
+        for (i, sym) in ["AAPL", "MSFT", "AAPL"].into_iter().enumerate() {
+            // the operator stops trading after the first order went out
+            if i == 1 {
+                std::fs::write(&path, "").unwrap();
+            }
+            let request = order_request(sym, order::Side::Buy, 100.0, 1.0, false, settings).unwrap();
+            submitted.push(submit_unless_stopped(&broker, &request, &shutdown).await.unwrap().is_some());
+        }
+        assert_eq!(submitted, [true, false, false]);
+        let orders = broker.orders.lock().unwrap().clone();
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].0, "AAPL");
+        assert_eq!(*broker.cash.lock().unwrap(), 900.0);
+
+        clear_stop(stop_file).await.unwrap();
+        let request = order_request("MSFT", order::Side::Buy, 100.0, 1.0, false, settings).unwrap();
+        assert!(submit_unless_stopped(&broker, &request, &shutdown).await.unwrap().is_some());
+        assert_eq!(broker.orders.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn mock_broker_calendar_covers_the_requested_days() {
+        let broker = MockBroker {
+            calendar: serde_json::from_str(
+                r#"[{"date": "2024-01-02", "open": "09:30", "close": "16:00"},
+                    {"date": "2024-01-03", "open": "09:30", "close": "16:00"}]"#,
+            )
+            .unwrap(),
+            ..Default::default()
+        };
+        let request = calendar::CalendarReq {
+            start: NaiveDate::from_ymd_opt(2024, 1, 3).unwrap(),
+            end: NaiveDate::from_ymd_opt(2024, 1, 10).unwrap(),
+        };
+
+        let days = broker.get_calendar(&request).await.unwrap();
+        assert_eq!(days.len(), 1);
+        assert_eq!(days[0].date, request.start);
+    }
+
+    // A broker with AAPL and MSFT at equal value and a session spanning today.
+    fn drifting_broker() -> MockBroker {
+        let today = Utc::now().with_timezone(&Eastern).date_naive();
+        MockBroker {
+            calendar: serde_json::from_value(serde_json::json!([
+                {"date": today.to_string(), "open": "00:00", "close": "23:59"}
+            ]))
+            .unwrap(),
+            ..MockBroker::new(0.0, &[("AAPL", 1.0, 100.0), ("MSFT", 1.0, 100.0)])
+        }
+    }
+
+    fn even_state() -> State {
+        State::new(
+            HashMap::new(),
+            HashMap::from([("AAPL".to_string(), 0.5), ("MSFT".to_string(), 0.5)]),
+        )
+    }
+
+    #[tokio::test]
+    async fn drift_below_the_threshold_waits_for_the_funding_time() {
+        let broker = drifting_broker();
+        let state = even_state();
+        let config: config::Config = toml::from_str("drift_trigger_threshold = 0.1").unwrap();
+        let interval = Duration::milliseconds(5);
+
+        let dt = Utc::now() + Duration::milliseconds(50);
+        let trigger = wait_for_funding_time(&broker, &state, Some(&config), dt, interval).await;
+        assert_eq!(trigger, TriggerType::Scheduled);
+        assert!(Utc::now() >= dt);
+
+    }
+
+    #[tokio::test]
+    async fn drift_already_beyond_the_threshold_funds_right_away() {
+        // AAPL moved overnight, before the process was restarted
+        let broker = drifting_broker();
+        broker.holdings.lock().unwrap().get_mut("AAPL").unwrap().price = 300.0;
+        let mut state = even_state();
+        let config: config::Config = toml::from_str("drift_trigger_threshold = 0.1").unwrap();
+        let interval = Duration::milliseconds(5);
+
+        let dt = Utc::now() + Duration::seconds(10);
+        let trigger = wait_for_funding_time(&broker, &state, Some(&config), dt, interval).await;
+        assert!(matches!(trigger, TriggerType::DriftTriggered { .. }), "{:?}", trigger);
+        assert!(Utc::now() < dt);
+
+        // the drift the triggered cycle left behind waits for the scheduled cycle
+        state.last_drift_trigger = Some(Utc::now());
+        let dt = Utc::now() + Duration::milliseconds(50);
+        let trigger = wait_for_funding_time(&broker, &state, Some(&config), dt, interval).await;
+        assert_eq!(trigger, TriggerType::Scheduled);
+        assert!(Utc::now() >= dt);
+    }
+
+    #[tokio::test]
+    async fn crossing_the_drift_threshold_funds_early() {
+        let broker = drifting_broker();
+        let state = even_state();
+        let config: config::Config = toml::from_str("drift_trigger_threshold = 0.1").unwrap();
+
+        let dt = Utc::now() + Duration::seconds(10);
+        let wait = wait_for_funding_time(&broker, &state, Some(&config), dt, Duration::milliseconds(5));
+        let market_move = async {
+            tokio::time::sleep(time::Duration::from_millis(20)).await;
+            broker.holdings.lock().unwrap().get_mut("AAPL").unwrap().price = 300.0;
+        };
+        let (trigger, ()) = tokio::join!(wait, market_move);
+
+        // AAPL is now 75% of the portfolio against its ideal 50%
+        let TriggerType::DriftTriggered { max_drift } = trigger else {
+            panic!("{:?}", trigger);
+        };
+        assert!((max_drift - 0.25).abs() < 1e-9, "{}", max_drift);
+        assert!(Utc::now() < dt);
+
+        // nothing is checked outside the session
+        let closed = MockBroker::new(0.0, &[("AAPL", 1.0, 300.0), ("MSFT", 1.0, 100.0)]);
+        assert_eq!(session_drift(&closed, &state, Some(&config), Utc::now()).await.unwrap(), None);
+    }
+
+    fn fill_settings(reprice_attempts: u32) -> FillSettings {
+        FillSettings {
+            poll_interval: time::Duration::ZERO,
+            timeout: time::Duration::from_secs(60),
+            reprice_attempts,
+            reprice_step: 0.01,
+        }
+    }
+
+    #[tokio::test]
+    async fn submitted_order_is_tracked_until_filled() {
+        let client = MockClient::default();
+        client.respond("order::Post", StatusCode::OK, order_json("AAPL", "buy", "2", "new", "0", None));
+        client.respond(
+            "order::Get",
+            StatusCode::OK,
+            order_json("AAPL", "buy", "2", "filled", "2", Some("99.50")),
+        );
+
+        let request = order_request("AAPL", order::Side::Buy, 100.0, 2.0, false, OrderSettings::default()).unwrap();
+        let order = client.submit_order(&request).await.unwrap();
+        let (endpoint, body) = &client.calls()[0];
+        assert_eq!(endpoint, "order::Post");
+        let body = body.as_ref().unwrap();
+        assert_eq!(body["type"], "limit");
+        assert_eq!(body["limit_price"], "100");
+        assert_eq!(body["qty"], "2");
+
+        let mut pending = vec![PendingOrder::new(&order, 2.0)];
+        let mut fills = Vec::new();
+        let requeued = monitor_and_fill(
+            &client,
+            &mut pending,
+            fill_settings(0),
+            None,
+            None,
+            &Shutdown::default(),
+            |sym, side, qty, price| fills.push((sym.to_string(), side, qty, price)),
+        )
+        .await
+        .unwrap();
+        assert!(requeued.is_empty());
+        assert!(pending.is_empty());
+        assert_eq!(fills, vec![("AAPL".to_string(), order::Side::Buy, 2.0, 99.5)]);
+    }
+
+    #[tokio::test]
+    async fn partial_fill_is_accounted_and_the_rest_requeued() {
+        let client = MockClient::default();
+        let mut expired = order_json("AAPL", "buy", "3", "expired", "1", Some("50.25"));
+        expired["type"] = "market".into();
+        client.respond("order::Get", StatusCode::OK, expired);
+
+        let submitted = order_json("AAPL", "buy", "3", "new", "0", None).to_string();
+        let order: order::Order = serde_json::from_str(&submitted).unwrap();
+        let mut pending = vec![PendingOrder::new(&order, 3.0)];
+        let mut fills = Vec::new();
+        let requeued = monitor_and_fill(
+            &client,
+            &mut pending,
+            fill_settings(0),
+            None,
+            None,
+            &Shutdown::default(),
+            |sym, side, qty, price| fills.push((sym.to_string(), side, qty, price)),
+        )
+        .await
+        .unwrap();
+        assert!(pending.is_empty());
+        assert_eq!(fills, vec![("AAPL".to_string(), order::Side::Buy, 1.0, 50.25)]);
+        assert_eq!(requeued.len(), 1);
+        assert_eq!((requeued[0].side, requeued[0].quantity), (order::Side::Buy, 2.0));
+    }
+
+    #[tokio::test]
+    async fn trade_updates_confirm_fills_between_polls() {
+        let client = MockClient::default();
+        client.respond("order::Get", StatusCode::OK, order_json("AAPL", "buy", "2", "new", "0", None));
+
+        let submitted = order_json("AAPL", "buy", "2", "new", "0", None).to_string();
+        let order: order::Order = serde_json::from_str(&submitted).unwrap();
+        let update = serde_json::json!({
+            "event": "fill",
+            "order": order_json("AAPL", "buy", "2", "filled", "2", Some("99.75")),
+        });
+        let update = serde_json::from_str(&update.to_string()).map_err(|e| e.to_string());
+        let mut updates = TradeUpdates::from_stream(futures::stream::iter([update]));
+
+        let mut pending = vec![PendingOrder::new(&order, 2.0)];
+        let mut fills = Vec::new();
+        monitor_and_fill(
+            &client,
+            &mut pending,
+            FillSettings {
+                poll_interval: time::Duration::from_secs(60),
+                ..fill_settings(0)
+            },
+            Some(&mut updates),
+            None,
+            &Shutdown::default(),
+            |sym, side, qty, price| fills.push((sym.to_string(), side, qty, price)),
+        )
+        .await
+        .unwrap();
+
+        // the order was only polled once, before the stream reported the fill
+        assert_eq!(client.calls().len(), 1);
+        assert!(pending.is_empty());
+        assert_eq!(fills, vec![("AAPL".to_string(), order::Side::Buy, 2.0, 99.75)]);
+    }
+
+    #[tokio::test]
+    async fn canceled_limit_order_is_repriced_before_going_to_market() {
+        let client = MockClient::default();
+        client.respond(
+            "order::Get",
+            StatusCode::OK,
+            order_json("AAPL", "buy", "3", "canceled", "1", Some("100.00")),
+        );
+        client.respond("order::Post", StatusCode::OK, order_json("AAPL", "buy", "2", "new", "0", None));
+        client.respond(
+            "order::Get",
+            StatusCode::OK,
+            order_json("AAPL", "buy", "2", "filled", "2", Some("101.00")),
+        );
+
+        let submitted = order_json("AAPL", "buy", "3", "new", "0", None).to_string();
+        let order: order::Order = serde_json::from_str(&submitted).unwrap();
+        let mut pending = vec![PendingOrder::new(&order, 3.0)];
+        let mut fills = Vec::new();
+        monitor_and_fill(
+            &client,
+            &mut pending,
+            fill_settings(1),
+            None,
+            None,
+            &Shutdown::default(),
+            |sym, side, qty, price| fills.push((sym.to_string(), side, qty, price)),
+        )
+        .await
+        .unwrap();
+
+        let posted: Vec<_> = client.calls().into_iter().filter(|(endpoint, _)| endpoint == "order::Post").collect();
+        assert_eq!(posted.len(), 1);
+        let body = posted[0].1.as_ref().unwrap();
+        assert_eq!(body["type"], "limit");
+        assert_eq!(body["limit_price"], "101");
+        assert_eq!(body["qty"], "2");
+        assert!(pending.is_empty());
+        assert_eq!(
+            fills,
+            vec![
+                ("AAPL".to_string(), order::Side::Buy, 1.0, 100.0),
+                ("AAPL".to_string(), order::Side::Buy, 2.0, 101.0)
+            ]
+        );
+    }
+    #[tokio::test]
+    async fn the_journal_records_resubmissions_with_their_parameters() {
+        let path = std::env::temp_dir().join(format!("apca_balancer_journal_{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        let client = MockClient::default();
+        client.respond(
+            "order::Get",
+            StatusCode::OK,
+            order_json("AAPL", "buy", "3", "canceled", "1", Some("100.00")),
+        );
+        client.respond("order::Post", StatusCode::OK, order_json("AAPL", "buy", "2", "new", "0", None));
+        client.respond(
+            "order::Get",
+            StatusCode::OK,
+            order_json("AAPL", "buy", "2", "filled", "2", Some("101.00")),
+        );
+
+        let submitted = order_json("AAPL", "buy", "3", "new", "0", None).to_string();
+        let order: order::Order = serde_json::from_str(&submitted).unwrap();
+        let mut pending = vec![PendingOrder::new(&order, 3.0)];
+        monitor_and_fill(
+            &client,
+            &mut pending,
+            fill_settings(1),
+            None,
+            Some(path),
+            &Shutdown::default(),
+            |_, _, _, _| {},
+        )
+        .await
+        .unwrap();
+
+        let entries: Vec<journal::JournalEntry> = std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        std::fs::remove_file(path).unwrap();
+        let events: Vec<_> = entries.iter().map(|e| (e.event, e.quantity, e.price)).collect();
+        assert_eq!(
+            events,
+            vec![
+                (journal::JournalEvent::Filled, 1.0, 100.0),
+                (journal::JournalEvent::Submitted, 2.0, 101.0),
+                (journal::JournalEvent::Filled, 2.0, 101.0),
+            ]
+        );
+        let resubmission = &entries[1];
+        assert_eq!(resubmission.order_type, Some(order::Type::Limit));
+        assert_eq!(resubmission.time_in_force, Some(order::TimeInForce::Day));
+        assert_eq!(resubmission.client_order_id.as_deref(), Some("904837e3-3b76-47ec-b432-046db621571b"));
+        assert!(resubmission.drift_error.is_none());
+    }
+
+    #[test]
+    fn planning_error_is_the_error_of_the_best_trade() {
+        let (equities, prices, ideal) = ([300.0, 100.0, 50.0], [10.0, 20.0, 5.0], [0.5, 0.3, 0.2]);
+        for weighted in [false, true] {
+            let (idx, side, err) = best_asset_to_fund(
+                equities.iter().cloned(),
+                prices.iter().cloned(),
+                prices.iter().cloned(),
+                ideal.iter().cloned(),
+                |_| true,
+                |_| false,
+                weighted,
+            )
+            .unwrap();
+            assert_eq!(side, order::Side::Buy);
+
+            let mut traded = equities;
+            traded[idx] += prices[idx];
+            assert!((planning_error(&traded, &ideal, weighted) - err).abs() < 1e-12);
+            assert!(err < planning_error(&equities, &ideal, weighted));
+        }
+    }
+}
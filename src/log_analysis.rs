@@ -0,0 +1,212 @@
+use apca::api::v2::order;
+use chrono::{Datelike, Weekday};
+use chrono_tz::US::Eastern;
+use std::collections::HashMap;
+
+use crate::error::{Error, Result};
+use crate::rebalancing_report::RebalancingReport;
+use crate::stats::{self, mean};
+
+// Fewer cycles than this say more about the individual days than about any pattern.
+pub const MIN_LOG_ENTRIES: usize = 20;
+
+// The cycles with a market volatility are split into this many buckets of equal size.
+const VOL_BUCKETS: usize = 4;
+
+// When funding cycles did the allocation the most good. A cycle's improvement
+// is how much its orders lowered the allocation's mean squared error.
+#[derive(Debug)]
+pub struct LogAnalysis {
+    pub entries: usize,
+    pub avg_mse_improvement_by_day_of_week: HashMap<Weekday, f64>,
+    // Each bucket's mean market volatility and mean improvement, calmest first.
+    pub avg_mse_improvement_by_market_vol: Vec<(f64, f64)>,
+    // Spearman correlation of the improvement with the market volatility.
+    pub vol_correlation: Option<f64>,
+    // Symbols by the number of cycles that bought them, most often first.
+    pub most_frequently_bought: Vec<String>,
+}
+
+fn mse_improvement(report: &RebalancingReport) -> f64 {
+    report.allocation_rmse_before.powi(2) - report.allocation_rmse_after.powi(2)
+}
+
+pub fn analyze_rebalance_log(log: &[RebalancingReport]) -> Result<LogAnalysis> {
+    // dry runs never placed their orders
+    let log: Vec<_> = log.iter().filter(|r| !r.dry_run).collect();
+    if log.len() < MIN_LOG_ENTRIES {
+        return Err(Error::UnexpectedData(format!(
+            "{} funding cycles were reported, analyzing them needs at least {}",
+            log.len(),
+            MIN_LOG_ENTRIES
+        )));
+    }
+
+    let mut by_day: HashMap<Weekday, Vec<f64>> = HashMap::new();
+    for report in &log {
+        let day = report.timestamp.with_timezone(&Eastern).weekday();
+        by_day.entry(day).or_default().push(mse_improvement(report));
+    }
+    let avg_mse_improvement_by_day_of_week = by_day
+        .into_iter()
+        .map(|(day, improvements)| (day, mean(improvements.into_iter()).unwrap()))
+        .collect();
+
+    let mut by_vol: Vec<_> = log
+        .iter()
+        .filter_map(|r| r.market_vol.map(|vol| (vol, mse_improvement(r))))
+        .collect();
+    by_vol.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let (vols, improvements): (Vec<_>, Vec<_>) = by_vol.iter().cloned().unzip();
+    let vol_correlation = stats::spearman(&vols, &improvements);
+    let bucket_size = by_vol.len().div_ceil(VOL_BUCKETS).max(1);
+    let avg_mse_improvement_by_market_vol = by_vol
+        .chunks(bucket_size)
+        .map(|bucket| {
+            let vol = mean(bucket.iter().map(|&(vol, _)| vol)).unwrap();
+            (vol, mean(bucket.iter().map(|&(_, improvement)| improvement)).unwrap())
+        })
+        .collect();
+
+    let mut buys: HashMap<&str, usize> = HashMap::new();
+    for report in &log {
+        let mut bought: Vec<_> = report
+            .orders
+            .iter()
+            .filter(|o| o.side == order::Side::Buy)
+            .map(|o| o.symbol.as_str())
+            .collect();
+        bought.sort_unstable();
+        bought.dedup();
+        for sym in bought {
+            *buys.entry(sym).or_default() += 1;
+        }
+    }
+    let mut most_frequently_bought: Vec<_> = buys.into_iter().collect();
+    most_frequently_bought.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+
+    Ok(LogAnalysis {
+        entries: log.len(),
+        avg_mse_improvement_by_day_of_week,
+        avg_mse_improvement_by_market_vol,
+        vol_correlation,
+        most_frequently_bought: most_frequently_bought.into_iter().map(|(sym, _)| sym.to_string()).collect(),
+    })
+}
+
+pub fn print_analysis(analysis: &LogAnalysis) {
+    println!("{} funding cycles analyzed", analysis.entries);
+    println!();
+
+    println!("Average MSE improvement by day of the week");
+    let days = [
+        Weekday::Mon,
+        Weekday::Tue,
+        Weekday::Wed,
+        Weekday::Thu,
+        Weekday::Fri,
+        Weekday::Sat,
+        Weekday::Sun,
+    ];
+    for day in days {
+        if let Some(improvement) = analysis.avg_mse_improvement_by_day_of_week.get(&day) {
+            println!("  {:<5}{:>12.6}", day, improvement);
+        }
+    }
+    println!();
+
+    if analysis.avg_mse_improvement_by_market_vol.is_empty() {
+        println!("No market volatility was recorded");
+    } else {
+        println!("Average MSE improvement by market volatility");
+        for (vol, improvement) in &analysis.avg_mse_improvement_by_market_vol {
+            println!("  {:>6.1}%{:>12.6}", vol * 100.0, improvement);
+        }
+        match analysis.vol_correlation {
+            Some(rho) => println!("  Spearman correlation = {:.3}", rho),
+            None => println!("  Spearman correlation unavailable"),
+        }
+    }
+    println!();
+
+    println!("Most frequently bought: {}", analysis.most_frequently_bought.join(", "));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rebalancing_report::{OrderSummary, TriggerType};
+    use chrono::{Duration, TimeZone, Utc};
+
+    fn buy(sym: &str) -> OrderSummary {
+        OrderSummary {
+            symbol: sym.to_string(),
+            side: order::Side::Buy,
+            qty: 1.0,
+            price: 100.0,
+            estimated_cost: 100.0,
+        }
+    }
+
+    // Four weeks of daily cycles from Monday 2024-01-01, improving the
+    // allocation more later in the week and when the market is more volatile.
+    fn synthetic_log() -> Vec<RebalancingReport> {
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 15, 30, 0).unwrap();
+        (0..28)
+            .map(|i| {
+                let weekday = i % 7;
+                let vol = 0.1 + 0.01 * i as f64;
+                let mse_after = 0.01 - 0.001 * weekday as f64 - 0.0001 * i as f64;
+                let mut orders = vec![buy("VTI")];
+                if i % 2 == 0 {
+                    orders.extend([buy("BND"), buy("BND")]);
+                }
+                RebalancingReport {
+                    timestamp: start + Duration::days(i),
+                    dry_run: false,
+                    trigger_type: TriggerType::Scheduled,
+                    total_equity: 10000.0,
+                    cash_deployed: 100.0,
+                    orders,
+                    pre_allocation: HashMap::new(),
+                    post_allocation: HashMap::new(),
+                    allocation_rmse_before: 0.1,
+                    allocation_rmse_after: mse_after.sqrt(),
+                    market_vol: Some(vol),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn improvements_are_grouped_by_weekday_and_volatility() {
+        let analysis = analyze_rebalance_log(&synthetic_log()).unwrap();
+        assert_eq!(analysis.entries, 28);
+
+        let by_day = &analysis.avg_mse_improvement_by_day_of_week;
+        assert_eq!(by_day.len(), 7);
+        // Mondays are days 0, 7, 14 and 21
+        assert!((by_day[&Weekday::Mon] - 0.0001 * 10.5).abs() < 1e-12, "{:?}", by_day);
+        assert!(by_day[&Weekday::Sun] > by_day[&Weekday::Mon]);
+
+        let by_vol = &analysis.avg_mse_improvement_by_market_vol;
+        assert_eq!(by_vol.len(), VOL_BUCKETS);
+        assert!((by_vol[0].0 - 0.13).abs() < 1e-12, "{:?}", by_vol);
+        assert!(by_vol.windows(2).all(|w| w[0].0 < w[1].0));
+        assert!(analysis.vol_correlation.unwrap() > 0.0);
+
+        // BND is counted once per cycle however many orders it had
+        assert_eq!(analysis.most_frequently_bought, ["VTI", "BND"]);
+    }
+
+    #[test]
+    fn short_logs_are_rejected() {
+        let mut log = synthetic_log();
+        log.truncate(MIN_LOG_ENTRIES);
+        assert!(analyze_rebalance_log(&log).is_ok());
+
+        // dry runs don't count towards the minimum
+        log[0].dry_run = true;
+        assert!(matches!(analyze_rebalance_log(&log), Err(Error::UnexpectedData(_))));
+    }
+}
@@ -0,0 +1,336 @@
+use apca::api::v2::account_activities::{
+    self, Activity, ActivityReq, ActivityType, Direction, TradeActivity,
+};
+use apca::api::v2::{order, position};
+use crate::api::TimedClient;
+use crate::error::{Error, Result};
+use crate::journal::{self, JournalEntry};
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+use std::fs;
+
+// Alpaca caps each page of account activities at this many entries.
+const ACTIVITY_PAGE_SIZE: usize = 100;
+
+pub async fn fetch_contributions(
+    client: &TimedClient,
+    after: DateTime<Utc>,
+) -> Result<Vec<(DateTime<Utc>, f64)>> {
+    let mut contributions = Vec::new();
+    let mut page_token = None;
+
+    loop {
+        let request = ActivityReq {
+            types: vec![ActivityType::CashDeposit, ActivityType::CashWithdrawal],
+            direction: Direction::Ascending,
+            after: Some(after),
+            page_size: Some(ACTIVITY_PAGE_SIZE),
+            page_token: page_token.take(),
+            ..Default::default()
+        };
+        let activities = client.issue::<account_activities::Get>(&request).await?;
+
+        let count = activities.len();
+        page_token = activities.last().map(|a| a.id().to_string());
+
+        contributions.extend(activities.into_iter().filter_map(|a| match a {
+            Activity::NonTrade(a) => Some((a.date, a.net_amount.to_f64().unwrap())),
+            Activity::Trade(_) => None,
+        }));
+
+        if count < ACTIVITY_PAGE_SIZE {
+            return Ok(contributions);
+        }
+    }
+}
+
+pub async fn fetch_fills(client: &TimedClient, after: DateTime<Utc>) -> Result<Vec<TradeActivity>> {
+    let mut fills = Vec::new();
+    let mut page_token = None;
+
+    loop {
+        let request = ActivityReq {
+            types: vec![ActivityType::Fill],
+            direction: Direction::Ascending,
+            after: Some(after),
+            page_size: Some(ACTIVITY_PAGE_SIZE),
+            page_token: page_token.take(),
+            ..Default::default()
+        };
+        let activities = client.issue::<account_activities::Get>(&request).await?;
+
+        let count = activities.len();
+        page_token = activities.last().map(|a| a.id().to_string());
+
+        fills.extend(activities.into_iter().filter_map(|a| a.into_trade().ok()));
+
+        if count < ACTIVITY_PAGE_SIZE {
+            return Ok(fills);
+        }
+    }
+}
+
+// Fraction of the account's equity traded over the last 30 days.
+pub async fn fetch_monthly_turnover(client: &TimedClient, equity: f64) -> Result<f64> {
+    let traded: f64 = fetch_fills(client, Utc::now() - Duration::days(30))
+        .await?
+        .iter()
+        .map(|t| t.quantity.to_f64().unwrap() * t.price.to_f64().unwrap())
+        .sum();
+
+    Ok(if equity > 0.0 { traded / equity } else { 0.0 })
+}
+
+type Sample = (DateTime<Utc>, f64);
+
+// Returns the net contributions over (start, end] and how much of the period
+// they were invested for, weighting each by the fraction of the period left.
+fn period_flows(start: DateTime<Utc>, end: DateTime<Utc>, contributions: &[Sample]) -> (f64, f64) {
+    let period = (end - start).num_seconds() as f64;
+
+    contributions
+        .iter()
+        .filter(|&&(t, _)| t > start && t <= end)
+        .fold((0.0, 0.0), |(net, weighted), &(t, f)| {
+            let weight = if period > 0.0 {
+                (end - t).num_seconds() as f64 / period
+            } else {
+                0.0
+            };
+            (net + f, weighted + weight * f)
+        })
+}
+
+// The Modified Dietz return between two equity samples.
+fn modified_dietz((t0, e0): Sample, (t1, e1): Sample, contributions: &[Sample]) -> f64 {
+    let (net_flow, weighted_flow) = period_flows(t0, t1, contributions);
+
+    let capital = e0 + weighted_flow;
+    if capital > 0.0 {
+        (e1 - e0 - net_flow) / capital
+    } else {
+        0.0
+    }
+}
+
+// Chains Modified Dietz holding period returns so deposits and withdrawals
+// don't show up as growth.
+pub fn total_return_index(equity_history: &[Sample], contributions: &[Sample]) -> Vec<Sample> {
+    let Some(&(start, _)) = equity_history.first() else {
+        return Vec::new();
+    };
+
+    let index = equity_history.windows(2).scan(100.0, |nav, w| {
+        *nav *= 1.0 + modified_dietz(w[0], w[1], contributions);
+        Some((w[1].0, *nav))
+    });
+
+    std::iter::once((start, 100.0)).chain(index).collect()
+}
+
+pub struct ContributionAdjustedReturn {
+    pub money_weighted_return: f64,
+    pub time_weighted_return: f64,
+    pub total_contributions: f64,
+    pub total_investment_gain: f64,
+}
+
+// The money-weighted return is a single Modified Dietz period over the whole
+// history, while the time-weighted return chains one period per recorded
+// equity sample so the timing of contributions doesn't affect it.
+pub fn contribution_adjusted_return(
+    equity_history: &[Sample],
+    contributions: &[Sample],
+) -> Option<ContributionAdjustedReturn> {
+    let (&first, &last) = (equity_history.first()?, equity_history.last()?);
+    let (total_contributions, _) = period_flows(first.0, last.0, contributions);
+
+    let time_weighted_return = total_return_index(equity_history, contributions)
+        .last()
+        .map(|&(_, nav)| nav / 100.0 - 1.0)?;
+
+    Some(ContributionAdjustedReturn {
+        money_weighted_return: modified_dietz(first, last, contributions),
+        time_weighted_return,
+        total_contributions,
+        total_investment_gain: last.1 - first.1 - total_contributions,
+    })
+}
+
+pub fn write_nav_series(filename: &str, series: &[Sample]) -> Result<()> {
+    let rows: String = series
+        .iter()
+        .map(|(t, nav)| format!("{},{:.4}\n", t.to_rfc3339(), nav))
+        .collect();
+    fs::write(filename, format!("timestamp,nav\n{}", rows))?;
+    Ok(())
+}
+
+fn signed_quantity(fill: &JournalEntry) -> f64 {
+    match fill.side {
+        order::Side::Buy => fill.quantity,
+        order::Side::Sell => -fill.quantity,
+    }
+}
+
+fn holdings_value(holdings: &HashMap<String, f64>, prices: &HashMap<String, f64>) -> f64 {
+    holdings.iter().map(|(sym, qty)| qty * prices[sym]).sum()
+}
+
+// Current prices for the journaled symbols still held.
+fn current_prices(positions: &[position::Position]) -> impl Iterator<Item = (String, f64)> + '_ {
+    positions
+        .iter()
+        .map(|pos| (pos.symbol.clone(), pos.current_price.as_ref().unwrap().to_f64().unwrap()))
+}
+
+// The journal only records the balancer's own trades, so each fill is a cash
+// flow that starts a new sub-period. Between fills the holdings are valued at
+// the last fill price of each symbol, and at the end at the current prices.
+pub fn compute_twr(journal_path: &str, positions: &[position::Position]) -> Result<f64> {
+    let fills = journal::read_fills(journal_path)?;
+    if fills.is_empty() {
+        return Err(Error::UnexpectedData(format!("{} has no filled orders", journal_path)));
+    }
+
+    let mut holdings: HashMap<String, f64> = HashMap::new();
+    let mut prices: HashMap<String, f64> = HashMap::new();
+    let mut growth = 1.0;
+    let mut start_value = 0.0;
+
+    for fill in &fills {
+        prices.insert(fill.symbol.clone(), fill.price);
+        if start_value > 0.0 {
+            growth *= holdings_value(&holdings, &prices) / start_value;
+        }
+        *holdings.entry(fill.symbol.clone()).or_default() += signed_quantity(fill);
+        start_value = holdings_value(&holdings, &prices);
+    }
+
+    prices.extend(current_prices(positions).filter(|(sym, _)| holdings.contains_key(sym)));
+    if start_value > 0.0 {
+        growth *= holdings_value(&holdings, &prices) / start_value;
+    }
+
+    Ok(growth - 1.0)
+}
+
+const IRR_MAX_ITERATIONS: usize = 100;
+const IRR_TOLERANCE: f64 = 1e-10;
+
+// The annual rate at which the journaled buys, less the sells, grow to the
+// current value of the holdings, found with Newton-Raphson.
+pub fn compute_irr(journal_path: &str, positions: &[position::Position]) -> Result<f64> {
+    let fills = journal::read_fills(journal_path)?;
+    if fills.is_empty() {
+        return Err(Error::UnexpectedData(format!("{} has no filled orders", journal_path)));
+    }
+
+    let mut holdings: HashMap<String, f64> = HashMap::new();
+    let mut prices: HashMap<String, f64> = HashMap::new();
+    for fill in &fills {
+        prices.insert(fill.symbol.clone(), fill.price);
+        *holdings.entry(fill.symbol.clone()).or_default() += signed_quantity(fill);
+    }
+    prices.extend(current_prices(positions).filter(|(sym, _)| holdings.contains_key(sym)));
+    let final_value = holdings_value(&holdings, &prices);
+
+    let now = Utc::now();
+    let flows: Vec<_> = fills
+        .iter()
+        .map(|fill| {
+            let years = (now - fill.timestamp).num_seconds() as f64 / (365.25 * 86400.0);
+            (years, signed_quantity(fill) * fill.price)
+        })
+        .collect();
+
+    // the future value of the flows less the final value, and its derivative
+    let mut rate: f64 = 0.1;
+    for _ in 0..IRR_MAX_ITERATIONS {
+        let (f, df) = flows.iter().fold((-final_value, 0.0), |(f, df), &(years, flow)| {
+            (
+                f + flow * (1.0 + rate).powf(years),
+                df + flow * years * (1.0 + rate).powf(years - 1.0),
+            )
+        });
+        if df == 0.0 {
+            break;
+        }
+
+        let next = rate - f / df;
+        if !next.is_finite() || next <= -1.0 {
+            break;
+        }
+        if (next - rate).abs() < IRR_TOLERANCE {
+            return Ok(next);
+        }
+        rate = next;
+    }
+
+    Err(Error::UnexpectedData("the IRR of the journaled trades did not converge".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn day(d: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 1, d, 16, 0, 0).unwrap()
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn cash_flows_alone_leave_the_nav_flat() {
+        let history = [(day(1), 1000.0), (day(11), 1500.0), (day(21), 1200.0)];
+        let flows = [(day(6), 500.0), (day(16), -300.0)];
+
+        let index = total_return_index(&history, &flows);
+        assert_eq!(index.len(), 3);
+        assert!(index.iter().all(|&(_, nav)| close(nav, 100.0)), "{:?}", index);
+        assert_eq!(index.iter().map(|&(t, _)| t).collect::<Vec<_>>(), [day(1), day(11), day(21)]);
+    }
+
+    #[test]
+    fn nav_grows_by_the_modified_dietz_returns() {
+        // $50 of gains in each period, around a deposit and then a withdrawal
+        // made halfway through it
+        let history = [(day(1), 1000.0), (day(11), 1550.0), (day(21), 1300.0)];
+        let flows = [(day(6), 500.0), (day(16), -300.0)];
+
+        let index = total_return_index(&history, &flows);
+        assert!(close(index[0].1, 100.0));
+        // 50 / (1000 + 0.5 * 500)
+        assert!(close(index[1].1, 104.0), "{:?}", index);
+        // 50 / (1550 - 0.5 * 300)
+        assert!(close(index[2].1, 104.0 * (1.0 + 50.0 / 1400.0)), "{:?}", index);
+
+        // flows outside the history, or on its first day, belong to no period
+        let early = [(day(1), 10000.0), (day(25), 10000.0)];
+        let flat = [(day(1), 1000.0), (day(11), 1000.0)];
+        assert!(close(total_return_index(&flat, &early)[1].1, 100.0));
+        assert!(total_return_index(&[], &flows).is_empty());
+    }
+
+    #[test]
+    fn three_periods_separate_returns_from_contributions() {
+        // $1000 deposits halfway through the first and last periods
+        let history = [(day(1), 10000.0), (day(11), 11200.0), (day(21), 11500.0), (day(31), 12700.0)];
+        let flows = [(day(6), 1000.0), (day(26), 1000.0)];
+
+        let r = contribution_adjusted_return(&history, &flows).unwrap();
+        assert!(close(r.total_contributions, 2000.0));
+        assert!(close(r.total_investment_gain, 700.0));
+        // period returns of 200 / 10500, 300 / 11200 and 200 / 12000
+        let twr = (1.0 + 200.0 / 10500.0) * (1.0 + 300.0 / 11200.0) * (1.0 + 200.0 / 12000.0) - 1.0;
+        assert!(close(r.time_weighted_return, twr), "{}", r.time_weighted_return);
+        // the deposits are invested for 25 and 5 of the 30 days
+        let mwr = 700.0 / (10000.0 + 1000.0 * 25.0 / 30.0 + 1000.0 * 5.0 / 30.0);
+        assert!(close(r.money_weighted_return, mwr), "{}", r.money_weighted_return);
+
+        assert!(contribution_adjusted_return(&[], &flows).is_none());
+    }
+}
@@ -0,0 +1,151 @@
+use chrono::Utc;
+use chrono_tz::US::Eastern;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use serde::Deserialize;
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::{load_state, load_state_with_fallback, save_state, State};
+
+// Where the state is kept, picked by `state_backend` in the config.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+pub enum StateBackend {
+    #[default]
+    Json,
+    Sqlite,
+}
+
+pub const DEFAULT_SQLITE_STATE_FILE: &str = "state.db";
+
+impl StateBackend {
+    // State paths ending in `.db`, `.sqlite` or `.sqlite3` are SQLite stores.
+    pub fn of_path(path: &str) -> Self {
+        match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("db" | "sqlite" | "sqlite3") => StateBackend::Sqlite,
+            _ => StateBackend::Json,
+        }
+    }
+}
+
+// Loads and saves a portfolio's state. Its futures are only awaited on the
+// balancer's own runtime, so they aren't required to be `Send`.
+#[allow(async_fn_in_trait)]
+pub trait StateStore {
+    async fn load(&self) -> Result<State>;
+    async fn save(&self, state: &State) -> Result<()>;
+}
+
+// Reads and writes one state file. Older versions are migrated on load, saves
+// keep the configured number of backups, and the file is encrypted when a
+// passphrase is set.
+pub struct StatePersistence {
+    pub filename: String,
+}
+
+impl StatePersistence {
+    pub fn new(filename: impl Into<String>) -> Self {
+        StatePersistence {
+            filename: filename.into(),
+        }
+    }
+
+    // Tries the file, then each backup from newest to oldest.
+    pub async fn load_with_fallback(&self) -> Result<State> {
+        load_state_with_fallback(&self.filename).await
+    }
+}
+
+impl StateStore for StatePersistence {
+    async fn load(&self) -> Result<State> {
+        load_state(&self.filename).await
+    }
+
+    async fn save(&self, state: &State) -> Result<()> {
+        save_state(&self.filename, state).await
+    }
+}
+
+// Keeps the state in a SQLite database. The latest state replaces the last
+// one like the JSON file does, encrypted the same way, and every save also
+// records the day's funding and the orders it tracks, which are never
+// overwritten.
+pub struct SqliteStateStore {
+    pub path: String,
+}
+
+impl SqliteStateStore {
+    pub fn new(path: impl Into<String>) -> Self {
+        SqliteStateStore { path: path.into() }
+    }
+
+    fn open(&self) -> Result<Connection> {
+        let conn = Connection::open(&self.path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS state (
+                id INTEGER PRIMARY KEY CHECK (id = 1),
+                version INTEGER NOT NULL,
+                data BLOB NOT NULL,
+                saved_at TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS funding_history (
+                date TEXT PRIMARY KEY,
+                last_funding_date TEXT NOT NULL,
+                fund_accum REAL NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS orders (
+                id TEXT PRIMARY KEY,
+                symbol TEXT NOT NULL,
+                quantity REAL NOT NULL,
+                submitted_at TEXT NOT NULL
+            );",
+        )?;
+        Ok(conn)
+    }
+
+    // The saved state as it would be read from a JSON state file.
+    pub fn read(&self) -> Result<Vec<u8>> {
+        // a missing database is reported like a missing state file
+        let conn = Connection::open_with_flags(&self.path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        conn.query_row("SELECT data FROM state WHERE id = 1", [], |r| r.get(0))
+            .optional()?
+            .ok_or_else(|| Error::InvalidState(format!("{} has no saved state", self.path)))
+    }
+
+    // Replaces the saved state with `data`, the encrypted serialization of `state`.
+    pub fn write(&self, state: &State, data: &[u8]) -> Result<()> {
+        let mut conn = self.open()?;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT OR REPLACE INTO state (id, version, data, saved_at) VALUES (1, ?1, ?2, ?3)",
+            params![state.version, data, Utc::now().to_rfc3339()],
+        )?;
+        if let Some(funded) = state.last_funding_date {
+            tx.execute(
+                "INSERT OR REPLACE INTO funding_history (date, last_funding_date, fund_accum) VALUES (?1, ?2, ?3)",
+                params![
+                    funded.with_timezone(&Eastern).date_naive().to_string(),
+                    funded.to_rfc3339(),
+                    state.fund_accum
+                ],
+            )?;
+        }
+        for pending in &state.pending_orders {
+            tx.execute(
+                "INSERT OR IGNORE INTO orders (id, symbol, quantity, submitted_at) VALUES (?1, ?2, ?3, ?4)",
+                params![pending.id, pending.symbol, pending.quantity, pending.submitted_at.to_rfc3339()],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+impl StateStore for SqliteStateStore {
+    async fn load(&self) -> Result<State> {
+        load_state(&self.path).await
+    }
+
+    async fn save(&self, state: &State) -> Result<()> {
+        save_state(&self.path, state).await
+    }
+}
@@ -0,0 +1,50 @@
+use chrono::{DateTime, Utc};
+
+use crate::schedule::{self, FundingFrequency};
+use crate::State;
+
+// Sizes the fundings that bring the invested equity to its target by the
+// finish date, one per `funding_frequency` period.
+pub struct FundingPlanner {
+    pub funding_frequency: FundingFrequency,
+    pub finish_date: DateTime<Utc>,
+    pub last_funding_date: Option<DateTime<Utc>>,
+    // Daily rate invested funds are assumed to earn until the finish date.
+    pub reinvestment_rate: f64,
+}
+
+impl FundingPlanner {
+    pub fn from_state(state: &State, reinvestment_rate: f64) -> Self {
+        FundingPlanner {
+            funding_frequency: state.funding_frequency,
+            finish_date: state.finish_date,
+            last_funding_date: state.last_funding_date,
+            reinvestment_rate,
+        }
+    }
+
+    fn period_rate(&self) -> f64 {
+        schedule::period_rate(self.reinvestment_rate, self.funding_frequency.period_days())
+    }
+
+    // The equal funding per period that grows to `total_additional_funding`
+    // by the finish date. It's never negative.
+    pub fn periodic_funding(&self, now: DateTime<Utc>, total_additional_funding: f64) -> f64 {
+        let days_until_finished = schedule::days_between(now, self.finish_date);
+        // the last, possibly partial, period still gets a full share of the funding
+        let periods_until_finished = (days_until_finished / self.funding_frequency.period_days()).max(1.0);
+        schedule::periodic_payment(total_additional_funding, self.period_rate(), periods_until_finished).max(0.0)
+    }
+
+    // The funding due at `now`. Periods missed since the last funding are
+    // caught up on, with the growth they would have had.
+    pub fn funding_due(&self, now: DateTime<Utc>, periodic_funding: f64) -> f64 {
+        let periods_since_last_funding = self
+            .last_funding_date
+            .map(|dt| schedule::days_between(dt, now) / self.funding_frequency.period_days());
+        match periods_since_last_funding {
+            Some(p) => schedule::accumulated_payments(periodic_funding, self.period_rate(), p),
+            None => periodic_funding,
+        }
+    }
+}
@@ -0,0 +1,298 @@
+use apca::api::v2::account_activities::Side;
+use apca::api::v2::{order, position};
+use apca::data::v2::{bars, last_quotes, quotes, trades};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::warn;
+
+use crate::api::TimedClient;
+use crate::error::Result;
+use crate::performance;
+
+// Buy limits are placed just below the last trade price by default.
+pub const DEFAULT_LIMIT_PRICE_FACTOR: f64 = 0.9999;
+
+// The fixed discount `simulate_narrow_spread_savings` compares against by default.
+pub const DEFAULT_SAVINGS_BASELINE_FACTOR: f64 = 0.999;
+
+// How far before an order the replay looks for its last trade and quote.
+const REPLAY_LOOKBACK_SECS: i64 = 5;
+
+fn default_max_pct_from_bid() -> f64 {
+    0.3
+}
+
+// Market orders fill right away at whatever the price is, limit orders only
+// at their limit price or better. Marketable limits are placed past the quote
+// on the far side, so they fill right away like market orders but never
+// beyond the collar.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+pub enum OrderType {
+    #[default]
+    Limit,
+    Market,
+    MarketableLimit,
+}
+
+// Fraction past the ask, or below the bid for sells, marketable limits are placed at.
+pub const DEFAULT_MARKETABLE_LIMIT_COLLAR: f64 = 0.005;
+
+// Falls back to the last price without a quote.
+pub fn marketable_limit_price(side: order::Side, price: f64, quote: Option<(f64, f64)>, collar: f64) -> f64 {
+    match (side, quote) {
+        (order::Side::Buy, Some((ask, _))) => ask * (1.0 + collar),
+        (order::Side::Buy, None) => price * (1.0 + collar),
+        (order::Side::Sell, Some((_, bid))) => bid * (1.0 - collar),
+        (order::Side::Sell, None) => price * (1.0 - collar),
+    }
+}
+
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub enum LimitPriceStrategy {
+    #[default]
+    FixedDiscount,
+    NarrowSpread {
+        #[serde(default = "default_max_pct_from_bid")]
+        max_pct_from_bid: f64,
+    },
+}
+
+impl LimitPriceStrategy {
+    pub fn needs_quotes(&self) -> bool {
+        matches!(self, LimitPriceStrategy::NarrowSpread { .. })
+    }
+
+    // Sell limits mirror the buy limits on the other side of the price. The
+    // factor sets the fixed discount from the last price.
+    pub fn limit_price(&self, side: order::Side, price: f64, quote: Option<(f64, f64)>, factor: f64) -> f64 {
+        match (self, quote, side) {
+            (LimitPriceStrategy::NarrowSpread { max_pct_from_bid }, Some((ask, bid)), order::Side::Buy) => {
+                smart_limit_price(ask, bid, *max_pct_from_bid)
+            }
+            (LimitPriceStrategy::NarrowSpread { max_pct_from_bid }, Some((ask, bid)), order::Side::Sell) => {
+                ask + bid - smart_limit_price(ask, bid, *max_pct_from_bid)
+            }
+            (_, _, order::Side::Buy) => price * factor,
+            (_, _, order::Side::Sell) => price * (2.0 - factor),
+        }
+    }
+}
+
+// Treats prices as uniformly distributed within the spread, so a buy limit at
+// the given percentile fills with roughly that probability.
+pub fn smart_limit_price(ask: f64, bid: f64, target_fill_probability: f64) -> f64 {
+    let (low, high) = (bid.min(ask), bid.max(ask));
+    low + target_fill_probability.clamp(0.0, 1.0) * (high - low)
+}
+
+fn quote_prices(quote: &quotes::Quote) -> Option<(f64, f64)> {
+    let (ask, bid) = (quote.ask_price.to_f64().unwrap(), quote.bid_price.to_f64().unwrap());
+    // a side without quotes is reported as zero
+    (ask > 0.0 && bid > 0.0).then_some((ask, bid))
+}
+
+pub async fn get_quotes(
+    client: &TimedClient,
+    syms: impl IntoIterator<Item = String>,
+) -> Result<HashMap<String, (f64, f64)>> {
+    let request = last_quotes::LastQuotesReqInit::default().init(syms);
+    let quotes = client.issue::<last_quotes::Get>(&request).await?;
+
+    Ok(quotes
+        .into_iter()
+        .filter_map(|(sym, q)| Some((sym, quote_prices(&q)?)))
+        .collect())
+}
+
+// The midpoint of the latest quote, if both sides are quoted.
+pub async fn mid_price(client: &TimedClient, sym: &str) -> Result<Option<f64>> {
+    Ok(get_quotes(client, [sym.to_string()])
+        .await?
+        .get(sym)
+        .map(|&(ask, bid)| (ask + bid) / 2.0))
+}
+
+// Daily closes over the last `days` calendar days, oldest first.
+pub async fn daily_closes(client: &TimedClient, sym: &str, days: i64) -> Result<Vec<f64>> {
+    let end = Utc::now();
+    let request = bars::BarsReqInit::default().init(sym, end - Duration::days(days), end, bars::TimeFrame::OneDay);
+    Ok(client
+        .issue::<bars::Get>(&request)
+        .await?
+        .bars
+        .iter()
+        .map(|bar| bar.close.to_f64().unwrap())
+        .collect())
+}
+
+// Exponential moving average with a smoothing factor of 2 / (n + 1), seeded
+// with the first value.
+pub fn ema(values: &[f64], n: u32) -> Option<f64> {
+    let alpha = 2.0 / (n as f64 + 1.0);
+    let (&first, rest) = values.split_first()?;
+    Some(rest.iter().fold(first, |ema, &v| alpha * v + (1.0 - alpha) * ema))
+}
+
+// Each position's EMA over its last `days` daily closes. Positions without
+// closes keep their live price.
+pub async fn ema_prices(client: &TimedClient, pos: &[position::Position], prices: &[f64], days: u32) -> Result<Vec<f64>> {
+    let mut smoothed = Vec::with_capacity(pos.len());
+    for (pos, &price) in pos.iter().zip(prices) {
+        // weekends and holidays leave about five closes a week
+        let closes = daily_closes(client, &pos.symbol, days as i64 * 7 / 5 + 7).await?;
+        let recent = &closes[closes.len().saturating_sub(days as usize)..];
+        match ema(recent, days) {
+            Some(ema) => smoothed.push(ema),
+            None => {
+                warn!("No daily closes for {}, sizing its orders at the live price", pos.symbol);
+                smoothed.push(price);
+            }
+        }
+    }
+    Ok(smoothed)
+}
+
+// What a NarrowSpread buy limit saves per share over a fixed discount of
+// `baseline_factor` off the last trade, both priced as of the order.
+pub fn narrow_spread_saving(last_trade: f64, quote: (f64, f64), max_pct_from_bid: f64, baseline_factor: f64) -> f64 {
+    let narrow = LimitPriceStrategy::NarrowSpread { max_pct_from_bid };
+    LimitPriceStrategy::FixedDiscount.limit_price(order::Side::Buy, last_trade, None, baseline_factor)
+        - narrow.limit_price(order::Side::Buy, last_trade, Some(quote), baseline_factor)
+}
+
+// The last trade price and the ask and bid of the last quote.
+type Market = (f64, (f64, f64));
+
+// The market in the seconds before `dt`.
+async fn market_before(client: &TimedClient, sym: &str, dt: DateTime<Utc>) -> Result<Option<Market>> {
+    let start = dt - Duration::seconds(REPLAY_LOOKBACK_SECS);
+    let request = trades::TradesReqInit::default().init(sym, start, dt);
+    let last_trade = client
+        .issue::<trades::Get>(&request)
+        .await?
+        .trades
+        .last()
+        .map(|trade| trade.price.to_f64().unwrap());
+    let request = quotes::QuotesReqInit::default().init(sym, start, dt);
+    let quote = client
+        .issue::<quotes::Get>(&request)
+        .await?
+        .quotes
+        .last()
+        .and_then(quote_prices);
+    Ok(last_trade.zip(quote))
+}
+
+// Replays the buys filled over the last `days` days and estimates what a
+// NarrowSpread limit would have saved over a fixed discount of
+// `baseline_factor`, assuming it would have filled as well. Both limits are
+// priced from the last trade and quote before the order was submitted.
+pub async fn simulate_narrow_spread_savings(
+    client: &TimedClient,
+    days: i64,
+    max_pct_from_bid: f64,
+    baseline_factor: f64,
+) -> Result<()> {
+    let fills = performance::fetch_fills(client, Utc::now() - Duration::days(days)).await?;
+
+    let mut savings: HashMap<String, (f64, usize)> = HashMap::new();
+    // an order filled in parts is only looked up once
+    let mut markets: HashMap<order::Id, Option<Market>> = HashMap::new();
+    let mut skipped = 0;
+
+    for fill in fills.iter().filter(|f| f.side == Side::Buy) {
+        let market = match markets.get(&fill.order_id) {
+            Some(&market) => market,
+            None => {
+                let order = client.issue::<order::Get>(&fill.order_id).await?;
+                let submitted_at = order.submitted_at.unwrap_or(order.created_at);
+                let market = market_before(client, &fill.symbol, submitted_at).await?;
+                markets.insert(fill.order_id, market);
+                market
+            }
+        };
+        let Some((last_trade, quote)) = market else {
+            skipped += 1;
+            continue;
+        };
+
+        let qty = fill.quantity.to_f64().unwrap();
+        let saved = narrow_spread_saving(last_trade, quote, max_pct_from_bid, baseline_factor) * qty;
+
+        let entry = savings.entry(fill.symbol.clone()).or_insert((0.0, 0));
+        entry.0 += saved;
+        entry.1 += 1;
+    }
+
+    let mut syms: Vec<_> = savings.keys().cloned().collect();
+    syms.sort();
+
+    println!("{:<8}{:>8}{:>14}", "Symbol", "Fills", "Est. savings");
+    for sym in syms {
+        let (saved, count) = savings[&sym];
+        println!("{:<8}{:>8}{:>14.2}", sym, count, saved);
+    }
+    println!(
+        "{:<8}{:>8}{:>14.2}",
+        "Total",
+        savings.values().map(|(_, c)| c).sum::<usize>(),
+        savings.values().map(|(s, _)| s).sum::<f64>()
+    );
+    if skipped > 0 {
+        warn!("{} fills had no trade or quote before their order and were skipped", skipped);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn smart_limits_sit_at_the_percentile_of_the_spread() {
+        // a 2% spread puts the limit well inside it
+        assert!(close(smart_limit_price(102.0, 100.0, 0.8), 101.6));
+        assert!(close(smart_limit_price(102.0, 100.0, 0.0), 100.0));
+        assert!(close(smart_limit_price(102.0, 100.0, 1.0), 102.0));
+        // probabilities outside [0, 1] are clamped to the quote
+        assert!(close(smart_limit_price(102.0, 100.0, 1.5), 102.0));
+        assert!(close(smart_limit_price(102.0, 100.0, -0.5), 100.0));
+    }
+
+    #[test]
+    fn narrow_and_crossed_spreads_stay_within_the_quote() {
+        // nothing to choose between when the ask is the bid
+        assert!(close(smart_limit_price(100.0, 100.0, 0.8), 100.0));
+        // a crossed quote is read the other way around
+        assert!(close(smart_limit_price(100.0, 102.0, 0.8), 101.6));
+    }
+
+    #[test]
+    fn limits_fall_back_to_the_fixed_discount_without_a_quote() {
+        let narrow = LimitPriceStrategy::NarrowSpread { max_pct_from_bid: 0.8 };
+        assert!(close(narrow.limit_price(order::Side::Buy, 100.0, None, 0.999), 99.9));
+        assert!(close(narrow.limit_price(order::Side::Sell, 100.0, None, 0.999), 100.1));
+
+        // sells mirror the buy limit across the spread
+        let quote = Some((102.0, 100.0));
+        assert!(close(narrow.limit_price(order::Side::Buy, 101.0, quote, 0.999), 101.6));
+        assert!(close(narrow.limit_price(order::Side::Sell, 101.0, quote, 0.999), 100.4));
+        let fixed = LimitPriceStrategy::FixedDiscount;
+        assert!(close(fixed.limit_price(order::Side::Buy, 100.0, quote, 0.999), 99.9));
+    }
+
+    #[test]
+    fn savings_are_measured_against_the_discount_off_the_last_trade() {
+        // the baseline sits 0.1% below the last trade of 101, at 100.899
+        let quote = (101.2, 100.0);
+        assert!(close(narrow_spread_saving(101.0, quote, 0.3, 0.999), 100.899 - 100.36));
+        // a wide spread can make the narrow limit the dearer one
+        assert!(narrow_spread_saving(101.0, (110.0, 100.0), 0.3, 0.999) < 0.0);
+    }
+}
@@ -0,0 +1,132 @@
+use apca::api::v2::{order, position};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+use crate::error::Result;
+
+pub const DEFAULT_REPORTS_DIR: &str = "reports";
+
+#[derive(Serialize, Deserialize)]
+pub struct OrderSummary {
+    pub symbol: String,
+    pub side: order::Side,
+    pub qty: f64,
+    // The limit price, or the last price for market orders.
+    pub price: f64,
+    pub estimated_cost: f64,
+}
+
+// Why a funding cycle ran, on its funding date or early once `drift_trigger_threshold` was crossed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TriggerType {
+    #[default]
+    Scheduled,
+    DriftTriggered { max_drift: f64 },
+}
+
+// What a funding cycle did, for an audit trail beyond the journal. The
+// allocations are fractions of the virtual equity, before the orders and as
+// projected once they fill. Fields added since the first reports default so
+// they can still be analyzed.
+#[derive(Serialize, Deserialize)]
+pub struct RebalancingReport {
+    pub timestamp: DateTime<Utc>,
+    pub dry_run: bool,
+    #[serde(default)]
+    pub trigger_type: TriggerType,
+    pub total_equity: f64,
+    pub cash_deployed: f64,
+    pub orders: Vec<OrderSummary>,
+    pub pre_allocation: HashMap<String, f64>,
+    pub post_allocation: HashMap<String, f64>,
+    pub allocation_rmse_before: f64,
+    pub allocation_rmse_after: f64,
+    // Annualized volatility of the market's daily returns leading up to the cycle.
+    #[serde(default)]
+    pub market_vol: Option<f64>,
+}
+
+pub fn allocation_fractions(positions: &[position::Position], equities: &[f64]) -> HashMap<String, f64> {
+    let total: f64 = equities.iter().sum();
+    positions
+        .iter()
+        .zip(equities)
+        .map(|(pos, e)| (pos.symbol.clone(), if total > 0.0 { e / total } else { 0.0 }))
+        .collect()
+}
+
+impl RebalancingReport {
+    // Writes `<dir>/YYYY-MM-DD.json`, replacing an earlier report from the
+    // same day, or `<dir>/YYYY-MM-DD-drift.json` for a drift-triggered cycle so
+    // it doesn't replace the scheduled cycle's report.
+    pub fn write(&self, dir: &str) -> Result<PathBuf> {
+        std::fs::create_dir_all(dir)?;
+        let suffix = match self.trigger_type {
+            TriggerType::Scheduled => "",
+            TriggerType::DriftTriggered { .. } => "-drift",
+        };
+        let path = Path::new(dir).join(format!("{}{}.json", self.timestamp.format("%Y-%m-%d"), suffix));
+        std::fs::write(&path, serde_json::to_vec_pretty(self)?)?;
+        Ok(path)
+    }
+}
+
+// Every report in `dir`, oldest first. Files that aren't reports are skipped.
+pub fn read_reports(dir: &str) -> Result<Vec<RebalancingReport>> {
+    let mut reports = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            match serde_json::from_slice(&std::fs::read(&path)?) {
+                Ok(report) => reports.push(report),
+                Err(e) => warn!("Skipping {}: {}", path.display(), e),
+            }
+        }
+    }
+    reports.sort_by_key(|r: &RebalancingReport| r.timestamp);
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn report(hour: u32, trigger_type: TriggerType) -> RebalancingReport {
+        RebalancingReport {
+            timestamp: Utc.with_ymd_and_hms(2024, 3, 5, hour, 0, 0).unwrap(),
+            dry_run: false,
+            trigger_type,
+            total_equity: 10000.0,
+            cash_deployed: 100.0,
+            orders: Vec::new(),
+            pre_allocation: HashMap::new(),
+            post_allocation: HashMap::new(),
+            allocation_rmse_before: 0.02,
+            allocation_rmse_after: 0.01,
+            market_vol: None,
+        }
+    }
+
+    #[test]
+    fn drift_triggered_and_scheduled_reports_of_a_day_are_both_kept() {
+        let dir = std::env::temp_dir().join(format!("apca_balancer_reports_{}", std::process::id()));
+        let dir = dir.to_str().unwrap();
+
+        let drift = report(15, TriggerType::DriftTriggered { max_drift: 0.12 });
+        assert!(drift.write(dir).unwrap().ends_with("2024-03-05-drift.json"));
+        assert!(report(19, TriggerType::Scheduled).write(dir).unwrap().ends_with("2024-03-05.json"));
+        // a rerun of the scheduled cycle still replaces its report
+        report(20, TriggerType::Scheduled).write(dir).unwrap();
+
+        let reports = read_reports(dir).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+        let triggers: Vec<_> = reports.iter().map(|r| r.trigger_type).collect();
+        assert_eq!(triggers, [drift.trigger_type, TriggerType::Scheduled]);
+        assert_eq!(reports[1].timestamp.format("%H").to_string(), "20");
+    }
+}
@@ -0,0 +1,210 @@
+use apca::api::v2::{asset, order, position};
+use num_decimal::Num;
+use std::collections::HashMap;
+use std::str::FromStr;
+use tracing::{info, warn};
+
+use crate::api::TimedClient;
+use crate::broker::Broker;
+use crate::error::Result;
+use crate::pricing::{self, OrderType};
+use crate::shutdown::Shutdown;
+use crate::{OrderSettings, State};
+
+#[derive(Debug)]
+pub struct ReconciliationWarning {
+    pub symbol: String,
+    // Fraction of the reference equity the position no longer covers.
+    pub magnitude: f64,
+}
+
+// A position worth less than its reference equity has been sold externally or
+// lost value, which hides the balancer's own shares from the virtual equity.
+fn reference_shortfalls(
+    market_values: &HashMap<String, f64>,
+    reference_equities: &HashMap<String, f64>,
+    threshold: f64,
+) -> Vec<ReconciliationWarning> {
+    let mut warnings: Vec<_> = reference_equities
+        .iter()
+        .filter(|&(_, &ref_e)| ref_e > 0.0)
+        .map(|(sym, &ref_e)| {
+            let e = market_values.get(sym).cloned().unwrap_or(0.0);
+            ReconciliationWarning {
+                symbol: sym.clone(),
+                magnitude: ((ref_e - e) / ref_e).max(0.0),
+            }
+        })
+        .filter(|w| w.magnitude > threshold)
+        .collect();
+
+    warnings.sort_by(|a, b| a.symbol.cmp(&b.symbol));
+    warnings
+}
+
+// Compares live positions against `reference_equities`, lowering the reference
+// to the current market value when `reconcile_reference_equities` is set.
+pub async fn reconcile_positions(
+    client: &TimedClient,
+    state: &mut State,
+) -> Result<Vec<ReconciliationWarning>> {
+    let pos: Vec<_> = client.get_positions().await?;
+    let market_values: HashMap<_, _> = pos
+        .iter()
+        .map(|pos| {
+            (
+                pos.symbol.clone(),
+                pos.market_value.as_ref().unwrap().to_f64().unwrap(),
+            )
+        })
+        .collect();
+
+    let warnings = reference_shortfalls(
+        &market_values,
+        &state.reference_equities,
+        state.reconciliation_threshold,
+    );
+
+    for w in &warnings {
+        warn!(
+            "{} is worth {:.1}% less than its reference equity",
+            w.symbol,
+            w.magnitude * 100.0
+        );
+
+        if state.reconcile_reference_equities {
+            let e = market_values.get(&w.symbol).cloned().unwrap_or(0.0);
+            info!("Updating the reference equity of {} to {:.2}", w.symbol, e);
+            state.reference_equities.insert(w.symbol.clone(), e);
+        }
+    }
+
+    Ok(warnings)
+}
+
+// Adds positions bought outside the balancer to the ideal allocations with a
+// weight of 0. Their current value becomes the reference equity, so they're
+// neither bought nor sold until given a weight. Watchlisted symbols are left
+// to join on their own.
+pub fn discover_new_positions(state: &mut State, pos: &[position::Position]) {
+    for pos in pos {
+        if state.ideal_allocations.contains_key(&pos.symbol) || state.watchlist.contains_key(&pos.symbol) {
+            continue;
+        }
+        info!(
+            "Found {} among the positions but not in ideal_allocations, adding it with a weight of 0. Review it and set a target weight.",
+            pos.symbol
+        );
+        state.ideal_allocations.insert(pos.symbol.clone(), 0.0);
+        let e = pos.market_value.as_ref().unwrap().to_f64().unwrap();
+        state.reference_equities.entry(pos.symbol.clone()).or_insert(e);
+    }
+}
+
+// Adds an empty position for every symbol with a target weight that isn't held
+// yet, priced at its latest ask, so the order search can buy it. Symbols
+// without a quote are left out until they have one.
+pub async fn add_unheld_targets(client: &TimedClient, state: &State, pos: &mut Vec<position::Position>) -> Result<()> {
+    let unheld: Vec<_> = state
+        .ideal_allocations
+        .iter()
+        .filter(|&(sym, &weight)| weight > 0.0 && !pos.iter().any(|pos| &pos.symbol == sym))
+        .map(|(sym, _)| sym.clone())
+        .collect();
+    if unheld.is_empty() {
+        return Ok(());
+    }
+
+    let quotes = pricing::get_quotes(client, unheld.clone()).await?;
+    for sym in unheld {
+        let Some(&(ask, _)) = quotes.get(&sym) else {
+            warn!("No quote for {}, which isn't held yet, so it can't be bought today", sym);
+            continue;
+        };
+        let asset = client.issue::<asset::Get>(&asset::Symbol::Sym(sym.clone())).await?;
+        info!("{} isn't held yet, considering it at {:.2}", sym, ask);
+        pos.push(empty_position(asset, ask));
+    }
+    Ok(())
+}
+
+fn empty_position(asset: asset::Asset, price: f64) -> position::Position {
+    let zero = Num::from(0);
+    position::Position {
+        asset_id: asset.id,
+        symbol: asset.symbol,
+        exchange: asset.exchange,
+        asset_class: asset.class,
+        average_entry_price: zero.clone(),
+        quantity: zero.clone(),
+        quantity_available: zero.clone(),
+        side: position::Side::Long,
+        market_value: Some(zero.clone()),
+        cost_basis: zero,
+        unrealized_gain_total: None,
+        unrealized_gain_total_percent: None,
+        unrealized_gain_today: None,
+        unrealized_gain_today_percent: None,
+        current_price: Some(Num::from_str(&price.to_string()).unwrap()),
+        last_day_price: None,
+        change_today: None,
+    }
+}
+
+// Sells the whole of every held position missing from the ideal allocations
+// at market. Watchlisted symbols and `keep`, the idle cash symbol, are left
+// alone, as are positions without a reference equity when `tracked_only`, since
+// a portfolio shares the account with others. Returns whether anything was sold.
+pub async fn sell_removed_positions(
+    client: &TimedClient,
+    shutdown: &Shutdown,
+    state: &mut State,
+    keep: Option<&str>,
+    tracked_only: bool,
+    dry_run: bool,
+) -> Result<bool> {
+    let pos: Vec<_> = client.get_positions().await?;
+    let removed: Vec<_> = pos
+        .iter()
+        .filter(|pos| {
+            !state.ideal_allocations.contains_key(&pos.symbol)
+                && !state.watchlist.contains_key(&pos.symbol)
+                && Some(pos.symbol.as_str()) != keep
+                && (!tracked_only || state.reference_equities.contains_key(&pos.symbol))
+                && pos.quantity.to_f64().unwrap() > 0.0
+        })
+        .collect();
+    if removed.is_empty() {
+        return Ok(false);
+    }
+
+    let syms: Vec<_> = removed.iter().map(|pos| pos.symbol.as_str()).collect();
+    warn!(
+        "!!! Liquidating {} positions missing from ideal_allocations: {} !!!",
+        removed.len(),
+        syms.join(", ")
+    );
+    let settings = OrderSettings {
+        order_type: OrderType::Market,
+        ..Default::default()
+    };
+    let mut sold = false;
+    for pos in removed {
+        let held = pos.quantity.to_f64().unwrap();
+        let qty = if state.fractional_shares { (held * 100.0).floor() / 100.0 } else { held.floor() };
+        let price = pos.current_price.as_ref().unwrap().to_f64().unwrap();
+        if dry_run {
+            info!("Would sell all {} shares of {}", qty, pos.symbol);
+            continue;
+        }
+        if state
+            .place_order(client, shutdown, &pos.symbol, order::Side::Sell, price, qty, settings)
+            .await?
+            .is_some()
+        {
+            state.reference_equities.remove(&pos.symbol);
+            sold = true;
+        }
+    }
+    Ok(sold)
+}
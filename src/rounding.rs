@@ -0,0 +1,99 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub enum RoundingStrategy {
+    // Never spend more than each order's funds.
+    #[default]
+    Floor,
+    // Round each order to the nearest share, which may overspend the budget.
+    Nearest,
+    // Floor every order, then round up the orders closest to the next share
+    // while the leftover budget allows.
+    OptimizedRounding,
+}
+
+// Converts `(funds, limit_price)` orders into whole share quantities.
+pub fn order_quantities(orders: &[(f64, f64)], budget: f64, strategy: RoundingStrategy) -> Vec<usize> {
+    let raw = orders.iter().map(|&(funds, limit_price)| funds / limit_price);
+
+    match strategy {
+        RoundingStrategy::Floor => raw.map(|q| q.floor() as usize).collect(),
+        RoundingStrategy::Nearest => raw.map(|q| q.round() as usize).collect(),
+        RoundingStrategy::OptimizedRounding => {
+            let raw: Vec<_> = raw.collect();
+            let mut quantities: Vec<_> = raw.iter().map(|q| q.floor() as usize).collect();
+
+            let mut remaining = budget
+                - quantities
+                    .iter()
+                    .zip(orders)
+                    .map(|(&q, &(_, limit_price))| q as f64 * limit_price)
+                    .sum::<f64>();
+
+            // rounding up the largest fractional parts removes the most rounding error
+            let mut by_fraction: Vec<_> = (0..orders.len()).collect();
+            by_fraction.sort_by(|&a, &b| (raw[b] - raw[b].floor()).total_cmp(&(raw[a] - raw[a].floor())));
+
+            for i in by_fraction {
+                let limit_price = orders[i].1;
+                if raw[i] > raw[i].floor() && limit_price <= remaining {
+                    quantities[i] += 1;
+                    remaining -= limit_price;
+                }
+            }
+
+            quantities
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Five orders whose funds add up to the whole budget, so no strategy has room to spare.
+    const ORDERS: [(f64, f64); 5] = [(240.0, 50.0), (200.0, 30.0), (310.0, 100.0), (150.0, 40.0), (100.0, 60.0)];
+    const BUDGET: f64 = 1000.0;
+
+    fn cost(quantities: &[usize]) -> f64 {
+        quantities.iter().zip(ORDERS).map(|(&q, (_, price))| q as f64 * price).sum()
+    }
+
+    // Root of the summed squared differences between each order's cost and its funds.
+    fn tracking_error(quantities: &[usize]) -> f64 {
+        quantities
+            .iter()
+            .zip(ORDERS)
+            .map(|(&q, (funds, price))| (q as f64 * price - funds).powi(2))
+            .sum::<f64>()
+            .sqrt()
+    }
+
+    #[test]
+    fn strategies_trade_budget_adherence_for_tracking_error() {
+        let floor = order_quantities(&ORDERS, BUDGET, RoundingStrategy::Floor);
+        let nearest = order_quantities(&ORDERS, BUDGET, RoundingStrategy::Nearest);
+        let optimized = order_quantities(&ORDERS, BUDGET, RoundingStrategy::OptimizedRounding);
+        assert_eq!(floor, [4, 6, 3, 3, 1]);
+        assert_eq!(nearest, [5, 7, 3, 4, 2]);
+        // E's share no longer fits once A, D and B were rounded up
+        assert_eq!(optimized, [5, 7, 3, 4, 1]);
+
+        assert_eq!(cost(&floor), 860.0);
+        assert_eq!(cost(&optimized), 980.0);
+        assert!(cost(&nearest) > BUDGET);
+
+        assert!((tracking_error(&floor) - 4600f64.sqrt()).abs() < 1e-9);
+        assert!((tracking_error(&optimized) - 2000f64.sqrt()).abs() < 1e-9);
+        // rounding to the nearest share tracks best, but only by overspending
+        assert!(tracking_error(&nearest) < tracking_error(&optimized));
+    }
+
+    #[test]
+    fn whole_shares_are_never_rounded_up() {
+        let orders = [(100.0, 50.0), (90.0, 30.0)];
+        for strategy in [RoundingStrategy::Floor, RoundingStrategy::Nearest, RoundingStrategy::OptimizedRounding] {
+            assert_eq!(order_quantities(&orders, 1000.0, strategy), [2, 3]);
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use apca::api::v2::calendar::OpenClose;
+use chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone, Utc, Weekday};
+use chrono_tz::US::Eastern;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::error::{Error, Result};
+
+// Orders are placed an hour after the open unless configured otherwise.
+pub const DEFAULT_TRADING_OFFSET_MINUTES: i64 = 60;
+
+// Days of the market calendar fetched when looking for the next trading day,
+// doubled up to the maximum while none is found.
+pub const DEFAULT_CALENDAR_LOOKAHEAD_DAYS: u32 = 14;
+pub const DEFAULT_CALENDAR_MAX_LOOKAHEAD_DAYS: u32 = 60;
+
+// Length of a regular session, 9:30 to 16:00 Eastern.
+const REGULAR_SESSION_MINUTES: i64 = 390;
+
+// Orders placed closer to the close than this may not fill the same day.
+const MIN_MINUTES_BEFORE_CLOSE: i64 = 30;
+
+// Extended hours orders are placed an hour before the open unless configured otherwise.
+pub const DEFAULT_EXTENDED_HOURS_OFFSET_MINUTES: i64 = 60;
+
+// The pre-market session starts at 4:00 Eastern.
+const PRE_MARKET_MINUTES: i64 = 330;
+
+// Offsets at or beyond a full session would land outside the market hours.
+pub fn validate_trading_offset(minutes: i64) -> Result<()> {
+    if minutes.abs() < REGULAR_SESSION_MINUTES {
+        Ok(())
+    } else {
+        Err(Error::InvalidConfig(format!(
+            "trading_offset_minutes must be within the {} minute session, got {}; positive values count from the open and negative ones from the close",
+            REGULAR_SESSION_MINUTES, minutes
+        )))
+    }
+}
+
+pub fn validate_extended_hours_offset(minutes: i64) -> Result<()> {
+    if minutes > 0 && minutes <= PRE_MARKET_MINUTES {
+        Ok(())
+    } else {
+        Err(Error::InvalidConfig(format!(
+            "extended_hours_offset_minutes must be in (0, {}], the minutes before the open within the pre-market session, got {}",
+            PRE_MARKET_MINUTES, minutes
+        )))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum FundingFrequency {
+    #[default]
+    Daily,
+    // Every Monday, or the next trading day when it's a holiday.
+    Weekly,
+    // The first trading day of each month.
+    Monthly,
+    // Every N calendar days.
+    Custom(u32),
+}
+
+impl FundingFrequency {
+    pub fn period_days(&self) -> f64 {
+        match self {
+            FundingFrequency::Daily => 1.0,
+            FundingFrequency::Weekly => 7.0,
+            FundingFrequency::Monthly => 365.25 / 12.0,
+            FundingFrequency::Custom(days) => (*days).max(1) as f64,
+        }
+    }
+
+    // The earliest time the next funding may happen after the one at `last`.
+    pub fn next_funding_dt(&self, last: DateTime<Utc>) -> DateTime<Utc> {
+        let last_date = last.with_timezone(&Eastern).date_naive();
+        let next_date = match self {
+            FundingFrequency::Daily => return last + Duration::days(1),
+            FundingFrequency::Custom(days) => return last + Duration::days((*days).max(1) as i64),
+            FundingFrequency::Weekly => {
+                last_date + Duration::days(7 - last_date.weekday().num_days_from_monday() as i64)
+            }
+            FundingFrequency::Monthly => {
+                let (year, month) = if last_date.month() == 12 {
+                    (last_date.year() + 1, 1)
+                } else {
+                    (last_date.year(), last_date.month() + 1)
+                };
+                NaiveDate::from_ymd_opt(year, month, 1).unwrap()
+            }
+        };
+
+        Eastern
+            .from_local_datetime(&next_date.and_hms_opt(0, 0, 0).unwrap())
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+}
+
+// Fractional days, so a funding run late in the day isn't counted as a day early.
+pub fn days_between(start: DateTime<Utc>, end: DateTime<Utc>) -> f64 {
+    (end - start).num_seconds() as f64 / 86400.0
+}
+
+// Converts a daily rate into the rate over `period_days`.
+pub fn period_rate(daily_rate: f64, period_days: f64) -> f64 {
+    (1.0 + daily_rate).powf(period_days) - 1.0
+}
+
+// The equal payment per period that grows to `total` after `periods` periods
+// when each payment earns `rate` per period until the end.
+pub fn periodic_payment(total: f64, rate: f64, periods: f64) -> f64 {
+    if rate.abs() < 1e-12 {
+        total / periods
+    } else {
+        total * rate / ((1.0 + rate).powf(periods) - 1.0)
+    }
+}
+
+// What `periods` payments grow to by the last one.
+pub fn accumulated_payments(payment: f64, rate: f64, periods: f64) -> f64 {
+    if rate.abs() < 1e-12 {
+        payment * periods
+    } else {
+        payment * ((1.0 + rate).powf(periods) - 1.0) / rate
+    }
+}
+
+// The eves of the holidays are thin every year, `dates` only lists the other
+// days to treat the same. Fields left out keep their defaults.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct ThinLiquidityDates {
+    pub dates: Vec<NaiveDate>,
+    pub extra_wait_hours: u32,
+    pub skip_thin_liquidity_days: bool,
+}
+
+// The day before Thanksgiving, Christmas Eve and New Year's Eve.
+fn thin_liquidity_dates(year: i32) -> Vec<NaiveDate> {
+    let thanksgiving = NaiveDate::from_weekday_of_month_opt(year, 11, Weekday::Thu, 4).unwrap();
+    vec![
+        thanksgiving - Duration::days(1),
+        NaiveDate::from_ymd_opt(year, 12, 24).unwrap(),
+        NaiveDate::from_ymd_opt(year, 12, 31).unwrap(),
+    ]
+}
+
+pub fn is_holiday_eve(date: NaiveDate) -> bool {
+    thin_liquidity_dates(date.year()).contains(&date)
+}
+
+impl Default for ThinLiquidityDates {
+    fn default() -> Self {
+        ThinLiquidityDates {
+            dates: Vec::new(),
+            extra_wait_hours: 2,
+            skip_thin_liquidity_days: false,
+        }
+    }
+}
+
+impl ThinLiquidityDates {
+    pub fn contains(&self, date: NaiveDate) -> bool {
+        self.dates.contains(&date) || is_holiday_eve(date)
+    }
+}
+
+// Picks the first trading day from the calendar and the time to trade on it,
+// delaying or skipping thin-liquidity days. A non-negative offset counts
+// minutes after the open and a negative one minutes before the close, unless
+// `pre_market_minutes` places the orders that long before the open instead.
+// Returns the trading time and that day's close.
+pub fn next_trading_dt(
+    open_close: &[OpenClose],
+    thin_liquidity: &ThinLiquidityDates,
+    offset_minutes: i64,
+    pre_market_minutes: Option<i64>,
+) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let oc = if thin_liquidity.skip_thin_liquidity_days {
+        open_close.iter().find(|oc| {
+            let thin = thin_liquidity.contains(oc.date);
+            if thin {
+                info!("Skipping thin-liquidity trading day {}", oc.date);
+            }
+            !thin
+        })?
+    } else {
+        open_close.first()?
+    };
+
+    let mut time = if let Some(minutes) = pre_market_minutes {
+        oc.open - Duration::minutes(minutes)
+    } else if offset_minutes >= 0 {
+        let mut offset = Duration::minutes(offset_minutes);
+        if thin_liquidity.extra_wait_hours > 0 && thin_liquidity.contains(oc.date) {
+            info!(
+                "{} is a thin-liquidity day, waiting an extra {} hours after the open",
+                oc.date, thin_liquidity.extra_wait_hours
+            );
+            offset = offset + Duration::hours(thin_liquidity.extra_wait_hours as i64);
+        }
+        oc.open + offset
+    } else {
+        oc.close + Duration::minutes(offset_minutes)
+    };
+
+    // early closes shorten the session below the validated length
+    let latest = (oc.close - Duration::minutes(MIN_MINUTES_BEFORE_CLOSE)).max(oc.open);
+    if pre_market_minutes.is_none() && (time < oc.open || time > latest) {
+        warn!(
+            "Trading time {} falls outside the {} session ({} to {} minutes before the {} close), trading at its edge instead",
+            time, oc.date, oc.open, MIN_MINUTES_BEFORE_CLOSE, oc.close
+        );
+        time = time.clamp(oc.open, latest);
+    }
+
+    let eastern = |time| Eastern.from_local_datetime(&oc.date.and_time(time)).unwrap().with_timezone(&Utc);
+    Some((eastern(time), eastern(oc.close)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thanksgiving_eve_is_thin_in_any_year() {
+        // Thanksgiving 2030 falls on November 28th, and the Friday after closes early
+        let calendar: Vec<OpenClose> = serde_json::from_str(
+            r#"[{"date": "2030-11-27", "open": "09:30", "close": "16:00"},
+                {"date": "2030-11-29", "open": "09:30", "close": "13:00"},
+                {"date": "2030-12-02", "open": "09:30", "close": "16:00"}]"#,
+        )
+        .unwrap();
+        let utc = |s: &str| s.parse::<DateTime<Utc>>().unwrap();
+
+        let mut thin = ThinLiquidityDates::default();
+        assert!(thin.dates.is_empty());
+        let trading = next_trading_dt(&calendar, &thin, 60, None).unwrap();
+        assert_eq!(trading, (utc("2030-11-27T17:30:00Z"), utc("2030-11-27T21:00:00Z")));
+
+        thin.skip_thin_liquidity_days = true;
+        let trading = next_trading_dt(&calendar, &thin, 60, None).unwrap();
+        assert_eq!(trading, (utc("2030-11-29T15:30:00Z"), utc("2030-11-29T18:00:00Z")));
+
+        // dates added by hand are skipped too
+        thin.dates.push(NaiveDate::from_ymd_opt(2030, 11, 29).unwrap());
+        let trading = next_trading_dt(&calendar, &thin, 60, None).unwrap();
+        assert_eq!(trading.0, utc("2030-12-02T15:30:00Z"));
+
+        let no_wait = ThinLiquidityDates { extra_wait_hours: 0, ..Default::default() };
+        let trading = next_trading_dt(&calendar, &no_wait, 60, None).unwrap();
+        assert_eq!(trading.0, utc("2030-11-27T15:30:00Z"));
+    }
+
+    #[test]
+    fn only_the_holiday_eves_are_thin_without_added_dates() {
+        let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+        assert!(is_holiday_eve(date(2024, 11, 27)));
+        assert!(is_holiday_eve(date(2025, 12, 24)));
+        assert!(is_holiday_eve(date(2025, 12, 31)));
+        assert!(!is_holiday_eve(date(2024, 7, 3)));
+
+        let thin: ThinLiquidityDates = toml::from_str("dates = [\"2024-07-03\"]").unwrap();
+        assert_eq!(thin.extra_wait_hours, 2);
+        assert!(thin.contains(date(2024, 7, 3)));
+        assert!(thin.contains(date(2024, 12, 24)));
+    }
+}
@@ -0,0 +1,178 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tracing::{error, info, warn};
+
+// How often a paused run checks whether the stop file was cleared.
+pub const STOP_FILE_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+// Set once SIGINT or SIGTERM arrives. Waits are abandoned right away, but work
+// that has to finish, like submitting an order and saving the state, checks
+// `is_requested` at safe points instead of being cancelled.
+#[derive(Default)]
+pub struct Shutdown {
+    requested: AtomicBool,
+    notify: Notify,
+    // No orders are submitted while this file exists.
+    stop_file: Option<String>,
+}
+
+impl Shutdown {
+    pub fn with_stop_file(stop_file: &str) -> Self {
+        Shutdown {
+            stop_file: Some(stop_file.to_string()),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    pub async fn wait(&self) {
+        let notified = self.notify.notified();
+        if self.is_requested() {
+            return;
+        }
+        notified.await;
+    }
+
+    // Checked before every order is submitted, so a stop file created in the
+    // middle of a funding cycle holds back the orders not yet submitted.
+    pub async fn trading_stopped(&self) -> bool {
+        if self.is_requested() {
+            return true;
+        }
+        match &self.stop_file {
+            Some(stop_file) => stop_file_exists(stop_file).await,
+            None => false,
+        }
+    }
+
+    // Runs `fut` to completion unless a shutdown is requested first.
+    pub async fn run_until<F: Future>(&self, fut: F) -> Option<F::Output> {
+        tokio::select! {
+            output = fut => Some(output),
+            _ = self.wait() => None,
+        }
+    }
+}
+
+pub async fn stop_file_exists(stop_file: &str) -> bool {
+    tokio::fs::metadata(stop_file).await.is_ok()
+}
+
+// Holds trading while the stop file exists. Returns false if a shutdown was
+// requested in the meantime, in which case nothing more should be submitted.
+pub async fn wait_while_stopped(shutdown: &Shutdown, poll_interval: Duration) -> bool {
+    let Some(stop_file) = &shutdown.stop_file else {
+        return !shutdown.is_requested();
+    };
+    while stop_file_exists(stop_file).await {
+        warn!("Stop file {} exists, not trading. Run clear-stop to resume.", stop_file);
+        if shutdown.run_until(tokio::time::sleep(poll_interval)).await.is_none() {
+            return false;
+        }
+    }
+    !shutdown.is_requested()
+}
+
+async fn terminate_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                term.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    std::future::pending::<()>().await;
+}
+
+pub async fn listen_for_signals(shutdown: &Shutdown) {
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate_signal() => {}
+    }
+    info!("Shutdown requested, finishing in-flight work before exiting");
+    shutdown.request();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    const POLL: Duration = Duration::from_millis(10);
+
+    fn stop_file(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("apca_balancer_stop_{}_{}", name, std::process::id()));
+        std::fs::write(&path, "").unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn a_shutdown_while_stopped_ends_the_wait_without_trading() {
+        let path = stop_file("shutdown");
+        let shutdown = Arc::new(Shutdown::with_stop_file(&path));
+        let requester = {
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(POLL * 5).await;
+                shutdown.request();
+            })
+        };
+
+        let trade = tokio::time::timeout(Duration::from_secs(10), wait_while_stopped(&shutdown, POLL))
+            .await
+            .unwrap();
+        requester.await.unwrap();
+        assert!(!trade);
+        // the stop is only lifted by clear-stop
+        assert!(stop_file_exists(&path).await);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn trading_resumes_once_the_stop_file_is_cleared() {
+        let path = stop_file("cleared");
+        let remover = {
+            let path = path.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(POLL * 5).await;
+                tokio::fs::remove_file(&path).await.unwrap();
+            })
+        };
+
+        let shutdown = Shutdown::with_stop_file(&path);
+        let trade = tokio::time::timeout(Duration::from_secs(10), wait_while_stopped(&shutdown, POLL))
+            .await
+            .unwrap();
+        remover.await.unwrap();
+        assert!(trade);
+    }
+
+    #[tokio::test]
+    async fn no_stop_file_trades_unless_shutting_down() {
+        let path = std::env::temp_dir().join("apca_balancer_stop_missing");
+        let path = path.to_str().unwrap();
+        let shutdown = Shutdown::with_stop_file(path);
+        assert!(wait_while_stopped(&shutdown, POLL).await);
+        assert!(!shutdown.trading_stopped().await);
+        shutdown.request();
+        assert!(!wait_while_stopped(&shutdown, POLL).await);
+        assert!(shutdown.trading_stopped().await);
+    }
+}
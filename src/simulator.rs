@@ -0,0 +1,51 @@
+use apca::api::v2::order;
+use tracing::{info, warn};
+
+use crate::State;
+
+// Stands in for order submission on `--simulate` runs. Orders are logged and
+// paid for from a virtual cash balance, seeded from the account's cash on the
+// first funding cycle, and the state is carried between cycles in memory so
+// the state file is never written.
+#[derive(Default)]
+pub struct Simulator {
+    cash: Option<f64>,
+    pub state: Option<State>,
+}
+
+impl Simulator {
+    pub fn seed_cash(&mut self, cash: f64) {
+        if self.cash.is_none() {
+            info!("Simulating orders against a virtual cash balance of {:.2}", cash);
+            self.cash = Some(cash);
+        }
+    }
+
+    pub fn cash(&self) -> f64 {
+        self.cash.unwrap_or(0.0)
+    }
+
+    // Returns whether the order went through, which buys only do while the
+    // virtual cash covers them.
+    pub fn submit(&mut self, sym: &str, side: order::Side, limit_price: f64, qty: f64) -> bool {
+        let cost = limit_price * qty;
+        let cash = self.cash.get_or_insert(0.0);
+        if side == order::Side::Buy && cost > *cash {
+            warn!(
+                "Simulated buy of {} {} for ${:.2} exceeds the virtual cash of {:.2}, skipping it",
+                qty, sym, cost, cash
+            );
+            return false;
+        }
+
+        *cash += match side {
+            order::Side::Buy => -cost,
+            order::Side::Sell => cost,
+        };
+        info!(
+            "Simulated {:?} of {} {} at {:.2}, virtual cash = {:.2}",
+            side, qty, sym, limit_price, cash
+        );
+        true
+    }
+}
@@ -0,0 +1,211 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::error::Result;
+use crate::fill_session::{FillSession, FillsEvent};
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SlackWebhookConfig {
+    pub webhook_url: String,
+    pub channel: String,
+    pub mention_user_on_alert: Option<String>,
+}
+
+pub struct PortfolioSnapshot {
+    pub timestamp: DateTime<Utc>,
+    pub equity: f64,
+    pub cash: f64,
+    pub drift: f64,
+    pub urgency: f64,
+    pub alert: bool,
+}
+
+fn slack_payload(config: &SlackWebhookConfig, report: &PortfolioSnapshot) -> Value {
+    let headline = match (&config.mention_user_on_alert, report.alert) {
+        (Some(user), true) => format!("<@{}> portfolio needs attention", user),
+        _ => "Portfolio summary".to_string(),
+    };
+
+    json!({
+        "channel": config.channel,
+        "text": headline,
+        "blocks": [
+            {
+                "type": "header",
+                "text": { "type": "plain_text", "text": format!("Portfolio health {}", report.timestamp.format("%Y-%m-%d")) }
+            },
+            {
+                "type": "section",
+                "text": { "type": "mrkdwn", "text": headline },
+                "fields": [
+                    { "type": "mrkdwn", "text": format!("*Equity*\n${:.2}", report.equity) },
+                    { "type": "mrkdwn", "text": format!("*Cash*\n${:.2}", report.cash) },
+                    { "type": "mrkdwn", "text": format!("*Drift (RMSE)*\n{:.2}%", report.drift * 100.0) },
+                    { "type": "mrkdwn", "text": format!("*Urgency*\n{:.0} / 100", report.urgency) }
+                ]
+            },
+            {
+                "type": "actions",
+                "elements": [
+                    {
+                        "type": "button",
+                        "text": { "type": "plain_text", "text": "View history" },
+                        "action_id": "view-history",
+                        "value": "view-history"
+                    },
+                    {
+                        "type": "button",
+                        "text": { "type": "plain_text", "text": "Export CSV" },
+                        "action_id": "export-csv",
+                        "value": "export-csv"
+                    }
+                ]
+            }
+        ]
+    })
+}
+
+// The `FillsCompleted` or `PartialFillsCompleted` event of a session, with a
+// line per order comparing its average fill price with its limit.
+fn fills_payload(config: &SlackWebhookConfig, session: &FillSession, event: &FillsEvent) -> Value {
+    let (event_name, shares_bought, capital_deployed) = match event {
+        FillsEvent::FillsCompleted { shares_bought, capital_deployed } => ("FillsCompleted", shares_bought, capital_deployed),
+        FillsEvent::PartialFillsCompleted { shares_bought, capital_deployed, .. } => {
+            ("PartialFillsCompleted", shares_bought, capital_deployed)
+        }
+    };
+    let headline = match event {
+        FillsEvent::FillsCompleted { .. } => format!("All orders of session {} filled", session.id),
+        FillsEvent::PartialFillsCompleted { .. } => format!("Some orders of session {} did not fill", session.id),
+    };
+    let fills: Vec<_> = session
+        .orders
+        .iter()
+        .map(|o| match o.avg_fill_price() {
+            Some(price) => format!(
+                "{} {:?} {} at ${:.2} vs limit ${:.2}",
+                o.symbol, o.side, o.filled_quantity, price, o.limit_price
+            ),
+            None => format!("{} {:?} unfilled, limit ${:.2}", o.symbol, o.side, o.limit_price),
+        })
+        .collect();
+
+    let mut blocks = vec![
+        json!({
+            "type": "header",
+            "text": { "type": "plain_text", "text": event_name }
+        }),
+        json!({
+            "type": "section",
+            "text": { "type": "mrkdwn", "text": headline },
+            "fields": [
+                { "type": "mrkdwn", "text": format!("*Shares bought*\n{}", shares_bought) },
+                { "type": "mrkdwn", "text": format!("*Capital deployed*\n${:.2}", capital_deployed) }
+            ]
+        }),
+        json!({
+            "type": "section",
+            "text": { "type": "mrkdwn", "text": format!("*Fills vs limits*\n{}", fills.join("\n")) }
+        }),
+    ];
+    if let FillsEvent::PartialFillsCompleted { unfilled, .. } = event {
+        let unfilled: Vec<_> = unfilled.iter().map(|(sym, qty)| format!("{} {}", sym, qty)).collect();
+        blocks.push(json!({
+            "type": "section",
+            "text": { "type": "mrkdwn", "text": format!("*Unfilled*\n{}", unfilled.join("\n")) }
+        }));
+    }
+
+    json!({
+        "channel": config.channel,
+        "text": headline,
+        "blocks": blocks
+    })
+}
+
+async fn post(config: &SlackWebhookConfig, payload: &Value) -> Result<()> {
+    reqwest::Client::new()
+        .post(&config.webhook_url)
+        .json(payload)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+pub async fn send_slack_summary(config: &SlackWebhookConfig, report: &PortfolioSnapshot) -> Result<()> {
+    post(config, &slack_payload(config, report)).await
+}
+
+pub async fn send_fills_notification(config: &SlackWebhookConfig, session: &FillSession) -> Result<()> {
+    post(config, &fills_payload(config, session, &session.event())).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn config() -> SlackWebhookConfig {
+        SlackWebhookConfig {
+            webhook_url: "https://hooks.slack.com/services/T0/B0/X".to_string(),
+            channel: "#portfolio".to_string(),
+            mention_user_on_alert: Some("U123".to_string()),
+        }
+    }
+
+    fn snapshot(alert: bool) -> PortfolioSnapshot {
+        PortfolioSnapshot {
+            timestamp: Utc.with_ymd_and_hms(2024, 3, 5, 21, 0, 0).unwrap(),
+            equity: 10234.5,
+            cash: 120.25,
+            drift: 0.0123,
+            urgency: 42.4,
+            alert,
+        }
+    }
+
+    #[test]
+    fn summaries_have_a_header_fields_and_actions() {
+        let payload = slack_payload(&config(), &snapshot(false));
+        assert_eq!(payload["channel"], "#portfolio");
+        assert_eq!(payload["text"], "Portfolio summary");
+
+        let blocks = payload["blocks"].as_array().unwrap();
+        let types: Vec<_> = blocks.iter().map(|b| b["type"].as_str().unwrap()).collect();
+        assert_eq!(types, ["header", "section", "actions"]);
+        assert_eq!(blocks[0]["text"]["type"], "plain_text");
+        assert_eq!(blocks[0]["text"]["text"], "Portfolio health 2024-03-05");
+
+        let fields: Vec<_> = blocks[1]["fields"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| f["text"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            fields,
+            ["*Equity*\n$10234.50", "*Cash*\n$120.25", "*Drift (RMSE)*\n1.23%", "*Urgency*\n42 / 100"]
+        );
+
+        let actions: Vec<_> = blocks[2]["elements"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| e["action_id"].as_str().unwrap())
+            .collect();
+        assert_eq!(actions, ["view-history", "export-csv"]);
+    }
+
+    #[test]
+    fn alerts_mention_the_configured_user() {
+        let payload = slack_payload(&config(), &snapshot(true));
+        assert_eq!(payload["text"], "<@U123> portfolio needs attention");
+        assert_eq!(payload["blocks"][1]["text"]["text"], payload["text"]);
+
+        let mut quiet = config();
+        quiet.mention_user_on_alert = None;
+        assert_eq!(slack_payload(&quiet, &snapshot(true))["text"], "Portfolio summary");
+    }
+}
@@ -0,0 +1,41 @@
+use apca::api::v2::position;
+use chrono::Utc;
+use std::fs::OpenOptions;
+use std::path::Path;
+
+use crate::error::Result;
+use crate::{normalized_ideal_allocations, virtual_equities, State};
+
+pub const DEFAULT_SNAPSHOT_PATH: &str = "portfolio_snapshots.csv";
+
+// Appends one row per position so the allocation history can be charted. The
+// fractions are of the virtual equity, like the ones the balancer targets.
+pub fn export_snapshot(path: &str, positions: &[position::Position], state: &State) -> Result<()> {
+    let write_header = !Path::new(path).exists();
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut writer = csv::Writer::from_writer(file);
+
+    if write_header {
+        writer.write_record(["date", "symbol", "equity", "actual", "ideal", "deviation"])?;
+    }
+
+    let equities = virtual_equities(positions, state);
+    let ideal_allocations = normalized_ideal_allocations(positions, state);
+    let total: f64 = equities.iter().sum();
+    let date = Utc::now().format("%Y-%m-%d").to_string();
+
+    for ((pos, e), ideal) in positions.iter().zip(&equities).zip(&ideal_allocations) {
+        let actual = if total > 0.0 { e / total } else { 0.0 };
+        writer.write_record([
+            date.clone(),
+            pos.symbol.clone(),
+            format!("{:.2}", e),
+            format!("{:.6}", actual),
+            format!("{:.6}", ideal),
+            format!("{:.6}", actual - ideal),
+        ])?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,112 @@
+pub fn mean(x: impl Iterator<Item = f64>) -> Option<f64> {
+    let (i, sum) = x.fold((0, 0.0), |(i, sum), v| (i + 1, sum + v));
+
+    if i > 0 {
+        Some(sum / i as f64)
+    } else {
+        None
+    }
+}
+
+// Sample variance, taking the mean first and then the squared deviations from
+// it, which loses less precision than a single pass. Needs two values.
+pub fn variance(x: impl Iterator<Item = f64> + Clone) -> Option<f64> {
+    covariance(x.clone(), x)
+}
+
+pub fn std_dev(x: impl Iterator<Item = f64> + Clone) -> Option<f64> {
+    variance(x).map(f64::sqrt)
+}
+
+// Sample covariance of two sequences of the same length, at least two long.
+pub fn covariance(x: impl Iterator<Item = f64> + Clone, y: impl Iterator<Item = f64> + Clone) -> Option<f64> {
+    let (n, mean_x, mean_y) = (x.clone().count(), mean(x.clone())?, mean(y.clone())?);
+    if n < 2 || y.clone().count() != n {
+        return None;
+    }
+
+    let sum: f64 = x.zip(y).map(|(a, b)| (a - mean_x) * (b - mean_y)).sum();
+    Some(sum / (n - 1) as f64)
+}
+
+// 1-based ranks of the values, ties sharing the average of the ranks they span.
+pub fn ranks(x: &[f64]) -> Vec<f64> {
+    let mut order: Vec<_> = (0..x.len()).collect();
+    order.sort_by(|&a, &b| x[a].total_cmp(&x[b]));
+
+    let mut ranks = vec![0.0; x.len()];
+    let mut start = 0;
+    while start < order.len() {
+        let end = start + order[start..].iter().take_while(|&&i| x[i] == x[order[start]]).count();
+        let rank = (start + end + 1) as f64 / 2.0;
+        for &i in &order[start..end] {
+            ranks[i] = rank;
+        }
+        start = end;
+    }
+    ranks
+}
+
+// Spearman's rank correlation, the Pearson correlation of the ranks. `None`
+// when either sequence is constant or the lengths differ.
+pub fn spearman(x: &[f64], y: &[f64]) -> Option<f64> {
+    let (rx, ry) = (ranks(x), ranks(y));
+    let cov = covariance(rx.iter().cloned(), ry.iter().cloned())?;
+    let spread = std_dev(rx.iter().cloned())? * std_dev(ry.iter().cloned())?;
+    (spread > 0.0).then(|| cov / spread)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-12
+    }
+
+    #[test]
+    fn empty_and_single_values_have_no_spread() {
+        let empty: [f64; 0] = [];
+        assert_eq!(mean(empty.into_iter()), None);
+        assert_eq!(variance(empty.into_iter()), None);
+        assert_eq!(std_dev(empty.into_iter()), None);
+        assert_eq!(covariance(empty.into_iter(), empty.into_iter()), None);
+
+        assert_eq!(mean([3.0].into_iter()), Some(3.0));
+        assert_eq!(variance([3.0].into_iter()), None);
+        assert_eq!(covariance([3.0].into_iter(), [4.0].into_iter()), None);
+    }
+
+    #[test]
+    fn negative_values() {
+        let x = [-2.0, -4.0, -6.0];
+        assert!(close(mean(x.into_iter()).unwrap(), -4.0));
+        assert!(close(variance(x.into_iter()).unwrap(), 4.0));
+        assert!(close(std_dev(x.into_iter()).unwrap(), 2.0));
+        assert!(close(covariance(x.into_iter(), [1.0, 2.0, 3.0].into_iter()).unwrap(), -2.0));
+    }
+
+    #[test]
+    fn constant_sequences_have_zero_variance() {
+        let x = [1e9 + 0.1; 5];
+        assert!(close(variance(x.into_iter()).unwrap(), 0.0));
+        assert!(close(covariance(x.into_iter(), [1.0, 2.0, 3.0, 4.0, 5.0].into_iter()).unwrap(), 0.0));
+    }
+
+    #[test]
+    fn mismatched_lengths_have_no_covariance() {
+        assert_eq!(covariance([1.0, 2.0].into_iter(), [1.0, 2.0, 3.0].into_iter()), None);
+    }
+
+    #[test]
+    fn spearman_correlates_ranks_not_values() {
+        assert_eq!(ranks(&[10.0, 30.0, 20.0, 20.0]), vec![1.0, 4.0, 2.5, 2.5]);
+
+        // monotonic but far from linear
+        let x = [1.0, 2.0, 3.0, 4.0, 5.0];
+        assert!(close(spearman(&x, &[1.0, 8.0, 27.0, 64.0, 1000.0]).unwrap(), 1.0));
+        assert!(close(spearman(&x, &[5.0, 4.0, 3.0, 2.0, -100.0]).unwrap(), -1.0));
+        assert_eq!(spearman(&x, &[2.0; 5]), None);
+        assert_eq!(spearman(&x, &[1.0, 2.0]), None);
+    }
+}
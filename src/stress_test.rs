@@ -0,0 +1,211 @@
+use apca::api::v2::order;
+use chrono::{DateTime, NaiveDate, NaiveTime, TimeZone, Utc};
+use chrono_tz::US::Eastern;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use tracing::warn;
+
+use crate::error::{Error, Result};
+use crate::{allocation_error, consolidate_orders, normalize_vec, rounding, Allocator, State};
+
+#[derive(Deserialize)]
+struct ScenarioRow {
+    date: NaiveDate,
+    symbol: String,
+    close: f64,
+}
+
+// The closes of each day in the scenario, by symbol.
+fn read_scenario(path: &str) -> Result<BTreeMap<NaiveDate, HashMap<String, f64>>> {
+    let mut days: BTreeMap<NaiveDate, HashMap<String, f64>> = BTreeMap::new();
+    for row in csv::Reader::from_path(path)?.deserialize() {
+        let row: ScenarioRow = row?;
+        days.entry(row.date).or_default().insert(row.symbol, row.close);
+    }
+    Ok(days)
+}
+
+// Scenario days are treated as funded at the close.
+fn close_dt(date: NaiveDate) -> DateTime<Utc> {
+    Eastern
+        .from_local_datetime(&date.and_time(NaiveTime::from_hms_opt(16, 0, 0).unwrap()))
+        .unwrap()
+        .with_timezone(&Utc)
+}
+
+// Replays the funding strategy over the scenario's closes, starting from cash
+// and the state's ideal allocations. Each funding day invests the target still
+// missing divided by the funding days left, and limit orders are assumed to
+// fill at their limit price. The benchmark is bought with the same fundings.
+pub fn stress_test(state: &State, scenario: &str, initial_equity: f64) -> Result<()> {
+    if initial_equity <= 0.0 {
+        return Err(Error::InvalidConfig(format!(
+            "initial equity must be positive, got {}",
+            initial_equity
+        )));
+    }
+
+    let days = read_scenario(scenario)?;
+    let mut syms: Vec<_> = state.ideal_allocations.keys().cloned().collect();
+    syms.sort();
+    let ideal_allocations = normalize_vec(syms.iter().map(|sym| state.ideal_allocations[sym]).collect());
+    let bounds = |bounds: &HashMap<String, f64>, default: f64| -> Vec<f64> {
+        syms.iter().map(|sym| bounds.get(sym).cloned().unwrap_or(default)).collect()
+    };
+    let allocator = Allocator {
+        ideal_allocations: ideal_allocations.clone(),
+        min_allocations: bounds(&state.min_allocations, 0.0),
+        max_allocations: bounds(&state.max_allocations, 1.0),
+        sell_enabled: state.sell_enabled,
+        fractional_shares: state.fractional_shares,
+        // the replay only reads the state, not the config
+        weighted_error: false,
+    };
+
+    // the replay starts on the first day every symbol has a close
+    let mut last_closes: HashMap<&str, f64> = HashMap::new();
+    let mut trading_days = Vec::new();
+    for (&date, closes) in &days {
+        last_closes.extend(closes.iter().map(|(sym, &close)| (sym.as_str(), close)));
+        if let Some(prices) = syms.iter().map(|sym| last_closes.get(sym.as_str()).cloned()).collect::<Option<Vec<_>>>() {
+            let benchmark_price = last_closes.get(state.benchmark_symbol.as_str()).cloned();
+            trading_days.push((date, prices, benchmark_price));
+        }
+    }
+    if trading_days.is_empty() {
+        return Err(Error::UnexpectedData(format!(
+            "{} has no day with a close for every symbol in ideal_allocations",
+            scenario
+        )));
+    }
+
+    let mut next_funding_dt = close_dt(trading_days[0].0);
+    let mut funding_days = Vec::new();
+    for (i, (date, _, _)) in trading_days.iter().enumerate() {
+        if close_dt(*date) >= next_funding_dt {
+            funding_days.push(i);
+            next_funding_dt = state.funding_frequency.next_funding_dt(close_dt(*date));
+        }
+    }
+
+    let target_invested = initial_equity * state.target_investment_equity_ratio;
+    let mut cash = initial_equity;
+    let mut shares = vec![0.0; syms.len()];
+    let mut fund_accum = 0.0;
+    let mut orders_filled = 0;
+    let (mut benchmark_cash, mut benchmark_shares) = (initial_equity, 0.0);
+
+    for (funding_idx, &day) in funding_days.iter().enumerate() {
+        let (_, prices, benchmark_price) = &trading_days[day];
+        let equities: Vec<f64> = shares.iter().zip(prices).map(|(s, p)| s * p).collect();
+        let invested: f64 = equities.iter().sum();
+        let periods_left = (funding_days.len() - funding_idx) as f64;
+        let funding = ((target_invested - invested) / periods_left).max(0.0);
+
+        if let Some(price) = benchmark_price {
+            let benchmark_invested = benchmark_shares * price;
+            let amount = ((target_invested - benchmark_invested) / periods_left).clamp(0.0, benchmark_cash);
+            benchmark_shares += amount / price;
+            benchmark_cash -= amount;
+        }
+
+        let funding_today = funding + fund_accum;
+        // the unspent funding carries over, like it does between funding cycles
+        if allocation_error(&equities, &ideal_allocations).sqrt() < state.min_rebalance_drift {
+            fund_accum = funding_today;
+            continue;
+        }
+
+        let budget = funding_today.min(cash);
+        let (orders, _) = allocator.generate_orders(&equities, prices, prices, budget)?;
+        // the replay works in floating point like the rest of the simulation
+        let orders: Vec<_> = consolidate_orders(orders)
+            .into_iter()
+            .map(|(idx, side, funding)| (idx, side, funding.to_f64().unwrap()))
+            .collect();
+
+        let limit_prices: Vec<f64> = orders
+            .iter()
+            .map(|&(idx, side, _)| {
+                state
+                    .limit_price_strategy
+                    .limit_price(side, prices[idx], None, state.limit_price_factor)
+            })
+            .collect();
+        let sized_buys: Vec<_> = orders
+            .iter()
+            .zip(&limit_prices)
+            .filter(|(&(_, side, _), _)| side == order::Side::Buy)
+            .map(|(&(_, _, funding), &limit_price)| (funding, limit_price))
+            .collect();
+        let sell_proceeds: f64 = orders
+            .iter()
+            .filter(|&&(_, side, _)| side == order::Side::Sell)
+            .map(|&(_, _, funding)| funding)
+            .sum();
+        let buy_quantities: Vec<f64> = if state.fractional_shares {
+            sized_buys
+                .iter()
+                .map(|&(funds, limit_price)| (funds / limit_price * 100.0).floor() / 100.0)
+                .collect()
+        } else {
+            rounding::order_quantities(&sized_buys, budget + sell_proceeds, state.rounding_strategy)
+                .into_iter()
+                .map(|q| q as f64)
+                .collect()
+        };
+        let mut buy_quantities = buy_quantities.into_iter();
+
+        let mut funds_used = 0.0;
+        for (&(idx, side, funding), &limit_price) in orders.iter().zip(&limit_prices) {
+            let qty = match side {
+                order::Side::Buy => buy_quantities.next().unwrap(),
+                order::Side::Sell => (funding / prices[idx] * 100.0).round() / 100.0,
+            };
+            if qty <= 0.0 {
+                continue;
+            }
+            let (signed_qty, signed_cost) = match side {
+                order::Side::Buy => (qty, qty * limit_price),
+                order::Side::Sell => (-qty, -qty * limit_price),
+            };
+            shares[idx] += signed_qty;
+            cash -= signed_cost;
+            funds_used += signed_cost;
+            orders_filled += 1;
+        }
+        fund_accum = funding_today - funds_used;
+    }
+
+    let (last_date, last_prices, last_benchmark_price) = trading_days.last().unwrap();
+    let final_value = cash + shares.iter().zip(last_prices).map(|(s, p)| s * p).sum::<f64>();
+
+    println!(
+        "Replayed {} trading days from {} to {}, funding on {} of them",
+        trading_days.len(),
+        trading_days[0].0,
+        last_date,
+        funding_days.len()
+    );
+    println!("{:<8}{:>12}{:>12}", "Symbol", "Value", "Actual %");
+    for ((sym, s), p) in syms.iter().zip(&shares).zip(last_prices) {
+        println!("{:<8}{:>12.2}{:>12.2}", sym, s * p, s * p / final_value * 100.0);
+    }
+    println!("{:<8}{:>12.2}{:>12.2}", "Cash", cash, cash / final_value * 100.0);
+    println!("Orders filled = {}", orders_filled);
+    println!("Final value = {:.2}", final_value);
+    println!("Return = {:.2}%", (final_value / initial_equity - 1.0) * 100.0);
+    match last_benchmark_price {
+        Some(price) => {
+            let benchmark_value = benchmark_cash + benchmark_shares * price;
+            println!(
+                "{} return = {:.2}%",
+                state.benchmark_symbol,
+                (benchmark_value / initial_equity - 1.0) * 100.0
+            );
+        }
+        None => warn!("{} has no closes for benchmark {}", scenario, state.benchmark_symbol),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,103 @@
+use apca::api::v2::{order, position};
+use tracing::{info, warn};
+
+use crate::api::TimedClient;
+use crate::error::Result;
+use crate::shutdown::Shutdown;
+use crate::{pricing, OrderSettings, State};
+
+pub const DEFAULT_IDLE_CASH_THRESHOLD: f64 = 100.0;
+
+// Parks cash the balancer isn't spending in a money-market ETF. The position
+// counts as cash to the balancer and is sold again when the funding needs it.
+pub struct IdleCashSweep {
+    pub symbol: String,
+    // Smallest amount worth sweeping.
+    pub threshold: f64,
+    // Always left in cash.
+    pub buffer: f64,
+    pub order_settings: OrderSettings,
+}
+
+impl IdleCashSweep {
+    pub fn position<'a>(&self, pos: &'a [position::Position]) -> Option<&'a position::Position> {
+        pos.iter().find(|pos| pos.symbol == self.symbol)
+    }
+
+    pub fn held_value(&self, pos: &[position::Position]) -> f64 {
+        self.position(pos)
+            .map_or(0.0, |pos| pos.market_value.as_ref().unwrap().to_f64().unwrap())
+    }
+
+    // Margin is never swept, only the cash beyond the buffer.
+    pub fn sweep_amount(&self, cash: f64, buying_power: f64) -> Option<f64> {
+        let available = cash.min(buying_power) - self.buffer;
+        (available > self.threshold).then_some(available)
+    }
+
+    // How much to sell so the cash covers the funding and the buffer.
+    pub fn unwind_amount(&self, held_value: f64, cash: f64, funding: f64) -> Option<f64> {
+        let shortfall = (funding + self.buffer - cash).min(held_value);
+        (shortfall > 0.0).then_some(shortfall)
+    }
+
+    pub async fn buy(&self, client: &TimedClient, shutdown: &Shutdown, state: &mut State, amount: f64) -> Result<()> {
+        let Some(&(ask, _)) = pricing::get_quotes(client, [self.symbol.clone()]).await?.get(&self.symbol) else {
+            warn!("No quote for idle cash symbol {}, not sweeping", self.symbol);
+            return Ok(());
+        };
+        let qty = self.quantity(state, order::Side::Buy, amount, ask, f64::INFINITY);
+        self.submit(client, shutdown, state, order::Side::Buy, ask, qty).await
+    }
+
+    pub async fn sell(
+        &self,
+        client: &TimedClient,
+        shutdown: &Shutdown,
+        state: &mut State,
+        held: &position::Position,
+        amount: f64,
+    ) -> Result<()> {
+        let price = held.current_price.as_ref().unwrap().to_f64().unwrap();
+        let held_qty = held.quantity.to_f64().unwrap();
+        let qty = self.quantity(state, order::Side::Sell, amount, price, held_qty);
+        self.submit(client, shutdown, state, order::Side::Sell, price, qty).await
+    }
+
+    // Buys never spend more than `amount` while sells raise at least `amount`,
+    // up to the `max_qty` held.
+    fn quantity(&self, state: &State, side: order::Side, amount: f64, price: f64, max_qty: f64) -> f64 {
+        let limit_price = self.limit_price(state, side, price);
+        let (raw, scale) = (amount / limit_price, if state.fractional_shares { 100.0 } else { 1.0 });
+        let qty = match side {
+            order::Side::Buy => (raw * scale).floor() / scale,
+            order::Side::Sell => (raw * scale).ceil() / scale,
+        };
+        qty.min(max_qty)
+    }
+
+    fn limit_price(&self, state: &State, side: order::Side, price: f64) -> f64 {
+        self.order_settings.order_price(state, side, price, None)
+    }
+
+    async fn submit(
+        &self,
+        client: &TimedClient,
+        shutdown: &Shutdown,
+        state: &mut State,
+        side: order::Side,
+        price: f64,
+        qty: f64,
+    ) -> Result<()> {
+        if qty <= 0.0 {
+            info!("Idle cash amount buys less than one share of {}", self.symbol);
+            return Ok(());
+        }
+
+        let limit_price = self.limit_price(state, side, price);
+        state
+            .place_order(client, shutdown, &self.symbol, side, limit_price, qty, self.order_settings)
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,225 @@
+use apca::api::v2::{account, calendar, order, position};
+use apca::RequestError;
+use http_endpoint::Endpoint;
+use reqwest::StatusCode;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use crate::api::{endpoint_name, AlpacaClient, Retryable};
+use crate::broker::Broker;
+use crate::error::{Error, Result};
+
+// Stands in for the Alpaca API. Responses are queued per endpoint, e.g.
+// `order::Post`, and answered in order, while every request is recorded with
+// its endpoint and JSON body.
+#[derive(Default)]
+pub struct MockClient {
+    responses: Mutex<HashMap<String, VecDeque<(StatusCode, String)>>>,
+    calls: Mutex<Vec<(String, Option<serde_json::Value>)>>,
+}
+
+impl MockClient {
+    pub fn respond(&self, endpoint: &str, status: StatusCode, body: serde_json::Value) {
+        self.responses
+            .lock()
+            .unwrap()
+            .entry(endpoint.to_string())
+            .or_default()
+            .push_back((status, body.to_string()));
+    }
+
+    pub fn calls(&self) -> Vec<(String, Option<serde_json::Value>)> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+impl AlpacaClient for MockClient {
+    async fn issue<E>(&self, input: &E::Input) -> Result<E::Output, RequestError<E::Error>>
+    where
+        E: Endpoint,
+        E::Error: Retryable,
+    {
+        let endpoint = endpoint_name::<E>();
+        let body = E::body(input)
+            .ok()
+            .flatten()
+            .map(|body| serde_json::from_slice(&body).unwrap());
+        self.calls.lock().unwrap().push((endpoint.clone(), body));
+
+        let (status, body) = self
+            .responses
+            .lock()
+            .unwrap()
+            .get_mut(&endpoint)
+            .and_then(VecDeque::pop_front)
+            .unwrap_or_else(|| panic!("no response queued for {}", endpoint));
+        E::evaluate(status, body.as_bytes()).map_err(RequestError::Endpoint)
+    }
+}
+
+// An order as Alpaca reports it, limited to the fields the balancer reads.
+pub fn order_json(symbol: &str, side: &str, qty: &str, status: &str, filled_qty: &str, fill_price: Option<&str>) -> serde_json::Value {
+    serde_json::json!({
+        "id": "904837e3-3b76-47ec-b432-046db621571b",
+        "client_order_id": "904837e3-3b76-47ec-b432-046db621571b",
+        "created_at": "2024-01-02T15:30:00Z",
+        "updated_at": "2024-01-02T15:30:00Z",
+        "submitted_at": "2024-01-02T15:30:00Z",
+        "filled_at": null,
+        "expired_at": null,
+        "canceled_at": null,
+        "failed_at": null,
+        "asset_id": "904837e3-3b76-47ec-b432-046db621571b",
+        "symbol": symbol,
+        "asset_class": "us_equity",
+        "qty": qty,
+        "filled_qty": filled_qty,
+        "type": "limit",
+        "order_class": "",
+        "side": side,
+        "time_in_force": "day",
+        "limit_price": "100.00",
+        "stop_price": null,
+        "filled_avg_price": fill_price,
+        "status": status,
+        "extended_hours": false,
+        "legs": null
+    })
+}
+
+// A symbol's holding in a `MockBroker`.
+pub struct MockHolding {
+    pub quantity: f64,
+    pub price: f64,
+}
+
+// A broker kept in memory. Every order fills at once, at its limit price or
+// at the holding's price for market orders, and moves cash into or out of the
+// holding. Buys beyond the cash are rejected.
+#[derive(Default)]
+pub struct MockBroker {
+    pub cash: Mutex<f64>,
+    pub holdings: Mutex<HashMap<String, MockHolding>>,
+    pub calendar: Vec<calendar::OpenClose>,
+    pub orders: Mutex<Vec<(String, order::Side, f64, f64)>>,
+}
+
+impl MockBroker {
+    pub fn new(cash: f64, holdings: &[(&str, f64, f64)]) -> Self {
+        MockBroker {
+            cash: Mutex::new(cash),
+            holdings: Mutex::new(
+                holdings
+                    .iter()
+                    .map(|&(sym, quantity, price)| (sym.to_string(), MockHolding { quantity, price }))
+                    .collect(),
+            ),
+            ..Default::default()
+        }
+    }
+}
+
+fn from_json<T: serde::de::DeserializeOwned>(json: serde_json::Value) -> T {
+    // apca's types borrow from the input, so they can't come from a `Value`
+    serde_json::from_str(&json.to_string()).unwrap()
+}
+
+impl Broker for MockBroker {
+    async fn get_account(&self) -> Result<account::Account> {
+        let cash = *self.cash.lock().unwrap();
+        let invested: f64 = self.holdings.lock().unwrap().values().map(|h| h.quantity * h.price).sum();
+        Ok(from_json(serde_json::json!({
+            "id": "904837e3-3b76-47ec-b432-046db621571b",
+            "status": "ACTIVE",
+            "currency": "USD",
+            "cash": cash.to_string(),
+            "pattern_day_trader": false,
+            "trade_suspended_by_user": false,
+            "trading_blocked": false,
+            "transfers_blocked": false,
+            "account_blocked": false,
+            "created_at": "2024-01-02T15:30:00Z",
+            "shorting_enabled": false,
+            "long_market_value": invested.to_string(),
+            "short_market_value": "0",
+            "equity": (cash + invested).to_string(),
+            "last_equity": (cash + invested).to_string(),
+            "multiplier": "1",
+            "buying_power": cash.to_string(),
+            "initial_margin": "0",
+            "maintenance_margin": "0",
+            "daytrade_count": 0
+        })))
+    }
+
+    async fn get_positions(&self) -> Result<Vec<position::Position>> {
+        let holdings = self.holdings.lock().unwrap();
+        Ok(holdings
+            .iter()
+            .filter(|(_, h)| h.quantity > 0.0)
+            .map(|(sym, h)| {
+                from_json(serde_json::json!({
+                    "asset_id": "904837e3-3b76-47ec-b432-046db621571b",
+                    "symbol": sym,
+                    "exchange": "NYSE",
+                    "asset_class": "us_equity",
+                    "avg_entry_price": h.price.to_string(),
+                    "qty": h.quantity.to_string(),
+                    "qty_available": h.quantity.to_string(),
+                    "side": "long",
+                    "market_value": (h.quantity * h.price).to_string(),
+                    "cost_basis": (h.quantity * h.price).to_string(),
+                    "unrealized_pl": null,
+                    "unrealized_plpc": null,
+                    "unrealized_intraday_pl": null,
+                    "unrealized_intraday_plpc": null,
+                    "current_price": h.price.to_string(),
+                    "lastday_price": null,
+                    "change_today": null
+                }))
+            })
+            .collect())
+    }
+
+    async fn get_calendar(&self, request: &calendar::CalendarReq) -> Result<Vec<calendar::OpenClose>> {
+        Ok(self
+            .calendar
+            .iter()
+            .filter(|day| day.date >= request.start && day.date < request.end)
+            .cloned()
+            .collect())
+    }
+
+    async fn submit_order(&self, request: &order::OrderReq) -> Result<order::Order> {
+        let sym = request.symbol.to_string();
+        let mut holdings = self.holdings.lock().unwrap();
+        let holding = holdings.entry(sym.clone()).or_insert(MockHolding { quantity: 0.0, price: 0.0 });
+        let price = request.limit_price.as_ref().map_or(holding.price, |p| p.to_f64().unwrap());
+        let qty = match &request.amount {
+            order::Amount::Quantity { quantity } => quantity.to_f64().unwrap(),
+            order::Amount::Notional { notional } => notional.to_f64().unwrap() / price,
+        };
+        let signed_qty = match request.side {
+            order::Side::Buy => qty,
+            order::Side::Sell => -qty,
+        };
+
+        let mut cash = self.cash.lock().unwrap();
+        if signed_qty * price > *cash || holding.quantity + signed_qty < 0.0 {
+            return Err(Error::OrderRejected {
+                symbol: sym,
+                reason: "insufficient buying power or shares".into(),
+            });
+        }
+        *cash -= signed_qty * price;
+        holding.quantity += signed_qty;
+        self.orders.lock().unwrap().push((sym.clone(), request.side, qty, price));
+
+        let side = match request.side {
+            order::Side::Buy => "buy",
+            order::Side::Sell => "sell",
+        };
+        let (qty, price) = (qty.to_string(), price.to_string());
+        Ok(from_json(order_json(&sym, side, &qty, "filled", &qty, Some(&price))))
+    }
+}
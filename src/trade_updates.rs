@@ -0,0 +1,71 @@
+use apca::api::v2::order;
+use apca::api::v2::updates::{OrderUpdate, OrderUpdates};
+use apca::Subscribable;
+use futures::stream::{BoxStream, StreamExt};
+use std::collections::HashMap;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+use crate::api::TimedClient;
+use crate::error::Result;
+
+// Alpaca's trade_updates stream, holding the latest state of each order it
+// reported until the order is taken.
+pub struct TradeUpdates {
+    stream: BoxStream<'static, std::result::Result<OrderUpdate, String>>,
+    // dropping the subscription would close the connection
+    _subscription: Option<<OrderUpdates as Subscribable>::Subscription>,
+    latest: HashMap<order::Id, order::Order>,
+    closed: bool,
+}
+
+impl TradeUpdates {
+    pub async fn connect(client: &TimedClient) -> Result<Self> {
+        let (stream, subscription) = client.subscribe::<OrderUpdates>().await?;
+        let stream = stream.map(|message| match message {
+            Ok(Ok(update)) => Ok(update),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(e) => Err(e.to_string()),
+        });
+        let mut updates = Self::from_stream(stream);
+        updates._subscription = Some(subscription);
+        Ok(updates)
+    }
+
+    pub fn from_stream(
+        stream: impl futures::Stream<Item = std::result::Result<OrderUpdate, String>> + Send + 'static,
+    ) -> Self {
+        TradeUpdates {
+            stream: stream.boxed(),
+            _subscription: None,
+            latest: HashMap::new(),
+            closed: false,
+        }
+    }
+
+    // Waits up to `timeout` for the next update. Once the stream has ended this
+    // only sleeps, leaving the orders to be polled.
+    pub async fn wait(&mut self, timeout: Duration) {
+        if self.closed {
+            tokio::time::sleep(timeout).await;
+            return;
+        }
+        match tokio::time::timeout(timeout, self.stream.next()).await {
+            Ok(Some(Ok(update))) => {
+                debug!("Trade update {:?} for order {}", update.event, update.order.id.to_string());
+                self.latest.insert(update.order.id, update.order);
+            }
+            Ok(Some(Err(e))) => warn!("Unreadable trade update: {}", e),
+            Ok(None) => {
+                warn!("The trade updates stream closed, falling back to polling orders");
+                self.closed = true;
+            }
+            Err(_) => {}
+        }
+    }
+
+    // The latest state of the order, if the stream reported it since it was last taken.
+    pub fn take(&mut self, id: &order::Id) -> Option<order::Order> {
+        self.latest.remove(id)
+    }
+}
@@ -0,0 +1,209 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use tracing::{info, warn};
+
+use crate::error::{Error, Result};
+use crate::{normalize_map, State};
+
+const SNP500_CSV_URL: &str =
+    "https://raw.githubusercontent.com/datasets/s-and-p-500-companies/main/data/constituents.csv";
+const NASDAQ100_URL: &str = "https://en.wikipedia.org/wiki/Nasdaq-100";
+
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub enum UniverseSource {
+    #[default]
+    Static,
+    SnP500Constituents,
+    Nasdaq100,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DynamicUniverse {
+    pub source: UniverseSource,
+    pub refresh_frequency_days: u32,
+    pub max_symbols: usize,
+    #[serde(default)]
+    pub last_refresh: Option<DateTime<Utc>>,
+    // Symbols that left the universe and are no longer funded.
+    #[serde(default)]
+    pub removed_symbols: Vec<String>,
+}
+
+impl DynamicUniverse {
+    pub fn refresh_due(&self) -> bool {
+        match self.last_refresh {
+            Some(dt) => Utc::now() - dt >= Duration::days(self.refresh_frequency_days as i64),
+            None => true,
+        }
+    }
+}
+
+// The first column of the dataset's CSV is the symbol.
+fn parse_snp500_csv(csv: &str) -> Vec<String> {
+    csv.lines()
+        .skip(1)
+        .filter_map(|line| line.split(',').next())
+        .map(|sym| sym.trim().trim_matches('"').to_string())
+        .filter(|sym| !sym.is_empty())
+        .collect()
+}
+
+fn strip_tags(html: &str) -> String {
+    let mut text = String::new();
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            c if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text.trim().to_string()
+}
+
+// Reads the ticker column of the constituents table on the Wikipedia page.
+fn parse_nasdaq100_html(html: &str) -> Result<Vec<String>> {
+    let start = html
+        .find("id=\"constituents\"")
+        .ok_or_else(|| Error::UnexpectedData("constituents table not found".to_string()))?;
+    let table = &html[start..];
+    let table = &table[..table.find("</table>").unwrap_or(table.len())];
+
+    let rows: Vec<Vec<String>> = table
+        .split("<tr")
+        .skip(1)
+        .map(|row| {
+            row.split("<t")
+                .skip(1)
+                .map(|cell| {
+                    let cell = cell.split_once('>').map(|(_, c)| c).unwrap_or("");
+                    strip_tags(cell.split("</t").next().unwrap_or(""))
+                })
+                .collect()
+        })
+        .collect();
+
+    let header = rows
+        .first()
+        .ok_or_else(|| Error::UnexpectedData("constituents table is empty".to_string()))?;
+    let column = header
+        .iter()
+        .position(|h| h == "Ticker" || h == "Symbol")
+        .ok_or_else(|| Error::UnexpectedData("constituents table has no ticker column".to_string()))?;
+
+    Ok(rows[1..]
+        .iter()
+        .filter_map(|row| row.get(column).cloned())
+        .filter(|sym| !sym.is_empty())
+        .collect())
+}
+
+pub async fn fetch_universe(source: UniverseSource) -> Result<Option<Vec<String>>> {
+    let symbols = match source {
+        UniverseSource::Static => return Ok(None),
+        UniverseSource::SnP500Constituents => parse_snp500_csv(
+            &reqwest::get(SNP500_CSV_URL)
+                .await?
+                .error_for_status()?
+                .text()
+                .await?,
+        ),
+        UniverseSource::Nasdaq100 => parse_nasdaq100_html(
+            &reqwest::get(NASDAQ100_URL)
+                .await?
+                .error_for_status()?
+                .text()
+                .await?,
+        )?,
+    };
+
+    // Alpaca uses dots for share classes, e.g. BRK.B
+    Ok(Some(
+        symbols
+            .into_iter()
+            .map(|sym| sym.replace('-', "."))
+            .collect(),
+    ))
+}
+
+// Adds new constituents at zero weight and drops departed ones from
+// `ideal_allocations`, recording them for an orderly exit.
+pub async fn refresh_universe(state: &mut State) -> Result<()> {
+    let Some(universe) = state.universe.as_mut() else {
+        warn!("No dynamic universe configured");
+        return Ok(());
+    };
+
+    let Some(mut symbols) = fetch_universe(universe.source).await? else {
+        universe.last_refresh = Some(Utc::now());
+        return Ok(());
+    };
+    symbols.truncate(universe.max_symbols);
+    let symbols: HashSet<_> = symbols.into_iter().collect();
+
+    let mut added: Vec<_> = symbols
+        .iter()
+        .filter(|sym| !state.ideal_allocations.contains_key(*sym))
+        .cloned()
+        .collect();
+    let mut removed: Vec<_> = state
+        .ideal_allocations
+        .keys()
+        .filter(|sym| !symbols.contains(*sym))
+        .cloned()
+        .collect();
+    added.sort();
+    removed.sort();
+
+    for sym in &added {
+        state.ideal_allocations.insert(sym.clone(), 0.0);
+        state.reference_equities.entry(sym.clone()).or_insert(0.0);
+    }
+    for sym in &removed {
+        state.ideal_allocations.remove(sym);
+        if !universe.removed_symbols.contains(sym) {
+            universe.removed_symbols.push(sym.clone());
+        }
+    }
+    universe
+        .removed_symbols
+        .retain(|sym| !symbols.contains(sym));
+    universe.last_refresh = Some(Utc::now());
+
+    normalize_map(&mut state.ideal_allocations);
+
+    info!("Universe refreshed: {} symbols", symbols.len());
+    if !added.is_empty() {
+        info!("Added at zero weight: {}", added.join(", "));
+    }
+    if !removed.is_empty() {
+        info!("Removed: {}", removed.join(", "));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snp500_symbols_are_read_from_the_first_column() {
+        let csv = include_str!("../testdata/snp500_constituents.csv");
+        assert_eq!(parse_snp500_csv(csv), ["MMM", "AOS", "ABT", "BRK.B", "BF.B"]);
+    }
+
+    #[test]
+    fn nasdaq100_symbols_are_read_from_the_constituents_table() {
+        let html = include_str!("../testdata/nasdaq100_constituents.html");
+        assert_eq!(parse_nasdaq100_html(html).unwrap(), ["ADBE", "GOOGL", "GOOG", "AAPL"]);
+
+        // a redesigned page is reported rather than read as an empty universe
+        let without_table = html.replace("id=\"constituents\"", "id=\"components\"");
+        assert!(matches!(parse_nasdaq100_html(&without_table), Err(Error::UnexpectedData(_))));
+        let without_ticker = html.replace("<th>Ticker</th>", "<th>Code</th>");
+        assert!(matches!(parse_nasdaq100_html(&without_ticker), Err(Error::UnexpectedData(_))));
+    }
+}
@@ -0,0 +1,67 @@
+use apca::api::v2::position;
+use tracing::warn;
+
+use crate::api::TimedClient;
+use crate::error::{Error, Result};
+use crate::{pricing, stats};
+
+pub const DEFAULT_TARGET_VOL: f64 = 0.15;
+
+// Calendar days of daily bars the volatility scaling is measured over.
+const SCALING_LOOKBACK_DAYS: i64 = 20;
+const TRADING_DAYS_PER_YEAR: f64 = 252.0;
+
+// Keeps a near-constant price from turning into an outsized buy, or a wild
+// one into a negligible one.
+const MIN_SCALE: f64 = 0.25;
+const MAX_SCALE: f64 = 4.0;
+
+// Sample standard deviation of the daily returns over the last `days` calendar days.
+pub async fn daily_volatility(client: &TimedClient, sym: &str, days: i64) -> Result<f64> {
+    let closes = pricing::daily_closes(client, sym, days).await?;
+    let returns: Vec<_> = closes.windows(2).map(|w| w[1] / w[0] - 1.0).collect();
+    if returns.len() < 2 {
+        return Err(Error::UnexpectedData(format!(
+            "not enough daily bars for {} to measure its volatility",
+            sym
+        )));
+    }
+
+    let vol = stats::std_dev(returns.iter().cloned()).unwrap();
+    if vol <= 0.0 {
+        return Err(Error::UnexpectedData(format!("{} had no price movement to measure", sym)));
+    }
+    Ok(vol)
+}
+
+// Broad-market fund whose volatility stands in for the market's.
+pub const MARKET_VOL_SYMBOL: &str = "SPY";
+
+// Annualized volatility of the market's daily returns over the scaling lookback.
+pub async fn market_volatility(client: &TimedClient) -> Result<f64> {
+    Ok(daily_volatility(client, MARKET_VOL_SYMBOL, SCALING_LOOKBACK_DAYS).await? * TRADING_DAYS_PER_YEAR.sqrt())
+}
+
+// Each position's buy increment is its price scaled by how far its annualized
+// volatility is below `target_vol`. Symbols whose volatility can't be measured
+// keep their price.
+pub async fn scaled_buy_sizes(
+    client: &TimedClient,
+    pos: &[position::Position],
+    prices: &[f64],
+    target_vol: f64,
+) -> Result<Vec<f64>> {
+    let mut sizes = Vec::with_capacity(pos.len());
+    for (pos, &price) in pos.iter().zip(prices) {
+        let scale = match daily_volatility(client, &pos.symbol, SCALING_LOOKBACK_DAYS).await {
+            Ok(vol) => (target_vol / (vol * TRADING_DAYS_PER_YEAR.sqrt())).clamp(MIN_SCALE, MAX_SCALE),
+            Err(Error::UnexpectedData(e)) => {
+                warn!("Not scaling {} by volatility: {}", pos.symbol, e);
+                1.0
+            }
+            Err(e) => return Err(e),
+        };
+        sizes.push(price * scale);
+    }
+    Ok(sizes)
+}
@@ -0,0 +1,38 @@
+use apca::api::v2::position;
+use tracing::info;
+
+use crate::State;
+
+// Moves the first watchlisted symbol into `ideal_allocations` at its target
+// weight, scaling the other allocations down to make room. A symbol only
+// graduates while the minimum allocations still fit beside it, and at most
+// one does per funding cycle so new positions are built up gradually.
+pub fn graduate_watchlist(state: &mut State, pos: &[position::Position], equity: f64, min_equity: f64) -> Option<String> {
+    if equity <= min_equity {
+        return None;
+    }
+
+    let mut candidates: Vec<_> = state.watchlist.iter().map(|(sym, &a)| (sym.clone(), a)).collect();
+    candidates.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let total_min: f64 = state.min_allocations.values().sum();
+    let (sym, allocation) = candidates
+        .into_iter()
+        .find(|&(_, allocation)| allocation + total_min <= 1.0)?;
+
+    for a in state.ideal_allocations.values_mut() {
+        *a *= 1.0 - allocation;
+    }
+    state.ideal_allocations.insert(sym.clone(), allocation);
+    state.watchlist.remove(&sym);
+
+    // shares bought before graduating aren't the balancer's
+    let market_value = pos
+        .iter()
+        .find(|pos| pos.symbol == sym)
+        .map_or(0.0, |pos| pos.market_value.as_ref().unwrap().to_f64().unwrap());
+    state.reference_equities.insert(sym.clone(), market_value);
+
+    info!("Graduated {} from the watchlist at an ideal allocation of {:.4}", sym, allocation);
+    Some(sym)
+}